@@ -0,0 +1,222 @@
+//! The scenes of the examples, described once so the golden tests trace the same objects.
+#![allow(dead_code)]
+
+use bevy::prelude::*;
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_8, PI};
+
+/// Meshes and materials are referenced by their index in the scene
+pub struct Scene {
+    pub meshes: Vec<Mesh>,
+    pub materials: Vec<StandardMaterial>,
+    /// Mesh, material and transform of every object
+    pub objects: Vec<(usize, usize, Transform)>,
+    pub camera: Transform,
+}
+
+impl Scene {
+    /// Spawns the objects and returns the transform of the camera
+    pub fn spawn(
+        self,
+        commands: &mut Commands,
+        meshes: &mut Assets<Mesh>,
+        materials: &mut Assets<StandardMaterial>,
+    ) -> Transform {
+        let meshes = self
+            .meshes
+            .into_iter()
+            .map(|mesh| meshes.add(mesh))
+            .collect::<Vec<_>>();
+        let materials = self
+            .materials
+            .into_iter()
+            .map(|material| materials.add(material))
+            .collect::<Vec<_>>();
+        for (mesh, material, transform) in self.objects {
+            commands.spawn(PbrBundle {
+                mesh: meshes[mesh].clone(),
+                material: materials[material].clone(),
+                transform,
+                ..default()
+            });
+        }
+        self.camera
+    }
+}
+
+fn quad() -> Mesh {
+    Mesh::from(shape::Quad {
+        size: Vec2::new(1.0, 1.0),
+        flip: false,
+    })
+}
+
+/// The six walls of a room standing on `offset`, facing inwards
+fn room(room_size: Vec3, offset: Vec3) -> [Transform; 6] {
+    let half_size = room_size / 2.0;
+    [
+        // Front
+        Transform::from_translation(Vec3::new(0.0, half_size.y, -half_size.z) + offset)
+            .with_scale(Vec3::new(room_size.x, room_size.y, 1.0)),
+        // Back
+        Transform::from_translation(Vec3::new(0.0, half_size.y, half_size.z) + offset)
+            .with_rotation(Quat::from_rotation_y(PI))
+            .with_scale(Vec3::new(room_size.x, room_size.y, 1.0)),
+        // Left
+        Transform::from_translation(Vec3::new(-half_size.x, half_size.y, 0.0) + offset)
+            .with_rotation(Quat::from_rotation_y(FRAC_PI_2))
+            .with_scale(Vec3::new(room_size.z, room_size.y, 1.0)),
+        // Right
+        Transform::from_translation(Vec3::new(half_size.x, half_size.y, 0.0) + offset)
+            .with_rotation(Quat::from_rotation_y(-FRAC_PI_2))
+            .with_scale(Vec3::new(room_size.z, room_size.y, 1.0)),
+        // Top
+        Transform::from_translation(Vec3::new(0.0, room_size.y, 0.0) + offset)
+            .with_rotation(Quat::from_rotation_x(FRAC_PI_2))
+            .with_scale(Vec3::new(room_size.x, room_size.z, 1.0)),
+        // Bottom
+        Transform::from_translation(offset)
+            .with_rotation(Quat::from_rotation_x(-FRAC_PI_2))
+            .with_scale(Vec3::new(room_size.x, room_size.z, 1.0)),
+    ]
+}
+
+pub fn cornell_box() -> Scene {
+    // Meshes
+    let (quad_mesh, cube_mesh) = (0, 1);
+    let meshes = vec![quad(), Mesh::from(shape::Cube { size: 1.0 })];
+
+    // Materials
+    let (red, white, green, light) = (0, 1, 2, 3);
+    let materials = vec![
+        StandardMaterial {
+            base_color: Color::rgb(0.65, 0.05, 0.05),
+            ..default()
+        },
+        StandardMaterial {
+            base_color: Color::rgb(0.73, 0.73, 0.73),
+            ..default()
+        },
+        StandardMaterial {
+            base_color: Color::rgb(0.12, 0.45, 0.15),
+            ..default()
+        },
+        StandardMaterial {
+            emissive: Color::rgb(15.0, 15.0, 15.0),
+            ..default()
+        },
+    ];
+
+    // room
+    let room_size = Vec3::new(5.0, 5.0, 5.0);
+    let wall_materials = [white, white, green, red, white, white];
+    let mut objects = wall_materials
+        .into_iter()
+        .zip(room(room_size, Vec3::ZERO))
+        .map(|(material, transform)| (quad_mesh, material, transform))
+        .collect::<Vec<_>>();
+
+    // light
+    objects.push((
+        quad_mesh,
+        light,
+        Transform::from_translation(Vec3::new(0.0, room_size.y - 0.1, 0.0))
+            .with_rotation(Quat::from_rotation_x(FRAC_PI_2)),
+    ));
+
+    // left cube
+    objects.push((
+        cube_mesh,
+        white,
+        Transform::from_translation(Vec3::new(-0.8, 1.5, -0.8))
+            .with_rotation(Quat::from_rotation_y(FRAC_PI_8 / 2.0))
+            .with_scale(Vec3::new(1.5, 3.0, 1.5)),
+    ));
+
+    // right cube
+    objects.push((
+        cube_mesh,
+        white,
+        Transform::from_translation(Vec3::new(1.0, 0.8, 0.5))
+            .with_rotation(Quat::from_rotation_y(-FRAC_PI_8))
+            .with_scale(Vec3::new(1.6, 1.6, 1.6)),
+    ));
+
+    Scene {
+        meshes,
+        materials,
+        objects,
+        camera: Transform::from_xyz(0.0, room_size.y / 2.0, 8.5),
+    }
+}
+
+pub fn emissive_walls() -> Scene {
+    // Meshes
+    let (quad_mesh, sphere_mesh) = (0, 1);
+    let meshes = vec![
+        quad(),
+        Mesh::from(shape::UVSphere {
+            radius: 1.0,
+            sectors: 30,
+            stacks: 30,
+        }),
+    ];
+
+    // Materials
+    let (red_material, blue_material, white_material) = (0, 1, 2);
+    let materials = vec![
+        StandardMaterial {
+            base_color: Color::RED,
+            emissive: Color::RED,
+            ..default()
+        },
+        StandardMaterial {
+            base_color: Color::BLUE,
+            emissive: Color::BLUE,
+            ..default()
+        },
+        StandardMaterial {
+            base_color: Color::ANTIQUE_WHITE,
+            ..default()
+        },
+    ];
+
+    // room
+    let room_size = Vec3::new(6.0, 3.0, 8.0);
+    let offset = Vec3::new(0.0, 0.0, 1.0);
+    let mut objects = room(room_size, offset)
+        .map(|transform| (quad_mesh, white_material, transform))
+        .to_vec();
+
+    // emissive walls
+    objects.push((
+        quad_mesh,
+        red_material,
+        Transform::from_translation(Vec3::new(-room_size.x / 2.0 + 0.01, room_size.y / 2.0, 0.0))
+            .with_rotation(Quat::from_rotation_y(FRAC_PI_2))
+            .with_scale(Vec3::new(room_size.z / 2.0, room_size.y / 2.0, 1.0)),
+    ));
+    objects.push((
+        quad_mesh,
+        blue_material,
+        Transform::from_translation(Vec3::new(room_size.x / 2.0 - 0.01, room_size.y / 2.0, 0.0))
+            .with_rotation(Quat::from_rotation_y(-FRAC_PI_2))
+            .with_scale(Vec3::new(room_size.z / 2.0, room_size.y / 2.0, 1.0)),
+    ));
+
+    // right and left spheres
+    let radius = 0.5;
+    for x in [1.0, -1.0] {
+        objects.push((
+            sphere_mesh,
+            white_material,
+            Transform::from_translation(Vec3::new(x, radius, 0.0)).with_scale(Vec3::splat(radius)),
+        ));
+    }
+
+    Scene {
+        meshes,
+        materials,
+        objects,
+        camera: Transform::from_xyz(0.0, room_size.y / 2.0, room_size.z / 2.0 - 0.1),
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{MeshValidation, PrepareMeshError};
+use bevy::{
+    asset::LoadState,
+    prelude::*,
+    render::{
+        render_resource::{CachedPipelineState, PipelineCacheError},
+        MainWorld, RenderApp,
+    },
+    utils::HashSet,
+};
+
+pub struct ErrorPlugin;
+impl Plugin for ErrorPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<RaytracerError>();
+
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .init_resource::<RaytracerErrors>()
+                .add_systems(ExtractSchedule, send_errors);
+        }
+    }
+}
+
+/// A failure of the render world, sent to the main world once when it appears.
+/// What it affects is skipped or replaced until fixed.
+#[derive(Event, Debug, Clone, PartialEq)]
+pub enum RaytracerError {
+    /// The mesh could not be converted and is not traced
+    Mesh(AssetId<Mesh>, PrepareMeshError),
+    /// Invalid data of the mesh was dropped or replaced, the rest is traced
+    InvalidMeshData(AssetId<Mesh>, MeshValidation),
+    /// A material texture failed to load or was removed, it is sampled as white
+    MissingTexture(AssetId<Image>),
+    /// A pipeline failed to compile, its pass is skipped
+    Pipeline(String),
+    /// The geometry exceeds the chunks the device can bind, some meshes are hidden
+    GeometryLimit {
+        hidden_meshes: usize,
+        max_chunks: usize,
+        max_binding_size: u64,
+    },
+    /// The material kinds or indices exceed what an instance can address,
+    /// the materials past them are not traced
+    MaterialLimit { dropped_materials: usize },
+    /// The texture array is full, the textures past it are sampled as white
+    TextureLimit {
+        dropped_textures: usize,
+        capacity: u32,
+    },
+    /// The color buffer is not on the GPU, the frame is skipped
+    MissingColorBuffer,
+}
+
+impl RaytracerError {
+    /// The error of a pipeline, unless it only waits for its shaders
+    pub fn from_pipeline(state: &CachedPipelineState) -> Option<Self> {
+        match state {
+            CachedPipelineState::Err(
+                PipelineCacheError::ShaderNotLoaded(_)
+                | PipelineCacheError::ShaderImportNotYetAvailable,
+            ) => None,
+            CachedPipelineState::Err(err) => Some(Self::Pipeline(err.to_string())),
+            _ => None,
+        }
+    }
+
+    /// The report of a converted mesh, unless its data was valid
+    pub fn from_validation(id: AssetId<Mesh>, validation: &MeshValidation) -> Option<Self> {
+        (!validation.is_clean()).then_some(Self::InvalidMeshData(id, *validation))
+    }
+}
+
+/// Errors of the render world, sent on the next extraction.
+/// Errors checked every frame are sent again only after they were gone for a frame.
+#[derive(Resource, Default)]
+pub struct RaytracerErrors {
+    errors: Vec<RaytracerError>,
+    sent: Vec<RaytracerError>,
+    /// Textures sampled as white, missing unless they are still loading
+    fallback_textures: HashSet<AssetId<Image>>,
+}
+
+impl RaytracerErrors {
+    pub fn send(&mut self, error: RaytracerError) {
+        self.errors.push(error);
+    }
+
+    pub fn fallback_texture(&mut self, id: AssetId<Image>) {
+        self.fallback_textures.insert(id);
+    }
+}
+
+fn send_errors(mut main_world: ResMut<MainWorld>, mut errors: ResMut<RaytracerErrors>) {
+    let RaytracerErrors {
+        errors,
+        sent,
+        fallback_textures,
+    } = &mut *errors;
+
+    let images = main_world.resource::<Assets<Image>>();
+    let asset_server = main_world.resource::<AssetServer>();
+    for id in fallback_textures.drain() {
+        let loading = asset_server.load_state(id) == LoadState::Loading;
+        if !loading && !images.contains(id) {
+            errors.push(RaytracerError::MissingTexture(id));
+        }
+    }
+
+    for error in errors.iter().filter(|error| !sent.contains(error)) {
+        warn!("Raytracer error: {:?}", error);
+        main_world.send_event(error.clone());
+    }
+    *sent = std::mem::take(errors);
+}
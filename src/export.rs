@@ -0,0 +1,276 @@
+use bevy::prelude::*;
+use exr::prelude::{
+    f16, AnyChannel, AnyChannels, AttributeValue, Encoding, FlatSamples, Image, Layer,
+    LayerAttributes, SmallVec, Text, WritableImage,
+};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Name of the layer holding the accumulated radiance.
+/// Its channels are written without prefix so compositors pick it up as the beauty pass.
+pub const RADIANCE_LAYER: &str = "radiance";
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(io::Error),
+    Exr(exr::error::Error),
+    MissingRadiance,
+    LayerSizeMismatch {
+        layer: String,
+        expected: usize,
+        found: usize,
+    },
+    InvalidName(String),
+    UnsupportedChannelCount(usize),
+}
+
+impl From<io::Error> for ExportError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<exr::error::Error> for ExportError {
+    fn from(err: exr::error::Error) -> Self {
+        Self::Exr(err)
+    }
+}
+
+/// Sample precision of the channels in an OpenEXR file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ExrPrecision {
+    #[default]
+    Half,
+    Float,
+}
+
+/// Information about how a frame was produced, embedded in the exported file
+#[derive(Debug, Clone)]
+pub struct RenderMetadata {
+    pub samples: u32,
+    pub max_bounces: u32,
+    pub render_time: Duration,
+    /// World space transform of the camera that rendered the frame
+    pub camera_transform: Mat4,
+}
+
+impl Default for RenderMetadata {
+    fn default() -> Self {
+        Self {
+            samples: 0,
+            max_bounces: 0,
+            render_time: Duration::ZERO,
+            camera_transform: Mat4::IDENTITY,
+        }
+    }
+}
+
+/// One named group of channels, e.g. the radiance or an AOV like normals or depth.
+/// Samples are interleaved per pixel, rows go top to bottom.
+#[derive(Debug, Clone)]
+pub struct HdrLayer {
+    pub name: String,
+    pub channels: Vec<String>,
+    pub data: Vec<f32>,
+}
+
+impl HdrLayer {
+    pub fn rgba(name: impl Into<String>, pixels: &[Vec4]) -> Self {
+        Self {
+            name: name.into(),
+            channels: ["R", "G", "B", "A"].map(String::from).to_vec(),
+            data: pixels.iter().flat_map(|pixel| pixel.to_array()).collect(),
+        }
+    }
+
+    pub fn rgb(name: impl Into<String>, pixels: &[Vec3]) -> Self {
+        Self {
+            name: name.into(),
+            channels: ["R", "G", "B"].map(String::from).to_vec(),
+            data: pixels.iter().flat_map(|pixel| pixel.to_array()).collect(),
+        }
+    }
+
+    pub fn xyz(name: impl Into<String>, pixels: &[Vec3]) -> Self {
+        Self {
+            name: name.into(),
+            channels: ["X", "Y", "Z"].map(String::from).to_vec(),
+            data: pixels.iter().flat_map(|pixel| pixel.to_array()).collect(),
+        }
+    }
+
+    pub fn scalar(name: impl Into<String>, pixels: &[f32]) -> Self {
+        Self {
+            name: name.into(),
+            channels: vec!["Y".into()],
+            data: pixels.to_vec(),
+        }
+    }
+
+    fn channel(&self, index: usize) -> impl Iterator<Item = f32> + '_ {
+        self.data
+            .iter()
+            .skip(index)
+            .step_by(self.channels.len())
+            .copied()
+    }
+}
+
+/// A frame ready to be written to disk
+#[derive(Debug, Clone)]
+pub struct HdrImage {
+    pub size: UVec2,
+    pub layers: Vec<HdrLayer>,
+    pub metadata: RenderMetadata,
+}
+
+impl HdrImage {
+    pub fn new(size: UVec2, radiance: &[Vec4], metadata: RenderMetadata) -> Self {
+        Self {
+            size,
+            layers: vec![HdrLayer::rgba(RADIANCE_LAYER, radiance)],
+            metadata,
+        }
+    }
+
+    pub fn with_layer(mut self, layer: HdrLayer) -> Self {
+        self.layers.push(layer);
+        self
+    }
+
+    fn validate(&self) -> Result<(), ExportError> {
+        if self.layers.first().map(|layer| layer.name.as_str()) != Some(RADIANCE_LAYER) {
+            return Err(ExportError::MissingRadiance);
+        }
+
+        let pixels = (self.size.x * self.size.y) as usize;
+        for layer in &self.layers {
+            let expected = pixels * layer.channels.len();
+            if layer.data.len() != expected {
+                return Err(ExportError::LayerSizeMismatch {
+                    layer: layer.name.clone(),
+                    expected,
+                    found: layer.data.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes every layer into a single part OpenEXR file.
+    /// AOV channels are prefixed with their layer name (`normal.X`, `depth.Y`, ...).
+    pub fn write_exr(
+        &self,
+        path: impl AsRef<Path>,
+        precision: ExrPrecision,
+    ) -> Result<(), ExportError> {
+        self.validate()?;
+
+        let text =
+            |name: &str| Text::new_or_none(name).ok_or(ExportError::InvalidName(name.into()));
+
+        let mut channels = SmallVec::new();
+        for layer in &self.layers {
+            for (index, channel) in layer.channels.iter().enumerate() {
+                let name = if layer.name == RADIANCE_LAYER {
+                    channel.clone()
+                } else {
+                    format!("{}.{}", layer.name, channel)
+                };
+                let samples = match precision {
+                    ExrPrecision::Half => {
+                        FlatSamples::F16(layer.channel(index).map(f16::from_f32).collect())
+                    }
+                    ExrPrecision::Float => FlatSamples::F32(layer.channel(index).collect()),
+                };
+                channels.push(AnyChannel::new(text(&name)?, samples));
+            }
+        }
+
+        let metadata = &self.metadata;
+        let mut attributes = LayerAttributes::named(RADIANCE_LAYER);
+        attributes.software_name = Some(text(&software_name())?);
+        // OpenEXR uses row vectors, which is the transpose of glam's column major layout
+        attributes.world_to_camera = Some(metadata.camera_transform.inverse().to_cols_array());
+        let mut insert = |name: &str, value| {
+            attributes.other.insert(Text::from(name), value);
+        };
+        insert(
+            "rusticrayz:samples",
+            AttributeValue::I32(metadata.samples as i32),
+        );
+        insert(
+            "rusticrayz:maxBounces",
+            AttributeValue::I32(metadata.max_bounces as i32),
+        );
+        insert(
+            "rusticrayz:renderTime",
+            AttributeValue::F64(metadata.render_time.as_secs_f64()),
+        );
+
+        let layer = Layer::new(
+            (self.size.x as usize, self.size.y as usize),
+            attributes,
+            Encoding::FAST_LOSSLESS,
+            AnyChannels::sort(channels),
+        );
+        Image::from_layer(layer).write().to_file(path)?;
+        Ok(())
+    }
+
+    /// Writes the radiance to `path` and every AOV next to it as `<stem>.<layer>.pfm`.
+    /// PFM has no room for metadata, use OpenEXR if it is needed.
+    pub fn write_pfm(&self, path: impl AsRef<Path>) -> Result<(), ExportError> {
+        self.validate()?;
+
+        let path = path.as_ref();
+        for layer in &self.layers {
+            let layer_path = if layer.name == RADIANCE_LAYER {
+                path.to_path_buf()
+            } else {
+                sibling_path(path, &layer.name)
+            };
+            let file = BufWriter::new(File::create(layer_path)?);
+            write_pfm(file, self.size, layer)?;
+        }
+        Ok(())
+    }
+}
+
+fn software_name() -> String {
+    format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+fn sibling_path(path: &Path, layer: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.{}.pfm", stem, layer))
+}
+
+/// Writes a single layer as a little endian PFM.
+/// Layers with an alpha channel are written as RGB since PFM has no alpha.
+fn write_pfm(mut writer: impl Write, size: UVec2, layer: &HdrLayer) -> Result<(), ExportError> {
+    let (magic, written) = match layer.channels.len() {
+        1 => ("Pf", 1),
+        3 => ("PF", 3),
+        4 => ("PF", 3),
+        count => return Err(ExportError::UnsupportedChannelCount(count)),
+    };
+    // A negative scale marks the data as little endian
+    write!(writer, "{}\n{} {}\n-1.0\n", magic, size.x, size.y)?;
+
+    // PFM scanlines go bottom to top
+    let stride = size.x as usize * layer.channels.len();
+    for row in layer.data.chunks_exact(stride.max(1)).rev() {
+        for pixel in row.chunks_exact(layer.channels.len()) {
+            for sample in &pixel[..written] {
+                writer.write_all(&sample.to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -8,13 +8,27 @@ use bevy::{
         RenderApp,
     },
 };
+use error::ErrorPlugin;
+pub use error::RaytracerError;
+pub use mesh_material::bvh_builder::{BvhBuilder, BvhLayout, BvhReport, BvhSettings, BvhTraversal};
+pub use mesh_material::instance::{InstanceMaterialOverride, InstanceUserData, RayVisibility};
+pub use mesh_material::material::{
+    pack_material_index, ExtendedMaterialPlugin, GpuCustomMaterial, RaytracedExtension,
+    RaytracedExtensionPlugin, RaytracedMaterial, RaytracedMaterialPlugin, TextureSlots,
+    TextureTable,
+};
+pub use mesh_material::mesh::{GeometryLimits, GeometrySettings, MeshDiagnostics, MeshValidation};
 use mesh_material::MeshMaterialPlugin;
+pub use mesh_material::PrepareMeshError;
 use raytracer::{RaytracerNode, RaytracerPipelinePlugin};
 use screen::{ScreenNode, ScreenPlugin};
 use view::ViewPlugin;
 
+mod error;
+pub mod export;
 mod mesh_material;
 mod raytracer;
+pub mod reference;
 mod screen;
 mod view;
 
@@ -41,6 +55,7 @@ const DEFAULT_RENDER_SCALE: f32 = 1.0;
 
 const RT_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(108718554336535632810954);
 const SCREEN_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(8520478187035914832103433315);
+const LBVH_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(241593098120757430977284318);
 
 pub struct RaytracerPlugin;
 impl Plugin for RaytracerPlugin {
@@ -57,11 +72,18 @@ impl Plugin for RaytracerPlugin {
             "shaders/screen.wgsl",
             Shader::from_wgsl
         );
+        load_internal_asset!(
+            app,
+            LBVH_SHADER_HANDLE,
+            "shaders/lbvh.wgsl",
+            Shader::from_wgsl
+        );
 
         app.init_resource::<RtSettings>()
             .add_plugins(ExtractResourcePlugin::<RtSettings>::default())
             .add_plugins(ExtractResourcePlugin::<ColorBuffer>::default())
             .add_plugins((
+                ErrorPlugin,
                 MeshMaterialPlugin,
                 ViewPlugin,
                 RaytracerPipelinePlugin,
@@ -94,16 +116,28 @@ impl Plugin for RaytracerPlugin {
 pub struct RtSettings {
     pub max_bounces: u32,
     pub render_scale: f32,
+    pub render_layers: RenderLayersMode,
 }
 impl FromWorld for RtSettings {
     fn from_world(_world: &mut World) -> Self {
         Self {
             max_bounces: DEFAULT_MAX_BOUNCES,
             render_scale: DEFAULT_RENDER_SCALE,
+            render_layers: default(),
         }
     }
 }
 
+/// Which rays of a camera skip the instances outside its `RenderLayers`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RenderLayersMode {
+    /// The other instances are not in the scene of the camera
+    #[default]
+    AllRays,
+    /// The other instances are not seen directly, but still cast shadows and light
+    PrimaryRays,
+}
+
 #[derive(Resource, Clone, ExtractResource, Deref, DerefMut)]
 pub struct ColorBuffer(Handle<Image>);
 
@@ -1,45 +1,53 @@
 use self::{
+    deform::DeformPlugin,
     instance::{GenericInstancePlugin, GpuInstance, InstancePlugin, InstanceRenderAssets},
-    material::{GenericMaterialPlugin, GpuStandardMaterial, MaterialPlugin, MaterialRenderAssets},
-    mesh::{GpuPrimitiveBuffer, GpuVertexBuffer, MeshPlugin, MeshRenderAssets},
+    lbvh::LbvhPlugin,
+    material::{
+        texture_capacity, GenericMaterialPlugin, GpuStandardMaterial, MaterialPlugin,
+        MaterialRenderAssets,
+    },
+    mesh::{GeometryLimits, GpuWordBuffer, MeshPlugin, MeshRenderAssets, CHUNK_BUFFERS},
 };
+use crate::error::RaytracerErrors;
 use bevy::{
     pbr::MeshPipeline,
     prelude::*,
     render::{
-        render_asset::RenderAssets, render_resource::*, renderer::RenderDevice, Render, RenderApp,
-        RenderSet,
+        render_asset::RenderAssets,
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet,
     },
     utils::HashMap,
 };
-use bvh::aabb::AABB;
 use itertools::Itertools;
-use std::{iter, num::NonZeroU32};
+use std::{num::NonZeroU32, ops::Range};
 
-mod instance;
-mod material;
-mod mesh;
+pub(crate) mod bvh_builder;
+pub(crate) mod deform;
+pub(crate) mod instance;
+pub(crate) mod lbvh;
+pub(crate) mod material;
+pub(crate) mod mesh;
 
 pub struct MeshMaterialPlugin;
 impl Plugin for MeshMaterialPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins((MeshPlugin, MaterialPlugin, InstancePlugin))
-            .add_plugins(GenericMaterialPlugin::<StandardMaterial>::default())
-            .add_plugins(GenericInstancePlugin::<StandardMaterial>::default());
+        app.add_plugins((
+            MeshPlugin,
+            MaterialPlugin,
+            InstancePlugin,
+            LbvhPlugin,
+            DeformPlugin,
+        ))
+        .add_plugins(GenericMaterialPlugin::<StandardMaterial>::default())
+        .add_plugins(GenericInstancePlugin::<StandardMaterial>::default());
 
         if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
-            render_app
-                .init_resource::<GpuMeshes>()
-                .add_systems(
-                    Render,
-                    prepare_texture_bind_group_layout
-                        .in_set(RenderSet::Queue)
-                        .before(queue_mesh_material_bind_group),
-                )
-                .add_systems(
-                    Render,
-                    queue_mesh_material_bind_group.in_set(RenderSet::QueueMeshes),
-                );
+            render_app.init_resource::<GpuMeshes>().add_systems(
+                Render,
+                queue_mesh_material_bind_group.in_set(RenderSet::QueueMeshes),
+            );
         }
     }
 
@@ -57,89 +65,108 @@ pub struct MeshMaterialBindGroupLayout(pub BindGroupLayout);
 impl FromWorld for MeshMaterialBindGroupLayout {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.resource::<RenderDevice>();
-        let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-            label: Some("mesh_material_bindgroup_layout"),
-            entries: &[
-                // Vertices
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: Some(GpuVertexBuffer::min_size()),
-                    },
-                    count: None,
-                },
-                // Primitives
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: Some(GpuPrimitiveBuffer::min_size()),
-                    },
-                    count: None,
+        let limits = GeometryLimits::new(&render_device.limits());
+        let mut entries = vec![
+            // Materials
+            BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuStandardMaterial::min_size()),
                 },
-                // Mesh nodes
-                BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: Some(GpuNodeBuffer::min_size()),
-                    },
-                    count: None,
+                count: None,
+            },
+            // Instances
+            BindGroupLayoutEntry {
+                binding: 4,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuInstance::min_size()),
                 },
-                // Materials
-                BindGroupLayoutEntry {
-                    binding: 3,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: Some(GpuStandardMaterial::min_size()),
-                    },
-                    count: None,
+                count: None,
+            },
+            // Instances nodes
+            BindGroupLayoutEntry {
+                binding: 5,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuNodeBuffer::min_size()),
                 },
-                // Instances
-                BindGroupLayoutEntry {
-                    binding: 4,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: Some(GpuInstance::min_size()),
-                    },
-                    count: None,
+                count: None,
+            },
+            // Raytraced materials
+            BindGroupLayoutEntry {
+                binding: 6,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuWordBuffer::min_size()),
                 },
-                // Instances nodes
-                BindGroupLayoutEntry {
-                    binding: 5,
+                count: None,
+            },
+        ];
+        // Vertices and primitives in either encoding of the geometry settings,
+        // mesh nodes and vertex attributes of each chunk
+        for chunk in 0..limits.max_chunks {
+            let sizes = [
+                GpuWordBuffer::min_size(),
+                GpuWordBuffer::min_size(),
+                GpuNodeBuffer::min_size(),
+                GpuWordBuffer::min_size(),
+            ];
+            entries.extend(sizes.into_iter().zip(chunk_bindings(chunk)).map(
+                |(min_binding_size, binding)| BindGroupLayoutEntry {
+                    binding,
                     visibility: ShaderStages::COMPUTE,
                     ty: BindingType::Buffer {
                         ty: BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
-                        min_binding_size: Some(GpuNodeBuffer::min_size()),
+                        min_binding_size: Some(min_binding_size),
                     },
                     count: None,
                 },
-            ],
+            ));
+        }
+        let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("mesh_material_bindgroup_layout"),
+            entries: &entries,
         });
 
         Self(layout)
     }
 }
 
+/// Bindings of the vertices, primitives, nodes and vertex attributes of a chunk.
+/// The first chunk surrounds the materials and instances, the others come after.
+fn chunk_bindings(chunk: usize) -> [u32; CHUNK_BUFFERS] {
+    if chunk == 0 {
+        [0, 1, 2, 7]
+    } else {
+        let first = 4 + CHUNK_BUFFERS as u32 * chunk as u32;
+        std::array::from_fn(|i| first + i as u32)
+    }
+}
+
+/// The layout of the texture array, whose capacity depends only on the device
+/// so that loading textures does not specialize the pipelines again
 #[derive(Resource, Clone)]
 pub struct TextureBindGroupLayout {
     pub layout: BindGroupLayout,
-    pub texture_count: u32,
+    pub capacity: u32,
 }
-impl TextureBindGroupLayout {
-    fn get_layout(render_device: &RenderDevice, texture_count: NonZeroU32) -> Self {
+
+impl FromWorld for TextureBindGroupLayout {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let capacity = texture_capacity(&render_device.limits());
+        let count = NonZeroU32::new(capacity);
         let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: Some("mesh_material_bindgroup_layout"),
             entries: &[
@@ -152,43 +179,21 @@ impl TextureBindGroupLayout {
                         view_dimension: TextureViewDimension::D2,
                         multisampled: false,
                     },
-                    count: Some(texture_count),
+                    count,
                 },
                 // Samplers
                 BindGroupLayoutEntry {
                     binding: 1,
                     visibility: ShaderStages::COMPUTE,
                     ty: BindingType::Sampler(SamplerBindingType::Filtering),
-                    count: Some(texture_count),
+                    count,
                 },
             ],
         });
-
-        Self {
-            layout,
-            texture_count: texture_count.into(),
-        }
-    }
-}
-
-impl FromWorld for TextureBindGroupLayout {
-    fn from_world(world: &mut World) -> Self {
-        let render_device = world.resource::<RenderDevice>();
-        Self::get_layout(render_device, NonZeroU32::new(1).unwrap())
+        Self { layout, capacity }
     }
 }
 
-fn prepare_texture_bind_group_layout(
-    render_device: Res<RenderDevice>,
-    materials: Res<MaterialRenderAssets>,
-    mut texture_layout: ResMut<TextureBindGroupLayout>,
-) {
-    *texture_layout = TextureBindGroupLayout::get_layout(
-        &render_device,
-        NonZeroU32::new(materials.textures.len() as u32 + 1).unwrap(),
-    );
-}
-
 #[derive(Resource)]
 pub struct MeshMaterialBindGroup {
     pub mesh_material: BindGroup,
@@ -206,49 +211,93 @@ fn queue_mesh_material_bind_group(
     images: Res<RenderAssets<Image>>,
     mesh_material_layout: Res<MeshMaterialBindGroupLayout>,
     texture_layout: Res<TextureBindGroupLayout>,
+    mut errors: ResMut<RaytracerErrors>,
 ) {
+    let partially_bound = render_device
+        .features()
+        .contains(WgpuFeatures::PARTIALLY_BOUND_BINDING_ARRAY);
+
+    // The chunks the scene does not fill repeat the first one
+    let chunk_resources = (0..meshes.limits().max_chunks)
+        .map(|chunk| {
+            let chunk = if chunk < meshes.chunk_count() {
+                chunk
+            } else {
+                0
+            };
+            Some([
+                meshes.vertex_binding(chunk)?,
+                meshes.primitive_binding(chunk)?,
+                meshes.node_binding(chunk)?,
+                meshes.attribute_binding(chunk)?,
+            ])
+        })
+        .collect::<Option<Vec<_>>>();
+
     if let (
-        Some(vertex_binding),
-        Some(primitive_binding),
-        Some(mesh_node_binding),
+        Some(chunk_resources),
         Some(material_binding),
         Some(instance_binding),
         Some(instance_node_binding),
+        Some(custom_material_binding),
     ) = (
-        meshes.vertex_buffer.binding(),
-        meshes.primitive_buffer.binding(),
-        meshes.node_buffer.binding(),
+        chunk_resources,
         materials.materials.binding(),
         instances.instance_buffer.binding(),
         instances.instance_node_buffer.binding(),
+        materials.custom_materials.binding(),
     ) {
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 3,
+                resource: material_binding,
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: instance_binding,
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: instance_node_binding,
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: custom_material_binding,
+            },
+        ];
+        for (chunk, bindings) in chunk_resources.into_iter().enumerate() {
+            let bindings = bindings.into_iter().zip(chunk_bindings(chunk));
+            entries
+                .extend(bindings.map(|(resource, binding)| BindGroupEntry { binding, resource }));
+        }
         let mesh_material = render_device.create_bind_group(
             "mesh_material_bindgroup",
             &mesh_material_layout.0,
-            &BindGroupEntries::sequential((
-                vertex_binding,
-                primitive_binding,
-                mesh_node_binding,
-                material_binding,
-                instance_binding,
-                instance_node_binding,
-            )),
+            &entries,
         );
 
-        let images = materials
-            .textures
-            .iter()
-            .map(|handle| {
-                images
-                    .get(handle)
-                    .unwrap_or(&mesh_pipeline.dummy_white_gpu_image)
+        // Free slots hold white, and are left unbound when the device allows it
+        let dummy = &mesh_pipeline.dummy_white_gpu_image;
+        let slots = materials.textures.slots();
+        let bound = if partially_bound {
+            slots.len().max(1)
+        } else {
+            texture_layout.capacity as usize
+        };
+        let images = (0..bound)
+            .map(|slot| match slots.get(slot) {
+                Some(Some(handle)) => images.get(handle).unwrap_or_else(|| {
+                    errors.fallback_texture(handle.id());
+                    dummy
+                }),
+                _ => dummy,
             })
-            .chain(iter::once(&mesh_pipeline.dummy_white_gpu_image)); // TODO: find a better solution
+            .collect_vec();
         let textures = images
-            .clone()
+            .iter()
             .map(|image| &*image.texture_view)
             .collect_vec();
-        let samplers = images.map(|image| &*image.sampler).collect_vec();
+        let samplers = images.iter().map(|image| &*image.sampler).collect_vec();
 
         let textures = render_device.create_bind_group(
             "texture_bindgroup",
@@ -268,12 +317,12 @@ fn queue_mesh_material_bind_group(
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum PrepareMeshError {
     MissingAttributePosition,
-    MissingAttributeNormal,
-    MissingAttributeUV,
     IncompatiblePrimitiveTopology,
+    /// Lines and points cannot be traced
+    UnsupportedTopology(PrimitiveTopology),
     NoPrimitive,
 }
 
@@ -282,11 +331,15 @@ pub enum PrepareMeshError {
 pub struct GpuMeshes(HashMap<Handle<Mesh>, GpuMeshIndex>);
 
 /// Offsets (and length for nodes) of the mesh in the universal buffer.
-#[derive(Debug, Default, Clone, Copy, ShaderType)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, ShaderType)]
 pub struct GpuMeshIndex {
     pub vertex: u32,
     pub primitive: u32,
     pub node: UVec2,
+    /// The chunk of the buffers holding the mesh, see [`mesh::GeometryLimits`]
+    pub chunk: u32,
+    /// Offset of the optional vertex attributes, and their flags, see [`mesh::GpuVertexAttributes`]
+    pub attributes: UVec2,
 }
 
 /// Holds the indices of the GPU representatives of material assets.
@@ -302,7 +355,8 @@ pub struct GpuNodeBuffer {
 }
 
 /// A node in the BVH
-/// This must match the Node definition on the shader
+/// This must match the Node definition on the shader.
+/// The wide layouts use it as a storage unit of 8 words, see [`GpuNode::from_words`].
 #[derive(Debug, Default, Clone, Copy, ShaderType)]
 pub struct GpuNode {
     /// AABB min
@@ -319,20 +373,179 @@ pub struct GpuNode {
     pub exit_index: u32,
 }
 
+/// Relative costs used by [`sah_cost`]
+const SAH_TRAVERSAL_COST: f32 = 1.0;
+const SAH_INTERSECTION_COST: f32 = 1.0;
+
+// Wide nodes store arbitrary bits in the boxes, so they are compared bitwise
+impl PartialEq for GpuNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_words() == other.to_words()
+    }
+}
+
 impl GpuNode {
-    pub fn pack(aabb: &AABB, entry_index: u32, exit_index: u32, primitive_index: u32) -> Self {
-        let entry_index = if entry_index == u32::MAX {
-            primitive_index | 0x80000000
-        } else {
-            entry_index
+    /// Packs raw words, in the order of the fields
+    pub fn from_words(words: [u32; 8]) -> Self {
+        let float = |index: usize| {
+            Vec3::new(
+                f32::from_bits(words[index]),
+                f32::from_bits(words[index + 1]),
+                f32::from_bits(words[index + 2]),
+            )
         };
-        let min = aabb.min.to_array().into();
-        let max = aabb.max.to_array().into();
         Self {
-            min,
-            entry_index,
-            max,
-            exit_index,
+            min: float(0),
+            entry_index: words[3],
+            max: float(4),
+            exit_index: words[7],
+        }
+    }
+
+    pub fn to_words(&self) -> [u32; 8] {
+        let [min_x, min_y, min_z] = self.min.to_array().map(f32::to_bits);
+        let [max_x, max_y, max_z] = self.max.to_array().map(f32::to_bits);
+        [
+            min_x,
+            min_y,
+            min_z,
+            self.entry_index,
+            max_x,
+            max_y,
+            max_z,
+            self.exit_index,
+        ]
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.entry_index >= 0x80000000
+    }
+
+    /// Index of the primitive (or instance) of a leaf node
+    pub fn primitive_index(&self) -> u32 {
+        self.entry_index & !0x80000000
+    }
+}
+
+/// Bounds of the subtrees found in `nodes[start..end]`.
+/// The boxes of leaves are undefined, so they are queried with `leaf_aabb`.
+fn subtrees_aabb(
+    nodes: &[GpuNode],
+    start: u32,
+    end: u32,
+    leaf_aabb: &impl Fn(u32) -> (Vec3, Vec3),
+) -> (Vec3, Vec3) {
+    let mut min = Vec3::splat(f32::MAX);
+    let mut max = Vec3::splat(f32::MIN);
+    let mut index = start;
+    while index < end {
+        let node = &nodes[index as usize];
+        let (node_min, node_max) = if node.is_leaf() {
+            leaf_aabb(node.primitive_index())
+        } else {
+            (node.min, node.max)
+        };
+        min = min.min(node_min);
+        max = max.max(node_max);
+        index = node.exit_index;
+    }
+    (min, max)
+}
+
+/// Recomputes the boxes of a flattened BVH bottom-up, keeping its topology.
+/// `leaf_aabb` returns the bounds of the primitive referenced by a leaf.
+pub fn refit_nodes(nodes: &mut [GpuNode], leaf_aabb: impl Fn(u32) -> (Vec3, Vec3)) {
+    // Children are always stored after their parent
+    for index in (0..nodes.len()).rev() {
+        let node = nodes[index];
+        if !node.is_leaf() {
+            let (min, max) = subtrees_aabb(nodes, node.entry_index, node.exit_index, &leaf_aabb);
+            nodes[index].min = min;
+            nodes[index].max = max;
+        }
+    }
+}
+
+/// Surface area heuristic cost of a flattened BVH, normalized by the area of its bounds
+pub fn sah_cost(nodes: &[GpuNode], leaf_aabb: impl Fn(u32) -> (Vec3, Vec3)) -> f32 {
+    let area = |(min, max): (Vec3, Vec3)| {
+        let size = (max - min).max(Vec3::ZERO);
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    };
+
+    let root_area = area(subtrees_aabb(nodes, 0, nodes.len() as u32, &leaf_aabb));
+    if root_area <= 0.0 {
+        return 0.0;
+    }
+
+    let cost = nodes
+        .iter()
+        .map(|node| {
+            if node.is_leaf() {
+                SAH_INTERSECTION_COST * area(leaf_aabb(node.primitive_index()))
+            } else {
+                SAH_TRAVERSAL_COST * area((node.min, node.max))
+            }
+        })
+        .sum::<f32>();
+    cost / root_area
+}
+
+/// Uploads `data` to the elements of `buffer` starting at `start`.
+/// `B` is the buffer type whose runtime sized array holds the elements.
+/// Returns the number of bytes written.
+pub fn write_range<B, T>(queue: &RenderQueue, buffer: &Buffer, start: usize, data: &[T]) -> u64
+where
+    B: encase::CalculateSizeFor,
+    T: ShaderSize + encase::internal::WriteInto + Clone,
+{
+    if data.is_empty() {
+        return 0;
+    }
+
+    let mut bytes = encase::StorageBuffer::new(Vec::new());
+    bytes.write(&data.to_vec()).unwrap();
+    queue.write_buffer(buffer, element_offset::<B, T>(start), bytes.as_ref());
+    bytes.as_ref().len() as u64
+}
+
+/// Offset in bytes of the element `index` of the runtime sized array of `B`
+pub fn element_offset<B, T>(index: usize) -> u64
+where
+    B: encase::CalculateSizeFor,
+    T: ShaderSize,
+{
+    B::calculate_size_for(index as u64 + 1).get() - T::SHADER_SIZE.get()
+}
+
+/// Ranges of the elements of `new` that differ from `old`, merged when contiguous
+pub fn dirty_ranges<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Range<usize>> {
+    let mut ranges = vec![];
+    let mut index = 0;
+    while index < new.len() {
+        if old.get(index) == Some(&new[index]) {
+            index += 1;
+            continue;
         }
+
+        let start = index;
+        while index < new.len() && old.get(index) != Some(&new[index]) {
+            index += 1;
+        }
+        ranges.push(start..index);
     }
+    ranges
+}
+
+/// Uploads the elements of `new` that differ from `old`, merged into contiguous ranges.
+/// Returns the number of bytes written.
+pub fn write_dirty_ranges<B, T>(queue: &RenderQueue, buffer: &Buffer, old: &[T], new: &[T]) -> u64
+where
+    B: encase::CalculateSizeFor,
+    T: ShaderSize + encase::internal::WriteInto + Clone + PartialEq,
+{
+    dirty_ranges(old, new)
+        .into_iter()
+        .map(|range| write_range::<B, T>(queue, buffer, range.start, &new[range]))
+        .sum()
 }
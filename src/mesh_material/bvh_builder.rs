@@ -0,0 +1,899 @@
+use super::{
+    lbvh::{karras_children, morton_code},
+    GpuNode, SAH_INTERSECTION_COST, SAH_TRAVERSAL_COST,
+};
+use bevy::{prelude::*, render::extract_resource::ExtractResource};
+use std::time::{Duration, Instant};
+
+const LEAF_FLAG: u32 = 0x80000000;
+/// Marks the unused children of a wide node
+const WIDE_EMPTY_CHILD: u32 = u32::MAX;
+/// Deeper nodes become leaves, whatever their size
+const MAX_DEPTH: u32 = 64;
+/// Spatial splits stop once the primitives are referenced this many times on average
+const MAX_DUPLICATION: f32 = 2.0;
+
+/// Node layout of the mesh BVHs, matched by a shader def
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BvhLayout {
+    /// Binary nodes with full precision boxes, traversed without a stack
+    #[default]
+    Binary,
+    /// Nodes of 4 children with quantized boxes, traversed with a stack
+    Wide4,
+    /// Nodes of 8 children with quantized boxes, traversed with a stack
+    Wide8,
+}
+
+impl BvhLayout {
+    /// Number of children of the wide layouts
+    pub fn width(&self) -> Option<usize> {
+        match self {
+            BvhLayout::Binary => None,
+            BvhLayout::Wide4 => Some(4),
+            BvhLayout::Wide8 => Some(8),
+        }
+    }
+}
+
+/// How the shader walks the binary layouts, matched by a shader def.
+/// The wide layouts of the meshes always use a stack.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BvhTraversal {
+    /// Follows the skip links in build order
+    #[default]
+    Stackless,
+    /// Visits the nearest child first and skips subtrees behind the closest hit,
+    /// with a short stack that falls back to the skip links when full
+    Ordered,
+}
+
+/// How the BVHs are built
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BvhBuilder {
+    /// Binned SAH with spatial splits and rotations, on the CPU
+    #[default]
+    Sah,
+    /// Linear BVH over the Morton codes of the centroids, a primitive per leaf.
+    /// Binary BVHs are built on the GPU, every time the geometry changes: the instance BVH
+    /// is rebuilt instead of refitted. The wide layouts are collapsed from the same tree,
+    /// built on the CPU.
+    Lbvh,
+}
+
+/// Quality settings of the mesh BVHs.
+/// Only meshes loaded after a change are affected, except for the layout which rebuilds them all.
+#[derive(Resource, Debug, Clone, ExtractResource)]
+pub struct BvhSettings {
+    /// Number of bins the SAH is evaluated on, along each axis
+    pub bins: usize,
+    /// Nodes are split until they hold at most this many primitives
+    pub max_leaf_size: usize,
+    /// Primitives may be split between children (SBVH) when the overlap of the children
+    /// is above this fraction of the area of the root. `None` disables spatial splits.
+    pub spatial_split_alpha: Option<f32>,
+    /// Passes of tree rotations run after the build
+    pub rotation_passes: u32,
+    pub layout: BvhLayout,
+    pub traversal: BvhTraversal,
+    /// Also applies to the instance BVH. The quality settings above only apply to [`BvhBuilder::Sah`].
+    pub builder: BvhBuilder,
+    /// Replaces the picture by the nodes (red) and primitives (green) visited by each pixel
+    pub show_traversal_stats: bool,
+}
+
+impl Default for BvhSettings {
+    fn default() -> Self {
+        Self {
+            bins: 16,
+            max_leaf_size: 4,
+            spatial_split_alpha: Some(1e-5),
+            rotation_passes: 2,
+            layout: BvhLayout::Binary,
+            traversal: BvhTraversal::Stackless,
+            builder: BvhBuilder::Sah,
+            show_traversal_stats: false,
+        }
+    }
+}
+
+impl BvhSettings {
+    /// Whether the nodes are left to the GPU builder
+    pub fn builds_on_gpu(&self) -> bool {
+        self.builder == BvhBuilder::Lbvh && self.layout == BvhLayout::Binary
+    }
+}
+
+/// Statistics of a build, to compare settings
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BvhReport {
+    /// SAH cost of the tree, relative to the area of its bounds
+    pub sah_cost: f32,
+    pub layout: BvhLayout,
+    pub builder: BvhBuilder,
+    /// The nodes are written by the GPU once uploaded, until then they are placeholders
+    pub on_gpu: bool,
+    /// Number of flattened nodes, in [`GpuNode`]s for the wide layouts
+    pub nodes: usize,
+    pub leaves: usize,
+    /// Number of primitive references, greater than the number of primitives after spatial splits
+    pub references: usize,
+    pub spatial_splits: usize,
+    pub depth: u32,
+    pub build_time: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Bounds {
+    pub const EMPTY: Self = Self {
+        min: Vec3::splat(f32::INFINITY),
+        max: Vec3::splat(f32::NEG_INFINITY),
+    };
+
+    pub fn grow(&mut self, point: Vec3) {
+        self.min = self.min.min(point);
+        self.max = self.max.max(point);
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn intersection(&self, other: &Self) -> Self {
+        Self {
+            min: self.min.max(other.min),
+            max: self.max.min(other.max),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.cmpgt(self.max).any()
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn area(&self) -> f32 {
+        let size = (self.max - self.min).max(Vec3::ZERO);
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+}
+
+/// Something a BVH can be built over
+pub trait BvhPrimitive {
+    fn bounds(&self) -> Bounds;
+
+    /// Bounds of the parts of the primitive within `bounds`, on each side of the plane
+    /// `axis = position`. Used by spatial splits.
+    fn split(&self, bounds: &Bounds, axis: usize, position: f32) -> (Bounds, Bounds) {
+        let mut left = *bounds;
+        let mut right = *bounds;
+        left.max[axis] = left.max[axis].min(position);
+        right.min[axis] = right.min[axis].max(position);
+        (left, right)
+    }
+}
+
+/// A primitive, or the part of it that ended up in a node after spatial splits
+#[derive(Debug, Clone, Copy)]
+struct Reference {
+    index: u32,
+    bounds: Bounds,
+}
+
+enum NodeKind {
+    Leaf(Vec<Reference>),
+    Inner([usize; 2]),
+}
+
+struct Node {
+    bounds: Bounds,
+    kind: NodeKind,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Split {
+    /// References go left if their centroid falls before `bin`
+    Object { axis: usize, bin: usize },
+    /// References are clipped at the plane, straddling ones go to both sides
+    Spatial { axis: usize, position: f32 },
+    /// Centroids cannot be told apart, cut the list in half
+    Median,
+}
+
+struct Builder<'a, P> {
+    primitives: &'a [P],
+    settings: &'a BvhSettings,
+    nodes: Vec<Node>,
+    root_area: f32,
+    references: usize,
+    report: BvhReport,
+}
+
+/// Builds a BVH over `primitives` and flattens it for the shader
+pub fn build_bvh<P: BvhPrimitive>(
+    primitives: &[P],
+    settings: &BvhSettings,
+) -> (Vec<GpuNode>, BvhReport) {
+    let start = Instant::now();
+    if primitives.is_empty() {
+        return (vec![], BvhReport::default());
+    }
+
+    let references = primitives
+        .iter()
+        .enumerate()
+        .map(|(index, primitive)| Reference {
+            index: index as u32,
+            bounds: primitive.bounds(),
+        })
+        .collect::<Vec<_>>();
+    let root_bounds = union(&references);
+
+    let mut builder = Builder {
+        primitives,
+        settings,
+        nodes: vec![],
+        root_area: root_bounds.area(),
+        references: references.len(),
+        report: BvhReport::default(),
+    };
+    let root = match settings.builder {
+        BvhBuilder::Sah => {
+            let root = builder.build(references, 0);
+            for _ in 0..settings.rotation_passes {
+                builder.rotate(root);
+            }
+            root
+        }
+        BvhBuilder::Lbvh => builder.build_linear(references),
+    };
+
+    let mut nodes = vec![];
+    match settings.layout.width() {
+        None => builder.flatten(root, &mut nodes),
+        Some(width) => {
+            let mut words = vec![];
+            builder.flatten_wide(builder.wide_child(root), width, &mut words);
+            nodes.extend(
+                words
+                    .chunks_exact(8)
+                    .map(|words| GpuNode::from_words(words.try_into().unwrap())),
+            );
+        }
+    }
+
+    let mut report = builder.report;
+    report.layout = settings.layout;
+    report.builder = settings.builder;
+    report.sah_cost = builder.cost(root);
+    report.nodes = nodes.len();
+    report.references = builder.references;
+    report.build_time = start.elapsed();
+    (nodes, report)
+}
+
+fn union(references: &[Reference]) -> Bounds {
+    references.iter().fold(Bounds::EMPTY, |bounds, reference| {
+        bounds.union(&reference.bounds)
+    })
+}
+
+fn centroid_bounds(references: &[Reference]) -> Bounds {
+    let mut centroids = Bounds::EMPTY;
+    for reference in references {
+        centroids.grow(reference.bounds.center());
+    }
+    centroids
+}
+
+impl<'a, P: BvhPrimitive> Builder<'a, P> {
+    fn push(&mut self, bounds: Bounds, kind: NodeKind) -> usize {
+        self.nodes.push(Node { bounds, kind });
+        self.nodes.len() - 1
+    }
+
+    fn build(&mut self, references: Vec<Reference>, depth: u32) -> usize {
+        self.report.depth = self.report.depth.max(depth);
+        let bounds = union(&references);
+        let count = references.len();
+
+        let split = if count > 1 && depth < MAX_DEPTH {
+            let (split, split_cost) = self.find_split(&references, &bounds);
+            let leaf_cost = SAH_INTERSECTION_COST * count as f32;
+            let fits_in_leaf = count <= self.settings.max_leaf_size && leaf_cost <= split_cost;
+            (!fits_in_leaf).then_some(split)
+        } else {
+            None
+        };
+        let Some(split) = split else {
+            self.report.leaves += 1;
+            return self.push(bounds, NodeKind::Leaf(references));
+        };
+
+        let (mut left, mut right) = self.partition(references, split);
+        if left.is_empty() || right.is_empty() {
+            // Clipping can disagree with the binning, never recurse on the same references
+            left.append(&mut right);
+            right = left.split_off(left.len() / 2);
+        }
+        let left = self.build(left, depth + 1);
+        let right = self.build(right, depth + 1);
+        self.push(bounds, NodeKind::Inner([left, right]))
+    }
+
+    /// Builds the same tree as the GPU builder: the Karras hierarchy over the sorted Morton codes
+    fn build_linear(&mut self, references: Vec<Reference>) -> usize {
+        let centroids = centroid_bounds(&references);
+        let mut sorted = references
+            .into_iter()
+            .map(|reference| {
+                (
+                    morton_code(reference.bounds.center(), &centroids),
+                    reference,
+                )
+            })
+            .collect::<Vec<_>>();
+        // Stable, like the radix sort
+        sorted.sort_by_key(|(code, _)| *code);
+        let (codes, references): (Vec<_>, Vec<_>) = sorted.into_iter().unzip();
+        self.link_linear(&codes, &references, 0, 0)
+    }
+
+    /// Node `index` of the Karras hierarchy: the inner nodes come first, then the leaves
+    fn link_linear(
+        &mut self,
+        codes: &[u32],
+        references: &[Reference],
+        index: usize,
+        depth: u32,
+    ) -> usize {
+        self.report.depth = self.report.depth.max(depth);
+        let inner = codes.len() - 1;
+        if index >= inner {
+            let reference = references[index - inner];
+            self.report.leaves += 1;
+            return self.push(reference.bounds, NodeKind::Leaf(vec![reference]));
+        }
+
+        let [left, right] = karras_children(codes, index);
+        let left = self.link_linear(codes, references, left, depth + 1);
+        let right = self.link_linear(codes, references, right, depth + 1);
+        let bounds = self.nodes[left].bounds.union(&self.nodes[right].bounds);
+        self.push(bounds, NodeKind::Inner([left, right]))
+    }
+
+    /// Best split of the node and its cost, relative to the area of the node
+    fn find_split(&self, references: &[Reference], bounds: &Bounds) -> (Split, f32) {
+        let area = bounds.area();
+        let relative_cost = |left: f32, right: f32| {
+            if area > 0.0 {
+                SAH_TRAVERSAL_COST + SAH_INTERSECTION_COST * (left + right) / area
+            } else {
+                f32::MAX
+            }
+        };
+
+        let mut best = (Split::Median, f32::MAX);
+        let mut overlap = 0.0;
+
+        let centroids = centroid_bounds(references);
+        for axis in 0..3 {
+            let Some(bins) = self.object_bins(references, &centroids, axis) else {
+                continue;
+            };
+            for (bin, (left, right)) in sweep(&bins).into_iter().enumerate() {
+                if left.count == 0 || right.count == 0 {
+                    continue;
+                }
+                let cost = relative_cost(left.cost(), right.cost());
+                if cost < best.1 {
+                    best = (Split::Object { axis, bin: bin + 1 }, cost);
+                    overlap = left.bounds.intersection(&right.bounds).area();
+                }
+            }
+        }
+
+        let spatial_allowed = self.settings.spatial_split_alpha.is_some_and(|alpha| {
+            overlap > alpha * self.root_area
+                && (self.references as f32) < MAX_DUPLICATION * self.primitives.len() as f32
+        });
+        if spatial_allowed {
+            for axis in 0..3 {
+                let Some((bins, width)) = self.spatial_bins(references, bounds, axis) else {
+                    continue;
+                };
+                for (bin, (left, right)) in sweep(&bins).into_iter().enumerate() {
+                    // Splits that do not separate anything would never end
+                    let count = references.len();
+                    let (left_count, right_count) = (left.count, right.count);
+                    if left_count == 0
+                        || right_count == 0
+                        || left_count == count
+                        || right_count == count
+                    {
+                        continue;
+                    }
+                    let cost = relative_cost(left.cost(), right.cost());
+                    if cost < best.1 {
+                        let position = bounds.min[axis] + width * (bin + 1) as f32;
+                        best = (Split::Spatial { axis, position }, cost);
+                    }
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Bounds and number of references whose centroid falls in each bin
+    fn object_bins(
+        &self,
+        references: &[Reference],
+        centroids: &Bounds,
+        axis: usize,
+    ) -> Option<Vec<Bin>> {
+        let extent = centroids.max[axis] - centroids.min[axis];
+        if extent <= 0.0 {
+            return None;
+        }
+
+        let count = self.settings.bins.max(2);
+        let mut bins = vec![Bin::default(); count];
+        for reference in references {
+            let bin = &mut bins[object_bin(reference, centroids, axis, count)];
+            bin.bounds = bin.bounds.union(&reference.bounds);
+            bin.entries += 1;
+            bin.exits += 1;
+        }
+        Some(bins)
+    }
+
+    /// Bounds of the clipped references in each bin, with the number of references
+    /// entering and leaving each bin
+    fn spatial_bins(
+        &self,
+        references: &[Reference],
+        bounds: &Bounds,
+        axis: usize,
+    ) -> Option<(Vec<Bin>, f32)> {
+        let extent = bounds.max[axis] - bounds.min[axis];
+        if extent <= 0.0 {
+            return None;
+        }
+
+        let count = self.settings.bins.max(2);
+        let width = extent / count as f32;
+        let bin_of =
+            |position: f32| (((position - bounds.min[axis]) / width) as usize).min(count - 1);
+
+        let mut bins = vec![Bin::default(); count];
+        for reference in references {
+            let first = bin_of(reference.bounds.min[axis]);
+            let last = bin_of(reference.bounds.max[axis]);
+            bins[first].entries += 1;
+            bins[last].exits += 1;
+
+            let mut rest = reference.bounds;
+            for (offset, bin) in bins[first..last].iter_mut().enumerate() {
+                let position = bounds.min[axis] + width * (first + offset + 1) as f32;
+                let (left, right) =
+                    self.primitives[reference.index as usize].split(&rest, axis, position);
+                bin.bounds = bin.bounds.union(&left);
+                rest = right;
+            }
+            bins[last].bounds = bins[last].bounds.union(&rest);
+        }
+        Some((bins, width))
+    }
+
+    fn partition(
+        &mut self,
+        mut references: Vec<Reference>,
+        split: Split,
+    ) -> (Vec<Reference>, Vec<Reference>) {
+        match split {
+            Split::Object { axis, bin } => {
+                let centroids = centroid_bounds(&references);
+                let count = self.settings.bins.max(2);
+                references
+                    .into_iter()
+                    .partition(|reference| object_bin(reference, &centroids, axis, count) < bin)
+            }
+            Split::Spatial { axis, position } => {
+                self.report.spatial_splits += 1;
+                let mut left = vec![];
+                let mut right = vec![];
+                for reference in references {
+                    if reference.bounds.max[axis] <= position {
+                        left.push(reference);
+                    } else if reference.bounds.min[axis] >= position {
+                        right.push(reference);
+                    } else {
+                        let (left_bounds, right_bounds) = self.primitives[reference.index as usize]
+                            .split(&reference.bounds, axis, position);
+                        // A primitive touching the plane has nothing on one side
+                        if left_bounds.is_empty() {
+                            right.push(reference);
+                        } else if right_bounds.is_empty() {
+                            left.push(reference);
+                        } else {
+                            left.push(Reference {
+                                bounds: left_bounds,
+                                ..reference
+                            });
+                            right.push(Reference {
+                                bounds: right_bounds,
+                                ..reference
+                            });
+                            self.references += 1;
+                        }
+                    }
+                }
+                (left, right)
+            }
+            Split::Median => {
+                let right = references.split_off(references.len() / 2);
+                (references, right)
+            }
+        }
+    }
+
+    /// Swaps children with grandchildren when that shrinks the area of the nodes (Kensler 2008)
+    fn rotate(&mut self, index: usize) {
+        let NodeKind::Inner(children) = self.nodes[index].kind else {
+            return;
+        };
+        for child in children {
+            self.rotate(child);
+        }
+
+        // Gain, side of the sibling, side of the grandchild and the grandchild
+        let mut best: Option<(f32, usize, usize, usize)> = None;
+        for (side, &sibling) in children.iter().enumerate() {
+            let NodeKind::Inner(grandchildren) = self.nodes[sibling].kind else {
+                continue;
+            };
+            let current = self.nodes[sibling].bounds.area();
+            let moved = children[1 - side];
+            for (grand_side, &grandchild) in grandchildren.iter().enumerate() {
+                // `moved` takes the place of `grandchild` under `sibling`
+                let kept = grandchildren[1 - grand_side];
+                let area = self.nodes[moved]
+                    .bounds
+                    .union(&self.nodes[kept].bounds)
+                    .area();
+                let gain = current - area;
+                if gain > best.map_or(0.0, |(gain, ..)| gain) {
+                    best = Some((gain, side, grand_side, grandchild));
+                }
+            }
+        }
+
+        if let Some((_, side, grand_side, grandchild)) = best {
+            let sibling = children[side];
+            let moved = children[1 - side];
+            let NodeKind::Inner(mut grandchildren) = self.nodes[sibling].kind else {
+                unreachable!();
+            };
+            grandchildren[grand_side] = moved;
+            self.nodes[sibling].kind = NodeKind::Inner(grandchildren);
+            self.nodes[sibling].bounds = self.nodes[grandchildren[0]]
+                .bounds
+                .union(&self.nodes[grandchildren[1]].bounds);
+
+            let mut children = children;
+            children[1 - side] = grandchild;
+            self.nodes[index].kind = NodeKind::Inner(children);
+        }
+    }
+
+    /// Same definition as [`super::sah_cost`], on the tree being built
+    fn cost(&self, root: usize) -> f32 {
+        let root_area = self.nodes[root].bounds.area();
+        if root_area <= 0.0 {
+            return 0.0;
+        }
+
+        let mut cost = 0.0;
+        let mut stack = vec![root];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            match &node.kind {
+                NodeKind::Leaf(references) => {
+                    cost += references
+                        .iter()
+                        .map(|reference| SAH_INTERSECTION_COST * reference.bounds.area())
+                        .sum::<f32>();
+                }
+                NodeKind::Inner(children) => {
+                    for &child in children {
+                        cost += SAH_TRAVERSAL_COST * self.nodes[child].bounds.area();
+                        stack.push(child);
+                    }
+                }
+            }
+        }
+        cost / root_area
+    }
+
+    /// Writes the nodes in the layout the shader traverses: every child is preceded by a
+    /// node holding its bounds, whose exit index skips the child.
+    /// The primitives of a leaf are consecutive leaf nodes.
+    fn flatten(&self, index: usize, nodes: &mut Vec<GpuNode>) {
+        match &self.nodes[index].kind {
+            NodeKind::Leaf(references) => {
+                for reference in references {
+                    nodes.push(GpuNode {
+                        min: reference.bounds.min,
+                        entry_index: reference.index | LEAF_FLAG,
+                        max: reference.bounds.max,
+                        exit_index: nodes.len() as u32 + 1,
+                    });
+                }
+            }
+            NodeKind::Inner(children) => {
+                for &child in children {
+                    let bounds = self.nodes[child].bounds;
+                    let wrapper = nodes.len();
+                    nodes.push(GpuNode {
+                        min: bounds.min,
+                        entry_index: wrapper as u32 + 1,
+                        max: bounds.max,
+                        exit_index: 0,
+                    });
+                    self.flatten(child, nodes);
+                    nodes[wrapper].exit_index = nodes.len() as u32;
+                }
+            }
+        }
+    }
+
+    fn wide_child(&self, index: usize) -> WideChild<'_> {
+        match &self.nodes[index].kind {
+            NodeKind::Leaf(references) => WideChild::References(references),
+            NodeKind::Inner(_) => WideChild::Node(index),
+        }
+    }
+
+    fn wide_child_bounds(&self, child: &WideChild) -> Bounds {
+        match child {
+            WideChild::Node(index) => self.nodes[*index].bounds,
+            WideChild::References(references) => union(references),
+        }
+    }
+
+    /// Writes the subtree as nodes of `width` children, each [`wide_node_units`] long.
+    /// Children are collapsed from the binary tree, opening the largest first.
+    /// Every leaf child holds a single primitive.
+    fn flatten_wide(&self, child: WideChild, width: usize, words: &mut Vec<u32>) {
+        let mut children = vec![child];
+        while children.len() < width {
+            let largest = children
+                .iter()
+                .enumerate()
+                .filter(|(_, child)| !child.is_primitive())
+                .map(|(index, child)| (index, self.wide_child_bounds(child).area()))
+                .max_by(|(_, a), (_, b)| a.total_cmp(b));
+            let Some((index, _)) = largest else {
+                break;
+            };
+            match children.swap_remove(index) {
+                WideChild::Node(index) => {
+                    let NodeKind::Inner([left, right]) = self.nodes[index].kind else {
+                        unreachable!();
+                    };
+                    children.push(self.wide_child(left));
+                    children.push(self.wide_child(right));
+                }
+                WideChild::References(references) => {
+                    let (left, right) = references.split_at(references.len() / 2);
+                    children.push(WideChild::References(left));
+                    children.push(WideChild::References(right));
+                }
+            }
+        }
+
+        let start = words.len();
+        words.resize(start + 8 * wide_node_units(width), 0);
+        let child_bounds = children
+            .iter()
+            .map(|child| self.wide_child_bounds(child))
+            .collect::<Vec<_>>();
+        quantize_wide_node(&mut words[start..], width, &child_bounds);
+
+        for slot in 0..width {
+            let Some(child) = children.get(slot) else {
+                words[start + 4 + slot] = WIDE_EMPTY_CHILD;
+                continue;
+            };
+
+            words[start + 4 + slot] = match child {
+                WideChild::References([reference]) => reference.index | LEAF_FLAG,
+                _ => {
+                    let offset = words.len() / 8;
+                    self.flatten_wide(child.clone(), width, words);
+                    offset as u32
+                }
+            };
+        }
+    }
+}
+
+fn object_bin(reference: &Reference, centroids: &Bounds, axis: usize, count: usize) -> usize {
+    let extent = centroids.max[axis] - centroids.min[axis];
+    let offset = (reference.bounds.center()[axis] - centroids.min[axis]) / extent;
+    ((offset * count as f32) as usize).min(count - 1)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bin {
+    bounds: Bounds,
+    /// References starting in this bin
+    entries: usize,
+    /// References ending in this bin
+    exits: usize,
+}
+
+impl Default for Bin {
+    fn default() -> Self {
+        Self {
+            bounds: Bounds::EMPTY,
+            entries: 0,
+            exits: 0,
+        }
+    }
+}
+
+/// The references on one side of a split plane
+#[derive(Debug, Clone, Copy)]
+struct Side {
+    bounds: Bounds,
+    count: usize,
+}
+
+impl Side {
+    const EMPTY: Self = Self {
+        bounds: Bounds::EMPTY,
+        count: 0,
+    };
+
+    fn cost(&self) -> f32 {
+        self.bounds.area() * self.count as f32
+    }
+}
+
+/// Both sides of every plane between two bins
+fn sweep(bins: &[Bin]) -> Vec<(Side, Side)> {
+    let mut planes = vec![(Side::EMPTY, Side::EMPTY); bins.len() - 1];
+
+    let mut left = Side::EMPTY;
+    for (plane, bin) in planes.iter_mut().zip(bins) {
+        left = Side {
+            bounds: left.bounds.union(&bin.bounds),
+            count: left.count + bin.entries,
+        };
+        plane.0 = left;
+    }
+    let mut right = Side::EMPTY;
+    for (plane, bin) in planes.iter_mut().zip(&bins[1..]).rev() {
+        right = Side {
+            bounds: right.bounds.union(&bin.bounds),
+            count: right.count + bin.exits,
+        };
+        plane.1 = right;
+    }
+    planes
+}
+
+/// A child of a wide node: an inner node of the binary tree, or some references of a leaf
+#[derive(Clone)]
+enum WideChild<'a> {
+    Node(usize),
+    References(&'a [Reference]),
+}
+
+impl WideChild<'_> {
+    fn is_primitive(&self) -> bool {
+        matches!(self, WideChild::References([_]))
+    }
+}
+
+/// Number of [`GpuNode`]s a wide node takes: its origin and exponents, the index of each
+/// child and the 6 quantized planes of each child, one byte per plane.
+pub fn wide_node_units(width: usize) -> usize {
+    (4 + width + 6 * width / 4).div_ceil(8)
+}
+
+/// Writes the origin, the exponents and the quantized child boxes of a wide node,
+/// leaving the child indices as they are. Returns the bounds of the node.
+fn quantize_wide_node(words: &mut [u32], width: usize, children: &[Bounds]) -> Bounds {
+    let bounds = children
+        .iter()
+        .fold(Bounds::EMPTY, |bounds, child| bounds.union(child));
+    let (exponents, scale) = quantization(&bounds);
+
+    words[..3].copy_from_slice(&bounds.min.to_array().map(f32::to_bits));
+    words[3] = exponents;
+
+    let planes = 4 + width;
+    words[planes..planes + 6 * width / 4].fill(0);
+    for (slot, child) in children.iter().enumerate() {
+        let low = ((child.min - bounds.min) / scale).floor();
+        let high = ((child.max - bounds.min) / scale).ceil();
+        let quantized = [low, high].map(|value| value.clamp(Vec3::ZERO, Vec3::splat(255.0)));
+        for (plane, value) in quantized
+            .iter()
+            .flat_map(|value| value.to_array())
+            .enumerate()
+        {
+            let word = planes + plane * width / 4 + slot / 4;
+            words[word] |= (value as u32) << (8 * (slot % 4));
+        }
+    }
+    bounds
+}
+
+/// Recomputes the child boxes of a flattened wide BVH bottom-up, keeping its topology.
+/// `leaf_aabb` returns the bounds of the primitive referenced by a leaf child.
+pub fn refit_wide_nodes(
+    nodes: &mut [GpuNode],
+    width: usize,
+    leaf_aabb: impl Fn(u32) -> (Vec3, Vec3),
+) {
+    let units = wide_node_units(width);
+    let mut words = nodes.iter().flat_map(GpuNode::to_words).collect::<Vec<_>>();
+    let mut bounds = vec![Bounds::EMPTY; nodes.len() / units];
+
+    // Every wide node takes the same number of units, and children are stored after their parent
+    for node in (0..bounds.len()).rev() {
+        let start = 8 * units * node;
+        let children = words[start + 4..start + 4 + width]
+            .iter()
+            .take_while(|&&child| child != WIDE_EMPTY_CHILD)
+            .map(|&child| {
+                if child >= LEAF_FLAG {
+                    let (min, max) = leaf_aabb(child & !LEAF_FLAG);
+                    Bounds { min, max }
+                } else {
+                    bounds[child as usize / units]
+                }
+            })
+            .collect::<Vec<_>>();
+        bounds[node] = quantize_wide_node(&mut words[start..], width, &children);
+    }
+
+    for (node, words) in nodes.iter_mut().zip(words.chunks_exact(8)) {
+        *node = GpuNode::from_words(words.try_into().unwrap());
+    }
+}
+
+/// Biased exponents of the power of two steps that cover `bounds` in 255 steps,
+/// packed one byte per axis, and the steps themselves
+fn quantization(bounds: &Bounds) -> (u32, Vec3) {
+    let mut exponents = 0;
+    let mut scale = Vec3::ZERO;
+    for axis in 0..3 {
+        let extent = bounds.max[axis] - bounds.min[axis];
+        let mut exponent = if extent > 0.0 {
+            ((extent / 255.0).log2().ceil() as i32).clamp(-126, 127)
+        } else {
+            -126
+        };
+        while exponent < 127 && 2f32.powi(exponent) * 255.0 < extent {
+            exponent += 1;
+        }
+        exponents |= ((exponent + 127) as u32) << (8 * axis);
+        scale[axis] = 2f32.powi(exponent);
+    }
+    (exponents, scale)
+}
@@ -0,0 +1,409 @@
+use super::{
+    bvh_builder::{refit_wide_nodes, BvhPrimitive},
+    lbvh::LbvhBuilds,
+    mesh::{prepare_mesh_assets, GpuMesh, GpuVertexCompact, MeshRenderAssets},
+    refit_nodes, GpuMeshIndex,
+};
+use bevy::{
+    prelude::*,
+    reflect::Struct,
+    render::{
+        mesh::{
+            morph::{MeshMorphWeights, MorphAttributes},
+            skinning::{SkinnedMesh, SkinnedMeshInverseBindposes},
+            VertexAttributeValues,
+        },
+        primitives::Aabb,
+        renderer::{RenderDevice, RenderQueue},
+        Extract, Render, RenderApp, RenderSet,
+    },
+};
+use itertools::Itertools;
+use std::collections::BTreeMap;
+
+/// Deforms the meshes of skinned and morphed instances on the CPU.
+/// Each of these instances traces a private copy of its mesh, whose BVH is refitted
+/// (or rebuilt by the GPU builder) every time its pose changes.
+pub struct DeformPlugin;
+impl Plugin for DeformPlugin {
+    fn build(&self, app: &mut App) {
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .init_resource::<ExtractedPoses>()
+                .init_resource::<DeformedMeshes>()
+                .add_systems(ExtractSchedule, extract_poses)
+                .add_systems(
+                    Render,
+                    (
+                        prepare_poses.before(prepare_mesh_assets),
+                        deform_meshes.after(prepare_mesh_assets),
+                    )
+                        .in_set(RenderSet::PrepareAssets),
+                );
+        }
+    }
+}
+
+/// Joint influences and morph targets of a mesh, read once when it is loaded
+#[derive(Debug, Default, Clone)]
+pub struct MeshDeformation {
+    pub joint_indices: Vec<[u16; 4]>,
+    pub joint_weights: Vec<[f32; 4]>,
+    /// Displacement of every vertex, by target
+    pub morph_targets: Vec<Vec<MorphDisplacement>>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MorphDisplacement {
+    pub position: Vec3,
+    pub normal: Vec3,
+}
+
+/// Joint matrices and morph weights of an instance
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MeshPose {
+    /// World transforms of the joints, times their inverse bind pose
+    pub joints: Vec<Mat4>,
+    pub morph_weights: Vec<f32>,
+}
+
+impl MeshDeformation {
+    /// Reads the joints and morph targets of a mesh, `None` if it has neither.
+    /// `morph_targets` is the image set by [`Mesh::set_morph_targets`], see [`morph_target_image`].
+    pub fn new(mesh: &Mesh, morph_targets: Option<&Image>) -> Option<Self> {
+        let joint_indices = match mesh.attribute(Mesh::ATTRIBUTE_JOINT_INDEX) {
+            Some(VertexAttributeValues::Uint16x4(indices)) => indices.clone(),
+            _ => vec![],
+        };
+        let joint_weights = match mesh.attribute(Mesh::ATTRIBUTE_JOINT_WEIGHT) {
+            Some(VertexAttributeValues::Float32x4(weights)) => weights.clone(),
+            _ => vec![],
+        };
+        let morph_targets = morph_targets
+            .map(|image| read_morph_targets(image, mesh.count_vertices()))
+            .unwrap_or_default();
+
+        if (joint_indices.is_empty() || joint_weights.is_empty()) && morph_targets.is_empty() {
+            return None;
+        }
+        Some(Self {
+            joint_indices,
+            joint_weights,
+            morph_targets,
+        })
+    }
+
+    /// Whether the pose moves the vertices to world space with the joints
+    pub fn is_skinned(&self, pose: &MeshPose) -> bool {
+        !self.joint_indices.is_empty() && !pose.joints.is_empty()
+    }
+
+    /// The vertices in the pose, like bevy's vertex shader: the weighted targets are added,
+    /// then the blend of the joint matrices is applied.
+    pub fn deform(&self, vertices: &[GpuVertexCompact], pose: &MeshPose) -> Vec<GpuVertexCompact> {
+        let skinned = self.is_skinned(pose);
+        vertices
+            .iter()
+            .enumerate()
+            .map(|(index, vertex)| {
+                let mut position = vertex.position;
+                let mut normal = vertex.normal;
+                for (target, weight) in self.morph_targets.iter().zip(&pose.morph_weights) {
+                    if let Some(displacement) = target.get(index) {
+                        position += *weight * displacement.position;
+                        normal += *weight * displacement.normal;
+                    }
+                }
+
+                if skinned {
+                    let model = self.skin_model(index, &pose.joints);
+                    position = model.transform_point3(position);
+                    normal = Mat3::from_mat4(model).inverse().transpose() * normal;
+                }
+
+                GpuVertexCompact {
+                    position,
+                    normal: normal.normalize_or_zero(),
+                    ..*vertex
+                }
+            })
+            .collect()
+    }
+
+    /// Blend of the joint matrices influencing a vertex
+    fn skin_model(&self, vertex: usize, joints: &[Mat4]) -> Mat4 {
+        let (Some(indices), Some(weights)) = (
+            self.joint_indices.get(vertex),
+            self.joint_weights.get(vertex),
+        ) else {
+            return Mat4::IDENTITY;
+        };
+        indices
+            .iter()
+            .zip(weights)
+            .map(|(&joint, &weight)| {
+                *joints.get(joint as usize).unwrap_or(&Mat4::IDENTITY) * weight
+            })
+            .fold(Mat4::ZERO, |model, joint| model + joint)
+    }
+}
+
+/// The morph targets of a mesh are private, but reachable by reflection
+pub fn morph_target_image(mesh: &Mesh) -> Option<&Handle<Image>> {
+    mesh.field("morph_targets")?
+        .downcast_ref::<Option<Handle<Image>>>()?
+        .as_ref()
+}
+
+/// Reads a [`MorphTargetImage`](bevy::render::mesh::morph::MorphTargetImage):
+/// a layer by target, with the position, normal and tangent displacements of each vertex.
+fn read_morph_targets(image: &Image, vertex_count: usize) -> Vec<Vec<MorphDisplacement>> {
+    let size = image.texture_descriptor.size;
+    let layer = (size.width * size.height) as usize;
+    let targets = size.depth_or_array_layers as usize;
+    let components = read_floats(&image.data);
+    if layer < vertex_count * MorphAttributes::COMPONENT_COUNT || components.len() < layer * targets
+    {
+        return vec![];
+    }
+
+    (0..targets)
+        .map(|target| {
+            (0..vertex_count)
+                .map(|vertex| {
+                    let start = target * layer + vertex * MorphAttributes::COMPONENT_COUNT;
+                    MorphDisplacement {
+                        position: Vec3::from_slice(&components[start..]),
+                        normal: Vec3::from_slice(&components[start + 3..]),
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn read_floats(data: &[u8]) -> Vec<f32> {
+    data.chunks_exact(4)
+        .map(|bytes| f32::from_ne_bytes(bytes.try_into().unwrap()))
+        .collect()
+}
+
+/// Deforms `mesh`, a copy of `bind_pose`, and refits its BVH.
+/// A BVH left to the GPU builder is not touched.
+/// Returns whether the vertices were moved to world space by the joints.
+pub fn deform_mesh(bind_pose: &GpuMesh, pose: &MeshPose, mesh: &mut GpuMesh) -> bool {
+    let Some(deformation) = &bind_pose.deformation else {
+        return false;
+    };
+    mesh.vertices = deformation.deform(&bind_pose.vertices, pose);
+    for (primitive, bind_pose) in mesh.primitives.iter_mut().zip(&bind_pose.primitives) {
+        for (vertex, bind_pose) in primitive.vertices.iter_mut().zip(&bind_pose.vertices) {
+            vertex.position = mesh.vertices[bind_pose.index as usize].position;
+        }
+    }
+
+    if !mesh.bvh.on_gpu {
+        let primitives = &mesh.primitives;
+        let leaf_aabb = |index: u32| {
+            let bounds = primitives[index as usize].bounds();
+            (bounds.min, bounds.max)
+        };
+        match mesh.bvh.layout.width() {
+            None => refit_nodes(&mut mesh.nodes, leaf_aabb),
+            Some(width) => refit_wide_nodes(&mut mesh.nodes, width, leaf_aabb),
+        }
+    }
+    deformation.is_skinned(pose)
+}
+
+/// Poses of every deformed instance, extracted each frame
+#[derive(Default, Resource)]
+pub struct ExtractedPoses(Vec<(Entity, Handle<Mesh>, MeshPose)>);
+
+#[allow(clippy::type_complexity)]
+fn extract_poses(
+    mut commands: Commands,
+    query: Extract<
+        Query<
+            (
+                Entity,
+                &Handle<Mesh>,
+                Option<&SkinnedMesh>,
+                Option<&MeshMorphWeights>,
+            ),
+            Or<(With<SkinnedMesh>, With<MeshMorphWeights>)>,
+        >,
+    >,
+    joints: Extract<Query<&GlobalTransform>>,
+    meshes: Extract<Res<Assets<Mesh>>>,
+    inverse_bindposes: Extract<Res<Assets<SkinnedMeshInverseBindposes>>>,
+) {
+    let mut poses = vec![];
+    for (entity, handle, skin, weights) in &query {
+        let Some(mesh) = meshes.get(handle) else {
+            continue;
+        };
+
+        let joints = skin
+            .filter(|_| mesh.attribute(Mesh::ATTRIBUTE_JOINT_INDEX).is_some())
+            .and_then(|skin| {
+                let inverse_bindposes = inverse_bindposes.get(&skin.inverse_bindposes)?;
+                skin.joints
+                    .iter()
+                    .zip(inverse_bindposes.iter())
+                    .map(|(joint, inverse_bindpose)| {
+                        let joint = joints.get(*joint).ok()?;
+                        Some(joint.compute_matrix() * *inverse_bindpose)
+                    })
+                    .collect::<Option<Vec<_>>>()
+            })
+            .unwrap_or_default();
+        let morph_weights = weights
+            .filter(|_| mesh.has_morph_targets())
+            .map(|weights| weights.weights().to_vec())
+            .unwrap_or_default();
+
+        if !joints.is_empty() || !morph_weights.is_empty() {
+            let pose = MeshPose {
+                joints,
+                morph_weights,
+            };
+            poses.push((entity, handle.clone_weak(), pose));
+        }
+    }
+
+    commands.insert_resource(ExtractedPoses(poses));
+}
+
+/// Private copies of the meshes of deformed instances, by entity.
+/// They live in the mesh buffers next to the shared meshes, placed by `prepare_mesh_assets`.
+#[derive(Default, Resource, Deref, DerefMut)]
+pub struct DeformedMeshes(BTreeMap<Entity, DeformedMesh>);
+
+pub struct DeformedMesh {
+    pub source: Handle<Mesh>,
+    pub pose: MeshPose,
+    /// Where the copy lives in the buffers, once its source is loaded
+    pub index: Option<GpuMeshIndex>,
+    /// Whether the copy is in world space, and not in the space of its instance
+    pub skinned: bool,
+    bind_pose: Option<GpuMesh>,
+    mesh: GpuMesh,
+    /// The pose changed since the copy was deformed
+    dirty: bool,
+}
+
+impl DeformedMesh {
+    fn new(source: Handle<Mesh>, pose: MeshPose) -> Self {
+        Self {
+            source,
+            pose,
+            index: None,
+            skinned: false,
+            bind_pose: None,
+            mesh: default(),
+            dirty: true,
+        }
+    }
+
+    pub fn mesh(&self) -> Option<&GpuMesh> {
+        self.bind_pose.as_ref().map(|_| &self.mesh)
+    }
+
+    /// Copies the loaded source, to be placed in the buffers
+    pub fn set_bind_pose(&mut self, bind_pose: &GpuMesh) {
+        self.mesh = bind_pose.clone();
+        self.bind_pose = Some(bind_pose.clone());
+    }
+
+    /// Records where the copy was placed, it is deformed there before the frame is traced
+    pub fn place(&mut self, index: GpuMeshIndex) {
+        self.index = Some(index);
+        self.dirty = true;
+    }
+
+    /// Takes the copy out of the buffers, until its source is loaded again
+    pub fn release(&mut self, render_assets: &mut MeshRenderAssets, builds: &mut LbvhBuilds) {
+        if let Some(index) = self.index.take() {
+            render_assets.free(&index, &self.mesh);
+            builds.cancel_mesh(&index);
+        }
+        self.bind_pose = None;
+    }
+
+    /// World space bounds of a skinned copy, otherwise the bounds in the space of its instance
+    pub fn aabb(&self) -> Aabb {
+        self.mesh.aabb()
+    }
+}
+
+/// Follows the instances that are deformed, and their poses
+fn prepare_poses(
+    mut poses: ResMut<ExtractedPoses>,
+    mut deformed: ResMut<DeformedMeshes>,
+    mut render_assets: ResMut<MeshRenderAssets>,
+    mut builds: ResMut<LbvhBuilds>,
+) {
+    let poses: BTreeMap<_, _> = poses
+        .0
+        .drain(..)
+        .map(|(entity, source, pose)| (entity, (source, pose)))
+        .collect();
+
+    let removed = deformed
+        .keys()
+        .filter(|entity| !poses.contains_key(entity))
+        .copied()
+        .collect_vec();
+    for entity in removed {
+        let mut copy = deformed.remove(&entity).unwrap();
+        copy.release(&mut render_assets, &mut builds);
+    }
+
+    for (entity, (source, pose)) in poses {
+        match deformed.get(&entity) {
+            Some(copy) if copy.source == source && copy.pose == pose => {}
+            Some(copy) if copy.source == source => {
+                let copy = deformed.get_mut(&entity).unwrap();
+                copy.pose = pose;
+                copy.dirty = true;
+            }
+            _ => {
+                let copy = DeformedMesh::new(source, pose);
+                if let Some(mut old_copy) = deformed.insert(entity, copy) {
+                    old_copy.release(&mut render_assets, &mut builds);
+                }
+            }
+        }
+    }
+}
+
+/// Deforms the copies whose pose changed, and uploads them
+pub fn deform_meshes(
+    mut deformed: ResMut<DeformedMeshes>,
+    mut render_assets: ResMut<MeshRenderAssets>,
+    mut builds: ResMut<LbvhBuilds>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    if !deformed
+        .values()
+        .any(|copy| copy.dirty && copy.index.is_some())
+    {
+        return;
+    }
+
+    for copy in deformed.values_mut() {
+        let (Some(index), Some(bind_pose), true) = (copy.index, &copy.bind_pose, copy.dirty) else {
+            continue;
+        };
+        copy.skinned = deform_mesh(bind_pose, &copy.pose, &mut copy.mesh);
+        copy.dirty = false;
+
+        if copy.mesh.bvh.on_gpu {
+            builds.build_mesh(index, copy.mesh.primitives.len() as u32);
+        }
+        render_assets.update(&index, &copy.mesh);
+    }
+    render_assets.write_buffer(&render_device, &render_queue);
+}
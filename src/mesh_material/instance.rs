@@ -1,5 +1,8 @@
 use super::{
-    mesh::prepare_mesh_assets, GpuMeshIndex, GpuMeshes, GpuNode, GpuNodeBuffer,
+    bvh_builder::{build_bvh, Bounds, BvhBuilder, BvhLayout, BvhPrimitive, BvhSettings},
+    deform::{deform_meshes, DeformedMeshes},
+    lbvh::{lbvh_node_count, placeholder_nodes, LbvhBuilds},
+    refit_nodes, sah_cost, write_dirty_ranges, GpuMeshIndex, GpuMeshes, GpuNode, GpuNodeBuffer,
     GpuStandardMaterials,
 };
 use bevy::{
@@ -9,16 +12,11 @@ use bevy::{
         primitives::Aabb,
         render_resource::*,
         renderer::{RenderDevice, RenderQueue},
-        view::VisibilitySystems,
+        view::{RenderLayers, VisibilitySystems},
         Extract, Render, RenderApp,
     },
     transform::TransformSystem,
 };
-use bvh::{
-    aabb::{Bounded, AABB},
-    bounding_hierarchy::BHShape,
-    bvh::BVH,
-};
 use itertools::Itertools;
 use std::collections::BTreeMap;
 use std::marker::PhantomData;
@@ -30,23 +28,27 @@ impl Plugin for InstancePlugin {
             render_app
                 .init_resource::<ExtractedInstances>()
                 .init_resource::<InstanceRenderAssets>()
-                .add_systems(Render, prepare_instances.after(prepare_mesh_assets));
+                .add_systems(Render, prepare_instances.after(deform_meshes));
         }
     }
 }
 
-#[derive(Default)]
-pub struct GenericInstancePlugin<M: Into<StandardMaterial>>(PhantomData<M>);
+pub struct GenericInstancePlugin<M: Asset>(PhantomData<M>);
+impl<M: Asset> Default for GenericInstancePlugin<M> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
 impl<M> Plugin for GenericInstancePlugin<M>
 where
-    M: Into<StandardMaterial> + Asset,
+    M: Asset,
 {
     fn build(&self, app: &mut App) {
         app.add_event::<InstanceEvent<M>>().add_systems(
             PostUpdate,
             instance_event_system::<M>
                 .after(TransformSystem::TransformPropagate)
-                .after(VisibilitySystems::CheckVisibility)
+                .after(VisibilitySystems::VisibilityPropagate)
                 .after(VisibilitySystems::CalculateBounds),
         );
 
@@ -56,50 +58,114 @@ where
     }
 }
 
+/// The instance BVH is rebuilt once refitting made its SAH cost grow by this factor
+pub const REBUILD_COST_GROWTH: f32 = 1.5;
+
 #[derive(Default, Resource)]
 pub struct InstanceRenderAssets {
     pub instance_buffer: StorageBuffer<GpuInstanceBuffer>,
     pub instance_node_buffer: StorageBuffer<GpuNodeBuffer>,
+    /// SAH cost of the instance BVH when it was last built
+    build_cost: f32,
 }
 
 impl InstanceRenderAssets {
     pub fn set(&mut self, instances: Vec<GpuInstance>, instance_nodes: Vec<GpuNode>) {
+        self.build_cost = instance_bvh_cost(&instance_nodes, &instances);
+        self.instance_buffer.get_mut().data = instances;
+        self.instance_node_buffer.get_mut().count = instance_nodes.len() as u32;
+        self.instance_node_buffer.get_mut().data = instance_nodes;
+    }
+
+    /// Sets the instances and leaves their BVH to the GPU builder.
+    /// The nodes kept here are placeholders, so the BVH cannot be refitted.
+    pub fn set_on_gpu(&mut self, instances: Vec<GpuInstance>, builds: &mut LbvhBuilds) {
+        builds.build_instances(instances.len() as u32);
+        let instance_nodes = placeholder_nodes(lbvh_node_count(instances.len()));
+        self.build_cost = 0.0;
         self.instance_buffer.get_mut().data = instances;
         self.instance_node_buffer.get_mut().count = instance_nodes.len() as u32;
         self.instance_node_buffer.get_mut().data = instance_nodes;
     }
 
+    /// Replaces instances that only moved, refits the BVH around them and uploads what changed.
+    /// Returns `false` if the BVH must be rebuilt instead, because the instances were not
+    /// uploaded yet or because its quality degraded too much.
+    pub fn refit(&mut self, instances: Vec<GpuInstance>, queue: &RenderQueue) -> bool {
+        let (Some(instance_buffer), Some(node_buffer)) = (
+            self.instance_buffer.buffer(),
+            self.instance_node_buffer.buffer(),
+        ) else {
+            return false;
+        };
+        let old_instances = &self.instance_buffer.get().data;
+        if old_instances.len() != instances.len() {
+            return false;
+        }
+        if *old_instances == instances {
+            return true;
+        }
+
+        let old_nodes = &self.instance_node_buffer.get().data;
+        let Some(nodes) = refit_instance_nodes(old_nodes, &instances, self.build_cost) else {
+            return false;
+        };
+
+        write_dirty_ranges::<GpuInstanceBuffer, _>(
+            queue,
+            instance_buffer,
+            old_instances,
+            &instances,
+        );
+        write_dirty_ranges::<GpuNodeBuffer, _>(queue, node_buffer, old_nodes, &nodes);
+
+        self.instance_buffer.get_mut().data = instances;
+        self.instance_node_buffer.get_mut().data = nodes;
+        true
+    }
+
     pub fn write_buffer(&mut self, device: &RenderDevice, queue: &RenderQueue) {
         self.instance_buffer.write_buffer(device, queue);
         self.instance_node_buffer.write_buffer(device, queue);
     }
 }
 
+/// Instances follow `InheritedVisibility` rather than the frustum culling of the main view,
+/// as objects out of view still cast light and shadows.
 #[derive(Event)]
-pub enum InstanceEvent<M: Into<StandardMaterial> + Asset> {
-    Created(Entity, Handle<Mesh>, Handle<M>, ViewVisibility),
-    Modified(Entity, Handle<Mesh>, Handle<M>, ViewVisibility),
+pub enum InstanceEvent<M: Asset> {
+    Created(Entity, Handle<Mesh>, Handle<M>, InheritedVisibility),
+    Modified(Entity, Handle<Mesh>, Handle<M>, InheritedVisibility),
     Removed(Entity),
 }
 
 #[allow(clippy::type_complexity)]
-fn instance_event_system<M: Into<StandardMaterial> + Asset>(
+fn instance_event_system<M: Asset>(
     mut events: EventWriter<InstanceEvent<M>>,
     mut removed: RemovedComponents<Handle<Mesh>>,
+    mut removed_layers: RemovedComponents<RenderLayers>,
+    mut removed_visibility: RemovedComponents<RayVisibility>,
+    mut removed_overrides: RemovedComponents<InstanceMaterialOverride>,
+    mut removed_user_data: RemovedComponents<InstanceUserData>,
     mut set: ParamSet<(
         Query<
-            (Entity, &Handle<Mesh>, &Handle<M>, &ViewVisibility),
+            (Entity, &Handle<Mesh>, &Handle<M>, &InheritedVisibility),
             Or<(Added<Handle<Mesh>>, Added<Handle<M>>)>,
         >,
         Query<
-            (Entity, &Handle<Mesh>, &Handle<M>, &ViewVisibility),
+            (Entity, &Handle<Mesh>, &Handle<M>, &InheritedVisibility),
             Or<(
                 Changed<GlobalTransform>,
                 Changed<Handle<Mesh>>,
                 Changed<Handle<M>>,
-                Changed<ViewVisibility>,
+                Changed<InheritedVisibility>,
+                Changed<RenderLayers>,
+                Changed<RayVisibility>,
+                Changed<InstanceMaterialOverride>,
+                Changed<InstanceUserData>,
             )>,
         >,
+        Query<(Entity, &Handle<Mesh>, &Handle<M>, &InheritedVisibility)>,
     )>,
 ) {
     for entity in removed.read() {
@@ -113,7 +179,6 @@ fn instance_event_system<M: Into<StandardMaterial> + Asset>(
             *visibility,
         ));
     }
-    // TODO: ViewVisibility is marked as changed every frame, even when they do not change
     for (entity, mesh, material, visibility) in &set.p1() {
         events.send(InstanceEvent::Modified(
             entity,
@@ -122,40 +187,79 @@ fn instance_event_system<M: Into<StandardMaterial> + Asset>(
             *visibility,
         ));
     }
+    // Back to the defaults
+    let instances = set.p2();
+    let removed = removed_layers
+        .read()
+        .chain(removed_visibility.read())
+        .chain(removed_overrides.read())
+        .chain(removed_user_data.read());
+    for entity in removed {
+        if let Ok((entity, mesh, material, visibility)) = instances.get(entity) {
+            events.send(InstanceEvent::Modified(
+                entity,
+                mesh.clone_weak(),
+                material.clone_weak(),
+                *visibility,
+            ));
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ExtractedInstance {
+    entity: Entity,
+    aabb: Aabb,
+    transform: GlobalTransform,
+    mesh: Handle<Mesh>,
+    material: UntypedHandle,
+    visible: bool,
+    layers: u32,
+    ray_visibility: RayVisibility,
+    material_override: InstanceMaterialOverride,
+    user_data: InstanceUserData,
 }
 
-#[allow(clippy::type_complexity)]
 #[derive(Default, Resource)]
 pub struct ExtractedInstances {
-    extracted: Vec<(
-        Entity,
-        Aabb,
-        GlobalTransform,
-        Handle<Mesh>,
-        UntypedHandle,
-        ViewVisibility,
-    )>,
+    extracted: Vec<ExtractedInstance>,
     removed: Vec<Entity>,
 }
 
-fn extract_instances<M: Into<StandardMaterial> + Asset>(
+#[allow(clippy::type_complexity)]
+fn extract_instances<M: Asset>(
     mut events: Extract<EventReader<InstanceEvent<M>>>,
-    query: Extract<Query<(&Aabb, &GlobalTransform)>>,
+    query: Extract<
+        Query<(
+            &Aabb,
+            &GlobalTransform,
+            Option<&RenderLayers>,
+            Option<&RayVisibility>,
+            Option<&InstanceMaterialOverride>,
+            Option<&InstanceUserData>,
+        )>,
+    >,
     mut extracted_instances: ResMut<ExtractedInstances>,
 ) {
     for event in events.read() {
         match event {
             InstanceEvent::Created(entity, mesh, material, visibility)
             | InstanceEvent::Modified(entity, mesh, material, visibility) => {
-                if let Ok((aabb, transform)) = query.get(*entity) {
-                    extracted_instances.extracted.push((
-                        *entity,
-                        *aabb,
-                        *transform,
-                        mesh.clone_weak(),
-                        material.clone_weak().untyped(),
-                        *visibility,
-                    ));
+                if let Ok((aabb, transform, layers, ray_visibility, material_override, user_data)) =
+                    query.get(*entity)
+                {
+                    extracted_instances.extracted.push(ExtractedInstance {
+                        entity: *entity,
+                        aabb: *aabb,
+                        transform: *transform,
+                        mesh: mesh.clone_weak(),
+                        material: material.clone_weak().untyped(),
+                        visible: visibility.get(),
+                        layers: layer_mask(layers),
+                        ray_visibility: ray_visibility.copied().unwrap_or_default(),
+                        material_override: material_override.copied().unwrap_or_default(),
+                        user_data: user_data.copied().unwrap_or_default(),
+                    });
                 }
             }
             InstanceEvent::Removed(entity) => extracted_instances.removed.push(*entity),
@@ -163,113 +267,202 @@ fn extract_instances<M: Into<StandardMaterial> + Asset>(
     }
 }
 
-type Instances = BTreeMap<Entity, (GpuInstance, ViewVisibility)>;
+impl ExtractedInstance {
+    /// The instance of its mesh, or of the private copy deformed by its pose.
+    /// Returns `None` until both the mesh and the material are ready.
+    fn prepare(
+        &self,
+        meshes: &GpuMeshes,
+        deformed: &DeformedMeshes,
+        materials: &GpuStandardMaterials,
+    ) -> Option<GpuInstance> {
+        let material = *materials.get(&self.material)?;
+        let instance = match deformed.get(&self.entity) {
+            None => {
+                let mesh = *meshes.get(&self.mesh)?;
+                GpuInstance::new(&self.aabb, &self.transform, mesh, material)
+            }
+            Some(copy) => {
+                // Skinned vertices are already in world space
+                let transform = if copy.skinned {
+                    GlobalTransform::IDENTITY
+                } else {
+                    self.transform
+                };
+                GpuInstance::new(&copy.aabb(), &transform, copy.index?, material)
+            }
+        };
+        let mut instance = GpuInstance {
+            layers: self.layers,
+            visibility: self.ray_visibility.bits(),
+            user_data: self.user_data.0,
+            ..instance
+        };
+        instance.set_material_override(&self.material_override);
+        Some(instance)
+    }
+}
+
+type Instances = BTreeMap<Entity, (GpuInstance, ExtractedInstance)>;
 
+/// Instances whose mesh or material is not ready yet.
+/// Meshes are built asynchronously, so this is expected for a few frames after they are loaded.
+type DeferredInstances = BTreeMap<Entity, ExtractedInstance>;
+
+#[allow(clippy::too_many_arguments)]
 fn prepare_instances(
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
     mut render_assets: ResMut<InstanceRenderAssets>,
     mut extracted_instances: ResMut<ExtractedInstances>,
     mut collection: Local<Instances>,
+    mut deferred: Local<DeferredInstances>,
+    mut builds: ResMut<LbvhBuilds>,
     meshes: Res<GpuMeshes>,
+    deformed: Res<DeformedMeshes>,
     materials: Res<GpuStandardMaterials>,
+    settings: Res<BvhSettings>,
 ) {
-    let instance_changed =
-        !extracted_instances.extracted.is_empty() || !extracted_instances.removed.is_empty();
+    let mut instance_changed = !extracted_instances.removed.is_empty();
+    // The nodes of the other builder cannot be refitted
+    let mut topology_changed = settings.is_changed();
 
     for removed in extracted_instances.removed.drain(..) {
-        collection.remove(&removed);
+        deferred.remove(&removed);
+        topology_changed |= collection.remove(&removed).is_some();
+    }
+    for instance in extracted_instances.extracted.drain(..) {
+        deferred.insert(instance.entity, instance);
     }
 
-    let mut prepare_next_frame = vec![];
-
-    for (entity, aabb, transform, &mesh, &material, visibility) in extracted_instances
-        .extracted
-        .drain(..)
-        .filter_map(|(entity, aabb, transform, mesh, material, visibility)| {
-            match (meshes.get(&mesh), materials.get(&material)) {
-                (Some(mesh), Some(material)) => {
-                    Some((entity, aabb, transform, mesh, material, visibility))
-                }
-                _ => {
-                    // TODO: not sure this can happen
-                    error!(
-                        "the mesh {:?} or material {:?} was not ready! how did this happen??",
-                        mesh, material
-                    );
-                    prepare_next_frame.push((entity, aabb, transform, mesh, material, visibility));
-                    None
-                }
+    let meshes_changed = meshes.is_changed() || deformed.is_changed();
+    if meshes_changed {
+        // Follow the meshes to their new place in the buffers, or wait for them to come back.
+        // Deformed meshes also change their bounds.
+        let mut removed = vec![];
+        for (entity, (instance, extracted)) in collection.iter_mut() {
+            match extracted.prepare(&meshes, &deformed, &materials) {
+                Some(prepared) => *instance = prepared,
+                None => removed.push(*entity),
             }
-        })
-    {
-        let transform = transform.compute_matrix();
-        let center = transform.transform_point3a(aabb.center);
-        let vertices = (0..8i32)
-            .map(|index| {
-                let x = 2 * (index & 1) - 1;
-                let y = 2 * ((index >> 1) & 1) - 1;
-                let z = 2 * ((index >> 2) & 1) - 1;
-                let vertex = aabb.half_extents * Vec3A::new(x as f32, y as f32, z as f32);
-                transform.transform_vector3a(vertex)
-            })
-            .collect_vec();
-
-        // TODO: i think bevy::render::view::calculate_bounds already does this
-        let mut min = Vec3A::ZERO;
-        let mut max = Vec3A::ZERO;
-        for vertex in vertices {
-            min = min.min(vertex);
-            max = max.max(vertex);
         }
-        min += center;
-        max += center;
-
-        let min = Vec3::from(min);
-        let max = Vec3::from(max);
-        collection.insert(
-            entity,
-            (
-                GpuInstance {
-                    min,
-                    max,
-                    transform,
-                    inverse_transpose_model: transform.inverse().transpose(),
-                    mesh,
-                    material,
-                },
-                visibility,
-            ),
-        );
+        topology_changed |= !removed.is_empty();
+        for entity in removed {
+            let (_, extracted) = collection.remove(&entity).unwrap();
+            deferred.insert(entity, extracted);
+        }
     }
 
-    extracted_instances
-        .extracted
-        .append(&mut prepare_next_frame);
-
-    if instance_changed || meshes.is_changed() {
-        collection.retain(|_, (_, visibility)| visibility.get());
+    let ready = deferred
+        .values()
+        .filter_map(|extracted| {
+            let instance = extracted.prepare(&meshes, &deformed, &materials)?;
+            Some((extracted.entity, instance))
+        })
+        .collect_vec();
+    for (entity, instance) in ready {
+        let extracted = deferred.remove(&entity).unwrap();
+        let visible = extracted.visible;
+        // Hidden instances are kept, so they come back once shown
+        topology_changed |= match collection.insert(entity, (instance, extracted)) {
+            Some((_, old)) => old.visible != visible,
+            None => visible,
+        };
+        instance_changed = true;
+    }
 
+    if instance_changed || topology_changed || meshes_changed {
         let instances = collection
             .values()
+            .filter(|(_, extracted)| extracted.visible)
             .map(|(instance, _)| instance)
             .cloned()
             .collect_vec();
-        let mut instances_shapes = instances
-            .iter()
-            .map(|instance| GpuInstanceShape(instance.clone(), 0))
-            .collect_vec();
 
-        let instance_nodes = if collection.is_empty() {
-            vec![]
-        } else {
-            let bvh = BVH::build(&mut instances_shapes);
-            bvh.flatten_custom(&GpuNode::pack)
-        };
+        if settings.builder == BvhBuilder::Lbvh {
+            render_assets.set_on_gpu(instances, &mut builds);
+            render_assets.write_buffer(&render_device, &render_queue);
+        } else if topology_changed || !render_assets.refit(instances.clone(), &render_queue) {
+            let instance_nodes = build_instance_nodes(&instances, BvhBuilder::Sah);
+            render_assets.set(instances, instance_nodes);
+            render_assets.write_buffer(&render_device, &render_queue);
+        }
+    }
+}
+
+/// The kinds of rays that hit an instance, all of them without this component.
+/// Bounces off the metal lobe of a material are specular, the others diffuse.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Reflect)]
+#[reflect(Component, Default, PartialEq)]
+pub struct RayVisibility(u32);
+
+impl RayVisibility {
+    pub const CAMERA: Self = Self(1 << 0);
+    pub const DIFFUSE: Self = Self(1 << 1);
+    pub const SPECULAR: Self = Self(1 << 2);
+    /// Reserved, no shadow rays are traced yet
+    pub const SHADOW: Self = Self(1 << 3);
+    /// Reserved, no transmission rays are traced yet
+    pub const TRANSMISSION: Self = Self(1 << 4);
+    pub const ALL: Self = Self(0b11111);
+    pub const NONE: Self = Self(0);
+
+    pub const fn with(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
 
-        render_assets.set(instances, instance_nodes);
-        render_assets.write_buffer(&render_device, &render_queue);
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
     }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for RayVisibility {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+/// Varies the material of one instance, without a material asset per variation.
+/// Applies to standard materials and to the base of extended ones, custom materials read it
+/// from `instance_buffer[hit.instance_index]`.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Reflect)]
+#[reflect(Component, Default, PartialEq)]
+pub struct InstanceMaterialOverride {
+    /// Multiplies the base color
+    pub tint: Color,
+    /// Multiplies the emissive color
+    pub emissive_multiplier: f32,
+    /// Added to the perceptual roughness, clamped to `[0, 1]`
+    pub roughness_offset: f32,
+}
+
+impl Default for InstanceMaterialOverride {
+    fn default() -> Self {
+        Self {
+            tint: Color::WHITE,
+            emissive_multiplier: 1.0,
+            roughness_offset: 0.0,
+        }
+    }
+}
+
+/// Data of an instance for custom shading code, read with `instance_user_data(hit)`
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Reflect)]
+#[reflect(Component, Default, PartialEq)]
+pub struct InstanceUserData(pub Vec4);
+
+/// The bits of the layers, entities without `RenderLayers` are on layer 0
+pub fn layer_mask(layers: Option<&RenderLayers>) -> u32 {
+    let layers = layers.copied().unwrap_or_default();
+    layers.iter().fold(0, |mask, layer| mask | 1 << layer)
 }
 
 /// Container for primitive data
@@ -280,34 +473,119 @@ pub struct GpuInstanceBuffer {
 }
 
 /// This must match the Vertex definition on the shader
-#[derive(Debug, Default, Clone, ShaderType)]
+#[derive(Debug, Default, Clone, PartialEq, ShaderType)]
 pub struct GpuInstance {
     pub min: Vec3,
     pub material: u32,
     pub max: Vec3,
+    /// Mask of the `RenderLayers`, the views skip the instances on none of their layers
+    pub layers: u32,
     pub transform: Mat4,
     pub inverse_transpose_model: Mat4,
     pub mesh: GpuMeshIndex,
+    /// Bits of the [`RayVisibility`]
+    pub visibility: u32,
+    /// See [`InstanceMaterialOverride`]
+    pub emissive_multiplier: f32,
+    pub roughness_offset: f32,
+    pub tint: Vec4,
+    /// See [`InstanceUserData`]
+    pub user_data: Vec4,
 }
 
-/// Used to create BVH
-struct GpuInstanceShape(GpuInstance, usize);
+impl GpuInstance {
+    pub fn new(
+        aabb: &Aabb,
+        transform: &GlobalTransform,
+        mesh: GpuMeshIndex,
+        material: u32,
+    ) -> Self {
+        let transform = transform.compute_matrix();
+        let center = transform.transform_point3a(aabb.center);
+        let vertices = (0..8i32)
+            .map(|index| {
+                let x = 2 * (index & 1) - 1;
+                let y = 2 * ((index >> 1) & 1) - 1;
+                let z = 2 * ((index >> 2) & 1) - 1;
+                let vertex = aabb.half_extents * Vec3A::new(x as f32, y as f32, z as f32);
+                transform.transform_vector3a(vertex)
+            })
+            .collect_vec();
+
+        // TODO: i think bevy::render::view::calculate_bounds already does this
+        let mut min = Vec3A::ZERO;
+        let mut max = Vec3A::ZERO;
+        for vertex in vertices {
+            min = min.min(vertex);
+            max = max.max(vertex);
+        }
+        min += center;
+        max += center;
 
-impl Bounded for GpuInstanceShape {
-    fn aabb(&self) -> AABB {
-        AABB {
-            min: self.0.min.to_array().into(),
-            max: self.0.max.to_array().into(),
+        Self {
+            min: min.into(),
+            max: max.into(),
+            layers: layer_mask(None),
+            transform,
+            inverse_transpose_model: transform.inverse().transpose(),
+            mesh,
+            material,
+            visibility: RayVisibility::ALL.bits(),
+            emissive_multiplier: 1.0,
+            roughness_offset: 0.0,
+            tint: Vec4::ONE,
+            user_data: Vec4::ZERO,
         }
     }
-}
 
-impl BHShape for GpuInstanceShape {
-    fn set_bh_node_index(&mut self, index: usize) {
-        self.1 = index;
+    pub fn set_material_override(&mut self, material_override: &InstanceMaterialOverride) {
+        self.tint = material_override.tint.into();
+        self.emissive_multiplier = material_override.emissive_multiplier;
+        self.roughness_offset = material_override.roughness_offset;
+    }
+
+    pub fn aabb(&self) -> (Vec3, Vec3) {
+        (self.min, self.max)
     }
+}
 
-    fn bh_node_index(&self) -> usize {
-        self.1
+/// SAH cost of a top level BVH, see [`sah_cost`]
+pub fn instance_bvh_cost(nodes: &[GpuNode], instances: &[GpuInstance]) -> f32 {
+    sah_cost(nodes, |index| instances[index as usize].aabb())
+}
+
+/// Refits the top level BVH around instances that moved.
+/// Returns `None` if its cost grew past [`REBUILD_COST_GROWTH`] times `build_cost`,
+/// as it must then be rebuilt.
+pub fn refit_instance_nodes(
+    nodes: &[GpuNode],
+    instances: &[GpuInstance],
+    build_cost: f32,
+) -> Option<Vec<GpuNode>> {
+    let mut nodes = nodes.to_vec();
+    refit_nodes(&mut nodes, |index| instances[index as usize].aabb());
+    let cost = instance_bvh_cost(&nodes, instances);
+    (cost <= build_cost * REBUILD_COST_GROWTH).then_some(nodes)
+}
+
+/// Builds and flattens the top level BVH over `instances`
+pub fn build_instance_nodes(instances: &[GpuInstance], builder: BvhBuilder) -> Vec<GpuNode> {
+    // Refitting keeps the bounds of whole instances, so their boxes are never split,
+    // and it works on the binary layout only
+    let settings = BvhSettings {
+        spatial_split_alpha: None,
+        layout: BvhLayout::Binary,
+        builder,
+        ..default()
+    };
+    build_bvh(instances, &settings).0
+}
+
+impl BvhPrimitive for GpuInstance {
+    fn bounds(&self) -> Bounds {
+        Bounds {
+            min: self.min,
+            max: self.max,
+        }
     }
 }
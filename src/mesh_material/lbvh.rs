@@ -0,0 +1,564 @@
+use super::{
+    bvh_builder::Bounds,
+    instance::InstanceRenderAssets,
+    mesh::{GeometrySettings, MeshRenderAssets},
+    GpuMeshIndex, GpuNode,
+};
+use crate::{
+    error::{RaytracerError, RaytracerErrors},
+    LBVH_SHADER_HANDLE,
+};
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::{render_system, RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet,
+    },
+    utils::HashMap,
+};
+use itertools::Itertools;
+use std::borrow::Cow;
+
+/// Primitives handled by each workgroup of the builder
+const WORKGROUP_SIZE: u32 = 256;
+const RADIX_BITS: u32 = 4;
+/// Passes of the radix sort over the 30 bits of the Morton codes
+const SORT_PASSES: u32 = 8;
+/// Cells of the Morton grid along each axis
+const MORTON_CELLS: f32 = 1024.0;
+/// Sizes of the `TreeNode` and `Aabb` structs of the shader
+const TREE_NODE_SIZE: u64 = 48;
+const AABB_SIZE: u64 = 32;
+
+/// Builds linear BVHs (Karras 2012) on the GPU, for geometry that changes every frame.
+/// The builds queued in [`LbvhBuilds`] run before the frame is traced.
+pub struct LbvhPlugin;
+impl Plugin for LbvhPlugin {
+    fn build(&self, app: &mut App) {
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app.init_resource::<LbvhBuilds>().add_systems(
+                Render,
+                build_bvhs.in_set(RenderSet::Render).before(render_system),
+            );
+        }
+    }
+
+    fn finish(&self, app: &mut App) {
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app.init_resource::<LbvhPipelines>();
+        }
+    }
+}
+
+/// Number of nodes of a linear BVH over `count` primitives: every leaf, and the nodes holding
+/// the bounds of the children of each inner node
+pub fn lbvh_node_count(count: usize) -> usize {
+    (3 * count).saturating_sub(2)
+}
+
+/// Nodes holding the place of a BVH the GPU did not build yet.
+/// Each one skips to the next without anything to hit, so they are safe to traverse.
+pub fn placeholder_nodes(count: usize) -> Vec<GpuNode> {
+    (1..=count as u32)
+        .map(|next| GpuNode {
+            min: Vec3::splat(f32::INFINITY),
+            entry_index: next,
+            max: Vec3::splat(f32::NEG_INFINITY),
+            exit_index: next,
+        })
+        .collect()
+}
+
+/// Power of two step that covers `extent` in [`MORTON_CELLS`] steps, inverted.
+/// Scaling by a power of two is exact, so the shader finds the same cells.
+fn morton_scale(extent: f32) -> f32 {
+    let bits = extent.to_bits();
+    let biased = (bits >> 23) & 0xFF;
+    if biased == 0 {
+        return 0.0;
+    }
+    let mut exponent = biased as i32 - 127 - 10;
+    if bits & 0x7FFFFF != 0 {
+        exponent += 1;
+    }
+    f32::from_bits(((127 - exponent).min(254) as u32) << 23)
+}
+
+/// Spreads 10 bits two bits apart
+fn expand_bits(value: u32) -> u32 {
+    let mut bits = value & 0x3FF;
+    bits = (bits | (bits << 16)) & 0x030000FF;
+    bits = (bits | (bits << 8)) & 0x0300F00F;
+    bits = (bits | (bits << 4)) & 0x030C30C3;
+    bits = (bits | (bits << 2)) & 0x09249249;
+    bits
+}
+
+/// Morton code of `point` on a grid over `bounds`, like the shader
+pub fn morton_code(point: Vec3, bounds: &Bounds) -> u32 {
+    (0..3).fold(0, |code, axis| {
+        let scale = morton_scale(bounds.max[axis] - bounds.min[axis]);
+        let cell = ((point[axis] - bounds.min[axis]) * scale).min(MORTON_CELLS - 1.0) as u32;
+        code | expand_bits(cell) << (2 - axis)
+    })
+}
+
+/// Length of the common prefix of two sorted codes, their index breaks ties
+fn common_prefix(codes: &[u32], i: i32, j: i32) -> i32 {
+    if j < 0 || j >= codes.len() as i32 {
+        return -1;
+    }
+    let (a, b) = (codes[i as usize], codes[j as usize]);
+    if a == b {
+        32 + (i as u32 ^ j as u32).leading_zeros() as i32
+    } else {
+        (a ^ b).leading_zeros() as i32
+    }
+}
+
+/// Children of the inner node `index` of the Karras hierarchy over sorted `codes`.
+/// The `codes.len() - 1` inner nodes come first, then the leaves in sorted order.
+pub fn karras_children(codes: &[u32], index: usize) -> [usize; 2] {
+    let prefix = |j: i32| common_prefix(codes, index as i32, j);
+    let i = index as i32;
+
+    // The range of codes sharing the prefix of the node extends in one direction
+    let direction = if prefix(i + 1) > prefix(i - 1) { 1 } else { -1 };
+    let min_prefix = prefix(i - direction);
+    let mut max_length = 2;
+    while prefix(i + max_length * direction) > min_prefix {
+        max_length *= 2;
+    }
+    let mut length = 0;
+    let mut step = max_length / 2;
+    while step >= 1 {
+        if prefix(i + (length + step) * direction) > min_prefix {
+            length += step;
+        }
+        step /= 2;
+    }
+    let j = i + length * direction;
+
+    // Split where the prefix of the range ends
+    let node_prefix = prefix(j);
+    let mut split = 0;
+    let mut divisor = 2;
+    let mut step = (length + divisor - 1) / divisor;
+    loop {
+        if prefix(i + (split + step) * direction) > node_prefix {
+            split += step;
+        }
+        if step <= 1 {
+            break;
+        }
+        divisor *= 2;
+        step = (length + divisor - 1) / divisor;
+    }
+    let gamma = i + split * direction + direction.min(0);
+
+    let leaf = |leaf: i32| codes.len() - 1 + leaf as usize;
+    let left = if i.min(j) == gamma {
+        leaf(gamma)
+    } else {
+        gamma as usize
+    };
+    let right = if i.max(j) == gamma + 1 {
+        leaf(gamma + 1)
+    } else {
+        gamma as usize + 1
+    };
+    [left, right]
+}
+
+/// Where a GPU build reads its primitives and writes its nodes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LbvhSource {
+    /// The primitives of a mesh, whose nodes are written in its range of the mesh node buffer
+    Mesh(GpuMeshIndex),
+    /// The instances, whose nodes fill the instance node buffer
+    Instances,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LbvhBuild {
+    pub source: LbvhSource,
+    /// Number of primitives or instances
+    pub count: u32,
+}
+
+/// BVHs to build on the GPU before the next frame is traced.
+/// Their nodes must already be allocated, see [`placeholder_nodes`].
+#[derive(Default, Resource)]
+pub struct LbvhBuilds(Vec<LbvhBuild>);
+
+impl LbvhBuilds {
+    /// Replaces a pending build of the same mesh, deformed meshes are built once per frame
+    pub fn build_mesh(&mut self, index: GpuMeshIndex, count: u32) {
+        self.cancel_mesh(&index);
+        self.0.push(LbvhBuild {
+            source: LbvhSource::Mesh(index),
+            count,
+        });
+    }
+
+    /// Replaces a pending build of the instances
+    pub fn build_instances(&mut self, count: u32) {
+        self.0.retain(|build| build.source != LbvhSource::Instances);
+        self.0.push(LbvhBuild {
+            source: LbvhSource::Instances,
+            count,
+        });
+    }
+
+    /// Forgets the pending build of a mesh that left the buffers
+    pub fn cancel_mesh(&mut self, index: &GpuMeshIndex) {
+        self.0
+            .retain(|build| build.source != LbvhSource::Mesh(*index));
+    }
+
+    /// Forgets the pending builds of every mesh, after they moved
+    pub fn cancel_meshes(&mut self) {
+        self.0.retain(|build| build.source == LbvhSource::Instances);
+    }
+}
+
+/// This must match the Params definition on the shader
+#[derive(Debug, Default, Clone, Copy, ShaderType)]
+struct LbvhParams {
+    count: u32,
+    source_offset: u32,
+    node_offset: u32,
+    shift: u32,
+    vertex_offset: u32,
+}
+
+#[derive(Resource)]
+pub struct LbvhPipelines {
+    scratch_layout: BindGroupLayout,
+    geometry_layout: BindGroupLayout,
+    reset: CachedComputePipelineId,
+    /// The primitives are read in the encoding of the meshes
+    primitive_bounds: HashMap<GeometrySettings, CachedComputePipelineId>,
+    instance_bounds: CachedComputePipelineId,
+    morton_codes: CachedComputePipelineId,
+    sort_histogram: CachedComputePipelineId,
+    sort_scan: CachedComputePipelineId,
+    sort_scatter: CachedComputePipelineId,
+    hierarchy: CachedComputePipelineId,
+    node_bounds: CachedComputePipelineId,
+    flatten: CachedComputePipelineId,
+}
+
+fn storage_entry(binding: u32, read_only: bool) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+impl FromWorld for LbvhPipelines {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let params_entry = BindGroupLayoutEntry {
+            binding: 0,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: true,
+                min_binding_size: Some(LbvhParams::min_size()),
+            },
+            count: None,
+        };
+        let mut scratch_entries = vec![params_entry];
+        scratch_entries.extend((1..8).map(|binding| storage_entry(binding, false)));
+        let scratch_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("lbvh_scratch_bind_group_layout"),
+            entries: &scratch_entries,
+        });
+        let geometry_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("lbvh_geometry_bind_group_layout"),
+            entries: &[
+                // Primitives or instances
+                storage_entry(0, true),
+                // Nodes
+                storage_entry(1, false),
+                // Vertices of compact primitives
+                storage_entry(2, true),
+            ],
+        });
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let queue = |entry_point: &'static str, shader_defs: Vec<ShaderDefVal>| {
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some(Cow::Owned(format!("lbvh_{entry_point}_pipeline"))),
+                layout: vec![scratch_layout.clone(), geometry_layout.clone()],
+                push_constant_ranges: vec![],
+                shader: LBVH_SHADER_HANDLE.clone(),
+                shader_defs,
+                entry_point: Cow::Borrowed(entry_point),
+            })
+        };
+
+        Self {
+            reset: queue("reset", vec![]),
+            primitive_bounds: [false, true]
+                .into_iter()
+                .cartesian_product([false, true])
+                .map(|(compact_primitives, quantized_vertices)| {
+                    let geometry = GeometrySettings {
+                        compact_primitives,
+                        quantized_vertices,
+                    };
+                    (geometry, queue("leaf_bounds", geometry.shader_defs()))
+                })
+                .collect(),
+            instance_bounds: queue("leaf_bounds", vec!["LBVH_INSTANCES".into()]),
+            morton_codes: queue("morton_codes", vec![]),
+            sort_histogram: queue("sort_histogram", vec![]),
+            sort_scan: queue("sort_scan", vec![]),
+            sort_scatter: queue("sort_scatter", vec![]),
+            hierarchy: queue("hierarchy", vec![]),
+            node_bounds: queue("node_bounds", vec![]),
+            flatten: queue("flatten", vec![]),
+            scratch_layout,
+            geometry_layout,
+        }
+    }
+}
+
+/// Buffers of the intermediate results, shared by the builds of a frame
+#[derive(Default)]
+struct LbvhScratch {
+    /// Number of primitives the buffers hold
+    capacity: u32,
+    buffers: Vec<Buffer>,
+    params: DynamicUniformBuffer<LbvhParams>,
+}
+
+impl LbvhScratch {
+    fn reserve(&mut self, render_device: &RenderDevice, count: u32) {
+        if count <= self.capacity {
+            return;
+        }
+        self.capacity = count.next_power_of_two();
+
+        let capacity = self.capacity as u64;
+        let groups = capacity.div_ceil(WORKGROUP_SIZE as u64);
+        let sizes = [
+            ("lbvh_keys", 2 * capacity * 4),
+            ("lbvh_values", 2 * capacity * 4),
+            ("lbvh_histograms", (1 << RADIX_BITS) * groups * 4),
+            ("lbvh_tree", 2 * capacity * TREE_NODE_SIZE),
+            ("lbvh_counters", capacity * 4),
+            ("lbvh_centroid_bounds", 6 * 4),
+            ("lbvh_primitive_boxes", capacity * AABB_SIZE),
+        ];
+        self.buffers = sizes
+            .into_iter()
+            .map(|(label, size)| {
+                render_device.create_buffer(&BufferDescriptor {
+                    label: Some(label),
+                    size,
+                    usage: BufferUsages::STORAGE,
+                    mapped_at_creation: false,
+                })
+            })
+            .collect();
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_bvhs(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    pipeline_cache: Res<PipelineCache>,
+    pipelines: Res<LbvhPipelines>,
+    mut builds: ResMut<LbvhBuilds>,
+    mut scratch: Local<LbvhScratch>,
+    meshes: Res<MeshRenderAssets>,
+    instances: Res<InstanceRenderAssets>,
+    mut errors: ResMut<RaytracerErrors>,
+) {
+    if builds.0.is_empty() {
+        return;
+    }
+
+    // The builds wait for the pipelines, the placeholders are traced meanwhile
+    let mut failed = vec![];
+    let mut get = |id| {
+        let state = pipeline_cache.get_compute_pipeline_state(id);
+        failed.extend(RaytracerError::from_pipeline(state));
+        pipeline_cache.get_compute_pipeline(id)
+    };
+    let (
+        Some(reset),
+        Some(primitive_bounds),
+        Some(instance_bounds),
+        Some(morton_codes),
+        Some(sort_histogram),
+        Some(sort_scan),
+        Some(sort_scatter),
+        Some(hierarchy),
+        Some(node_bounds),
+        Some(flatten),
+    ) = (
+        get(pipelines.reset),
+        get(pipelines.primitive_bounds[&meshes.geometry()]),
+        get(pipelines.instance_bounds),
+        get(pipelines.morton_codes),
+        get(pipelines.sort_histogram),
+        get(pipelines.sort_scan),
+        get(pipelines.sort_scatter),
+        get(pipelines.hierarchy),
+        get(pipelines.node_bounds),
+        get(pipelines.flatten),
+    )
+    else {
+        for error in failed {
+            errors.send(error);
+        }
+        return;
+    };
+
+    let builds = std::mem::take(&mut builds.0);
+    let count = builds.iter().map(|build| build.count).max().unwrap_or(0);
+    scratch.reserve(&render_device, count);
+
+    // The parameters of each build, then of each of its sort passes
+    scratch.params.clear();
+    let offsets = builds
+        .iter()
+        .map(|build| {
+            let (source_offset, node_offset, vertex_offset) = match build.source {
+                LbvhSource::Mesh(index) => (index.primitive, index.node.x, index.vertex),
+                LbvhSource::Instances => (0, 0, 0),
+            };
+            let mut params = LbvhParams {
+                count: build.count,
+                source_offset,
+                node_offset,
+                shift: 0,
+                vertex_offset,
+            };
+            let offset = scratch.params.push(params);
+            let passes = (0..SORT_PASSES)
+                .map(|pass| {
+                    params.shift = pass * RADIX_BITS;
+                    scratch.params.push(params)
+                })
+                .collect::<Vec<_>>();
+            (offset, passes)
+        })
+        .collect::<Vec<_>>();
+    scratch.params.write_buffer(&render_device, &render_queue);
+
+    let Some(params) = scratch.params.binding() else {
+        return;
+    };
+    let buffers = &scratch.buffers;
+    let scratch_bind_group = render_device.create_bind_group(
+        "lbvh_scratch_bind_group",
+        &pipelines.scratch_layout,
+        &BindGroupEntries::sequential((
+            params,
+            buffers[0].as_entire_binding(),
+            buffers[1].as_entire_binding(),
+            buffers[2].as_entire_binding(),
+            buffers[3].as_entire_binding(),
+            buffers[4].as_entire_binding(),
+            buffers[5].as_entire_binding(),
+            buffers[6].as_entire_binding(),
+        )),
+    );
+    // The instances bind the vertices of the first chunk too, the layout is shared
+    let geometry_bind_group =
+        |source: Option<BindingResource>, nodes: Option<BindingResource>, chunk: usize| {
+            let (source, nodes, vertices) = (source?, nodes?, meshes.vertex_binding(chunk)?);
+            Some(render_device.create_bind_group(
+                "lbvh_geometry_bind_group",
+                &pipelines.geometry_layout,
+                &BindGroupEntries::sequential((source, nodes, vertices)),
+            ))
+        };
+    let mesh_bind_groups = (0..meshes.chunk_count())
+        .map(|chunk| {
+            geometry_bind_group(
+                meshes.primitive_binding(chunk),
+                meshes.node_binding(chunk),
+                chunk,
+            )
+        })
+        .collect_vec();
+    let instance_bind_group = geometry_bind_group(
+        instances.instance_buffer.binding(),
+        instances.instance_node_buffer.binding(),
+        0,
+    );
+
+    let mut command_encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("lbvh_command_encoder"),
+    });
+    {
+        let mut pass = LbvhPass {
+            pass: command_encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("lbvh_pass"),
+            }),
+            scratch: &scratch_bind_group,
+        };
+        for (build, (offset, pass_offsets)) in builds.iter().zip(offsets) {
+            let (geometry_bind_group, leaf_bounds) = match build.source {
+                LbvhSource::Mesh(index) => (
+                    mesh_bind_groups
+                        .get(index.chunk as usize)
+                        .and_then(Option::as_ref),
+                    primitive_bounds,
+                ),
+                LbvhSource::Instances => (instance_bind_group.as_ref(), instance_bounds),
+            };
+            let Some(geometry_bind_group) = geometry_bind_group else {
+                continue;
+            };
+            if build.count == 0 {
+                continue;
+            }
+
+            let groups = build.count.div_ceil(WORKGROUP_SIZE);
+            pass.pass.set_bind_group(1, geometry_bind_group, &[]);
+            pass.dispatch(reset, offset, 1);
+            pass.dispatch(leaf_bounds, offset, groups);
+            pass.dispatch(morton_codes, offset, groups);
+            for pass_offset in pass_offsets {
+                pass.dispatch(sort_histogram, pass_offset, groups);
+                pass.dispatch(sort_scan, pass_offset, 1);
+                pass.dispatch(sort_scatter, pass_offset, groups);
+            }
+            pass.dispatch(hierarchy, offset, groups);
+            pass.dispatch(node_bounds, offset, groups);
+            let nodes = 2 * build.count - 1;
+            pass.dispatch(flatten, offset, nodes.div_ceil(WORKGROUP_SIZE));
+        }
+    }
+    render_queue.submit([command_encoder.finish()]);
+}
+
+struct LbvhPass<'a> {
+    pass: ComputePass<'a>,
+    scratch: &'a BindGroup,
+}
+
+impl<'a> LbvhPass<'a> {
+    /// Runs an entry point with the parameters found at `offset`
+    fn dispatch(&mut self, pipeline: &'a ComputePipeline, offset: u32, groups: u32) {
+        self.pass.set_pipeline(pipeline);
+        self.pass.set_bind_group(0, self.scratch, &[offset]);
+        self.pass.dispatch_workgroups(groups, 1, 1);
+    }
+}
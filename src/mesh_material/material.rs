@@ -1,29 +1,78 @@
-use super::GpuStandardMaterials;
+use super::{instance::GenericInstancePlugin, mesh::GpuWordBuffer, GpuStandardMaterials};
+use crate::{
+    error::{RaytracerError, RaytracerErrors},
+    RT_SHADER_HANDLE,
+};
 use bevy::{
+    pbr::{ExtendedMaterial, MaterialExtension},
     prelude::*,
     render::{
-        render_resource::*,
+        render_resource::{encase::internal::WriteInto, *},
         renderer::{RenderDevice, RenderQueue},
+        settings::WgpuLimits,
         Extract, Render, RenderApp, RenderSet,
     },
     utils::{HashMap, HashSet},
 };
 use indexmap::set::IndexSet;
-use std::marker::PhantomData;
+use std::{any::TypeId, collections::BTreeSet, fmt::Write, marker::PhantomData};
+
+/// The material index of an instance holds the kind of its material in the high bits,
+/// and its index (standard materials) or word offset (other kinds) in the low ones.
+pub const MATERIAL_KIND_SHIFT: u32 = 24;
+pub const MATERIAL_OFFSET_MASK: u32 = (1 << MATERIAL_KIND_SHIFT) - 1;
+
+/// Packs the material index of an instance, unless the kind or the offset does not fit
+pub fn pack_material_index(kind: u32, offset: usize) -> Option<u32> {
+    let offset = u32::try_from(offset).ok()?;
+    (kind >> (32 - MATERIAL_KIND_SHIFT) == 0 && offset <= MATERIAL_OFFSET_MASK)
+        .then_some(kind << MATERIAL_KIND_SHIFT | offset)
+}
+
+/// Slots of the texture array, unless the device binds fewer
+pub const MAX_TEXTURES: u32 = 1024;
+
+/// The slots of the texture array, fixed for the device so the layout never changes
+pub fn texture_capacity(limits: &WgpuLimits) -> u32 {
+    MAX_TEXTURES
+        .min(limits.max_sampled_textures_per_shader_stage)
+        .min(limits.max_samplers_per_shader_stage)
+        .max(1)
+}
 
 pub struct MaterialPlugin;
 impl Plugin for MaterialPlugin {
     fn build(&self, app: &mut App) {
+        app.init_resource::<RaytracedMaterials>();
+
         if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
             render_app
                 .init_resource::<GpuStandardMaterials>()
-                .init_resource::<MaterialRenderAssets>()
+                .init_resource::<ExtractedMaterials>()
                 .add_systems(
                     Render,
                     prepare_material_assets.in_set(RenderSet::PrepareAssets),
                 );
         }
     }
+
+    fn finish(&self, app: &mut App) {
+        // All the material plugins are built by now
+        let materials = app.world.resource::<RaytracedMaterials>().clone();
+        if !materials.is_empty() {
+            let source = include_str!("../shaders/raytracer.wgsl").to_owned() + &materials.shader();
+            app.world.resource_mut::<Assets<Shader>>().insert(
+                RT_SHADER_HANDLE,
+                Shader::from_wgsl(source, "shaders/raytracer.wgsl"),
+            );
+        }
+
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .insert_resource(materials)
+                .init_resource::<MaterialRenderAssets>();
+        }
+    }
 }
 
 #[derive(Default)]
@@ -42,23 +91,392 @@ where
     }
 }
 
-#[derive(Default, Resource)]
+/// A material traced with its own data and WGSL function, instead of as a [`StandardMaterial`].
+///
+/// The function is called as `FUNCTION(material, hit, wo)` and returns a `MaterialSample`,
+/// `material` being the offset of the words of the material in `custom_material_buffer`:
+/// the indices of its textures first (`U32_MAX` for empty slots), then its data.
+/// `custom_material_word` and `sample_custom_texture` read them. The vertex colors and
+/// second UVs of the hit are in `hit.color` and `hit.uv_1`, the data of the instance is read
+/// with `instance_user_data(hit)`. The `kind` of the sample is the
+/// [`RayVisibility`](crate::RayVisibility) bit the next ray tests, e.g. `RAY_DIFFUSE`.
+pub trait RaytracedMaterial: Asset + Clone {
+    /// The data of the material, laid out as in a storage buffer
+    type Data: ShaderType + WriteInto;
+    /// Name of the WGSL function sampling the material
+    const FUNCTION: &'static str;
+
+    /// WGSL source defining [`RaytracedMaterial::FUNCTION`], it is appended to the raytracer shader
+    fn shader() -> &'static str;
+    /// The texture slots of the material
+    fn textures(&self) -> Vec<Option<Handle<Image>>> {
+        vec![]
+    }
+    fn data(&self) -> Self::Data;
+}
+
+/// Traces the entities with a `Handle<M>`, dispatching to the WGSL function of `M`.
+pub struct RaytracedMaterialPlugin<M: RaytracedMaterial>(PhantomData<M>);
+impl<M: RaytracedMaterial> Default for RaytracedMaterialPlugin<M> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+impl<M: RaytracedMaterial> Plugin for RaytracedMaterialPlugin<M> {
+    fn build(&self, app: &mut App) {
+        app.world
+            .get_resource_or_insert_with(RaytracedMaterials::default)
+            .register::<M>();
+        app.add_plugins(GenericInstancePlugin::<M>::default());
+
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app.add_systems(
+                ExtractSchedule,
+                extract_raytraced_materials::<M>.in_set(RenderSet::ExtractCommands),
+            );
+        }
+    }
+}
+
+/// Traces the entities with an [`ExtendedMaterial`] as their base [`StandardMaterial`].
+pub struct ExtendedMaterialPlugin<E: MaterialExtension>(PhantomData<E>);
+impl<E: MaterialExtension> Default for ExtendedMaterialPlugin<E> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+impl<E: MaterialExtension> Plugin for ExtendedMaterialPlugin<E> {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(GenericInstancePlugin::<ExtendedMaterial<StandardMaterial, E>>::default());
+
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app.add_systems(
+                ExtractSchedule,
+                extract_extended_materials::<E>.in_set(RenderSet::ExtractCommands),
+            );
+        }
+    }
+}
+
+/// A [`MaterialExtension`] changing the sample of its base [`StandardMaterial`] in WGSL.
+///
+/// The hook is called as `FUNCTION(extension, hit, wo, sample)` and returns a `MaterialSample`,
+/// `sample` being the one of the base material and `extension` the offset of the words
+/// of the extension, laid out as those of a [`RaytracedMaterial`].
+pub trait RaytracedExtension: MaterialExtension {
+    /// The data of the extension, laid out as in a storage buffer
+    type RaytracedData: ShaderType + WriteInto;
+    /// Name of the WGSL hook
+    const FUNCTION: &'static str;
+
+    /// WGSL source defining [`RaytracedExtension::FUNCTION`], it is appended to the raytracer shader
+    fn shader() -> &'static str;
+    /// The texture slots of the extension
+    fn textures(&self) -> Vec<Option<Handle<Image>>> {
+        vec![]
+    }
+    fn raytraced_data(&self) -> Self::RaytracedData;
+}
+
+/// Traces the entities with an [`ExtendedMaterial`] as their base [`StandardMaterial`],
+/// passing the sample through the WGSL hook of `E`.
+pub struct RaytracedExtensionPlugin<E: RaytracedExtension>(PhantomData<E>);
+impl<E: RaytracedExtension> Default for RaytracedExtensionPlugin<E> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+impl<E: RaytracedExtension> Plugin for RaytracedExtensionPlugin<E> {
+    fn build(&self, app: &mut App) {
+        app.world
+            .get_resource_or_insert_with(RaytracedMaterials::default)
+            .register_extension::<E>();
+        app.add_plugins(GenericInstancePlugin::<ExtendedMaterial<StandardMaterial, E>>::default());
+
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app.add_systems(
+                ExtractSchedule,
+                extract_raytraced_extensions::<E>.in_set(RenderSet::ExtractCommands),
+            );
+        }
+    }
+}
+
+#[derive(Clone)]
+struct MaterialKind {
+    type_id: TypeId,
+    function: &'static str,
+    shader: &'static str,
+    /// Whether the function is the hook of a [`RaytracedExtension`]
+    extension: bool,
+}
+
+/// The registered [`RaytracedMaterial`] types. Their kind is their position plus one,
+/// the kind 0 being the [`StandardMaterial`].
+#[derive(Default, Clone, Resource)]
+pub struct RaytracedMaterials(Vec<MaterialKind>);
+
+impl RaytracedMaterials {
+    fn register<M: RaytracedMaterial>(&mut self) {
+        if self.kind::<M>().is_none() {
+            self.0.push(MaterialKind {
+                type_id: TypeId::of::<M>(),
+                function: M::FUNCTION,
+                shader: M::shader(),
+                extension: false,
+            });
+        }
+    }
+
+    fn register_extension<E: RaytracedExtension>(&mut self) {
+        if self.kind::<E>().is_none() {
+            self.0.push(MaterialKind {
+                type_id: TypeId::of::<E>(),
+                function: E::FUNCTION,
+                shader: E::shader(),
+                extension: true,
+            });
+        }
+    }
+
+    fn kind<M: 'static>(&self) -> Option<u32> {
+        let type_id = TypeId::of::<M>();
+        let position = self.0.iter().position(|kind| kind.type_id == type_id)?;
+        Some(position as u32 + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The shaders of the materials, and `sample_custom_material` dispatching on their kind
+    fn shader(&self) -> String {
+        let mut source = String::new();
+        for kind in &self.0 {
+            source += "\n";
+            source += kind.shader;
+        }
+
+        source += "\nfn sample_custom_material(kind: u32, material: u32, hit: HitInfo, wo: vec3<f32>) -> MaterialSample {\n    switch kind {\n";
+        for (index, kind) in self.0.iter().enumerate() {
+            let case = if index + 1 == self.0.len() {
+                "default".to_owned()
+            } else {
+                format!("case {}u", index + 1)
+            };
+            // The first word of an extension is the index of its base material
+            let call = if kind.extension {
+                format!(
+                    "{}(material + 1u, hit, wo, sample_standard_material(custom_material_word(material, 0u), hit, wo))",
+                    kind.function
+                )
+            } else {
+                format!("{}(material, hit, wo)", kind.function)
+            };
+            writeln!(source, "        {case}: {{ return {call}; }}").unwrap();
+        }
+        source += "    }\n}\n";
+        source
+    }
+}
+
+/// A [`RaytracedMaterial`] erased of its type, with its data encoded in words
+#[derive(Debug, Clone, PartialEq)]
+pub struct GpuCustomMaterial {
+    pub kind: u32,
+    pub textures: Vec<Option<Handle<Image>>>,
+    pub data: Vec<u32>,
+}
+
+impl GpuCustomMaterial {
+    pub fn new<M: RaytracedMaterial>(kind: u32, material: &M) -> Self {
+        Self::from_data(kind, material.textures(), &material.data())
+    }
+
+    fn from_data(
+        kind: u32,
+        textures: Vec<Option<Handle<Image>>>,
+        data: &(impl ShaderType + WriteInto),
+    ) -> Self {
+        let mut buffer = encase::StorageBuffer::new(Vec::new());
+        buffer.write(data).unwrap();
+        let data = buffer
+            .into_inner()
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+
+        Self {
+            kind,
+            textures,
+            data,
+        }
+    }
+
+    /// The words read by the shader: the indices of the textures, adding them to the table, then the data.
+    pub fn words(&self, textures: &mut impl TextureSlots) -> Vec<u32> {
+        let indices = self.textures.iter().map(|texture| {
+            texture
+                .as_ref()
+                .map_or(u32::MAX, |texture| textures.slot(texture))
+        });
+        indices.chain(self.data.iter().copied()).collect()
+    }
+}
+
+#[derive(Resource)]
 pub struct MaterialRenderAssets {
     pub materials: StorageBuffer<GpuStandardMaterialBuffer>,
-    pub textures: Vec<Handle<Image>>,
+    /// Words of the [`RaytracedMaterial`]s, see [`GpuCustomMaterial::words`]
+    pub custom_materials: StorageBuffer<GpuWordBuffer>,
+    pub textures: TextureTable,
+}
+
+impl FromWorld for MaterialRenderAssets {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        Self {
+            materials: default(),
+            custom_materials: default(),
+            textures: TextureTable::new(texture_capacity(&render_device.limits())),
+        }
+    }
+}
+
+/// Gives the textures of materials their index in the texture array
+pub trait TextureSlots {
+    /// The slot of the texture, `u32::MAX` if none is left
+    fn slot(&mut self, texture: &Handle<Image>) -> u32;
+}
+
+impl TextureSlots for IndexSet<Handle<Image>> {
+    fn slot(&mut self, texture: &Handle<Image>) -> u32 {
+        self.insert_full(texture.clone_weak()).0 as u32
+    }
+}
+
+/// The slots of the texture array. A texture keeps its slot while a material uses it,
+/// the freed slots are reused first, and the table never grows past its capacity.
+#[derive(Debug, Default)]
+pub struct TextureTable {
+    slots: Vec<Option<Handle<Image>>>,
+    indices: HashMap<AssetId<Image>, u32>,
+    free: BTreeSet<u32>,
+    capacity: u32,
+    /// Textures that found no slot since the last [`TextureTable::retain`]
+    overflow: HashSet<AssetId<Image>>,
+}
+
+impl TextureTable {
+    pub fn new(capacity: u32) -> Self {
+        Self {
+            capacity,
+            ..default()
+        }
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    /// The textures by slot, up to the last used one
+    pub fn slots(&self) -> &[Option<Handle<Image>>] {
+        &self.slots
+    }
+
+    pub fn get(&self, texture: AssetId<Image>) -> Option<u32> {
+        self.indices.get(&texture).copied()
+    }
+
+    /// Textures sampled as white because the table was full
+    pub fn overflow(&self) -> usize {
+        self.overflow.len()
+    }
+
+    /// Frees the slots of the textures not kept
+    pub fn retain(&mut self, mut keep: impl FnMut(AssetId<Image>) -> bool) {
+        let Self {
+            slots,
+            indices,
+            free,
+            overflow,
+            ..
+        } = self;
+        overflow.clear();
+        indices.retain(|&texture, &mut slot| {
+            let kept = keep(texture);
+            if !kept {
+                slots[slot as usize] = None;
+                free.insert(slot);
+            }
+            kept
+        });
+        while let Some(None) = slots.last() {
+            free.remove(&(slots.len() as u32 - 1));
+            slots.pop();
+        }
+    }
+}
+
+impl TextureSlots for TextureTable {
+    fn slot(&mut self, texture: &Handle<Image>) -> u32 {
+        if let Some(slot) = self.get(texture.id()) {
+            return slot;
+        }
+        let slot = match self.free.pop_first() {
+            Some(slot) => slot,
+            None if (self.slots.len() as u32) < self.capacity => {
+                self.slots.push(None);
+                self.slots.len() as u32 - 1
+            }
+            None => {
+                self.overflow.insert(texture.id());
+                return u32::MAX;
+            }
+        };
+        self.slots[slot as usize] = Some(texture.clone_weak());
+        self.indices.insert(texture.id(), slot);
+        slot
+    }
+}
+
+pub enum ExtractedMaterial {
+    Standard(Box<StandardMaterial>),
+    Custom(GpuCustomMaterial),
+    /// The words of the extension follow the index of the base material
+    Extended(Box<StandardMaterial>, GpuCustomMaterial),
+}
+
+impl ExtractedMaterial {
+    fn textures(&self) -> impl Iterator<Item = &Handle<Image>> {
+        let (standard, custom) = match self {
+            Self::Standard(material) => (Some(&**material), None),
+            Self::Custom(material) => (None, Some(material)),
+            Self::Extended(base, extension) => (Some(&**base), Some(extension)),
+        };
+        let standard = standard.into_iter().flat_map(|material| {
+            [
+                &material.base_color_texture,
+                &material.emissive_texture,
+                &material.metallic_roughness_texture,
+                &material.normal_map_texture,
+            ]
+        });
+        let custom = custom.into_iter().flat_map(|material| &material.textures);
+        standard.chain(custom).flatten()
+    }
 }
 
 #[derive(Default, Resource)]
 pub struct ExtractedMaterials {
-    extracted: Vec<(UntypedHandle, StandardMaterial)>,
+    extracted: Vec<(UntypedHandle, ExtractedMaterial)>,
     removed: Vec<UntypedHandle>,
 }
 
-fn extract_materials_assets<M: Into<StandardMaterial> + Asset + Clone>(
-    mut commands: Commands,
-    mut events: Extract<EventReader<AssetEvent<M>>>,
-    assets: Extract<Res<Assets<M>>>,
-) {
+/// Reads the asset events, returning the changed then the removed assets
+fn changed_assets<M: Asset>(
+    events: &mut EventReader<AssetEvent<M>>,
+) -> (HashSet<UntypedHandle>, Vec<UntypedHandle>) {
     let mut changed_assets = HashSet::new();
     let mut removed = vec![];
     for event in events.read() {
@@ -74,24 +492,84 @@ fn extract_materials_assets<M: Into<StandardMaterial> + Asset + Clone>(
             }
         }
     }
+    (changed_assets, removed)
+}
 
-    let mut extracted = vec![];
-    for handle in changed_assets.drain() {
+/// Extracts the changed assets with `extract`, and the removed ones
+fn extract_assets<M: Asset>(
+    events: &mut EventReader<AssetEvent<M>>,
+    assets: &Assets<M>,
+    extracted_assets: &mut ExtractedMaterials,
+    extract: impl Fn(&M) -> ExtractedMaterial,
+) {
+    let (changed_assets, removed) = changed_assets(events);
+    for handle in changed_assets {
         if let Some(material) = assets.get(&handle) {
-            extracted.push((handle, material.clone().into()));
+            extracted_assets.extracted.push((handle, extract(material)));
         }
     }
+    extracted_assets.removed.extend(removed);
+}
+
+fn extract_materials_assets<M: Into<StandardMaterial> + Asset + Clone>(
+    mut events: Extract<EventReader<AssetEvent<M>>>,
+    assets: Extract<Res<Assets<M>>>,
+    mut extracted_assets: ResMut<ExtractedMaterials>,
+) {
+    extract_assets(&mut events, &assets, &mut extracted_assets, |material| {
+        ExtractedMaterial::Standard(Box::new(material.clone().into()))
+    });
+}
+
+fn extract_raytraced_materials<M: RaytracedMaterial>(
+    mut events: Extract<EventReader<AssetEvent<M>>>,
+    assets: Extract<Res<Assets<M>>>,
+    materials: Res<RaytracedMaterials>,
+    mut extracted_assets: ResMut<ExtractedMaterials>,
+) {
+    let Some(kind) = materials.kind::<M>() else {
+        return;
+    };
+    extract_assets(&mut events, &assets, &mut extracted_assets, |material| {
+        ExtractedMaterial::Custom(GpuCustomMaterial::new(kind, material))
+    });
+}
 
-    commands.insert_resource(ExtractedMaterials { extracted, removed });
+fn extract_extended_materials<E: MaterialExtension>(
+    mut events: Extract<EventReader<AssetEvent<ExtendedMaterial<StandardMaterial, E>>>>,
+    assets: Extract<Res<Assets<ExtendedMaterial<StandardMaterial, E>>>>,
+    mut extracted_assets: ResMut<ExtractedMaterials>,
+) {
+    extract_assets(&mut events, &assets, &mut extracted_assets, |material| {
+        ExtractedMaterial::Standard(Box::new(material.base.clone()))
+    });
+}
+
+fn extract_raytraced_extensions<E: RaytracedExtension>(
+    mut events: Extract<EventReader<AssetEvent<ExtendedMaterial<StandardMaterial, E>>>>,
+    assets: Extract<Res<Assets<ExtendedMaterial<StandardMaterial, E>>>>,
+    materials: Res<RaytracedMaterials>,
+    mut extracted_assets: ResMut<ExtractedMaterials>,
+) {
+    let Some(kind) = materials.kind::<E>() else {
+        return;
+    };
+    extract_assets(&mut events, &assets, &mut extracted_assets, |material| {
+        let extension = &material.extension;
+        let extension =
+            GpuCustomMaterial::from_data(kind, extension.textures(), &extension.raytraced_data());
+        ExtractedMaterial::Extended(Box::new(material.base.clone()), extension)
+    });
 }
 
 pub fn prepare_material_assets(
     mut extracted_assets: ResMut<ExtractedMaterials>,
-    mut assets: Local<HashMap<UntypedHandle, StandardMaterial>>,
+    mut assets: Local<HashMap<UntypedHandle, ExtractedMaterial>>,
     mut materials: ResMut<GpuStandardMaterials>,
     mut render_assets: ResMut<MaterialRenderAssets>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
+    mut errors: ResMut<RaytracerErrors>,
 ) {
     if extracted_assets.removed.is_empty() && extracted_assets.extracted.is_empty() {
         return;
@@ -105,54 +583,69 @@ pub fn prepare_material_assets(
         assets.insert(handle, material);
     }
 
-    let mut textures = IndexSet::new();
-    let materials = assets
-        .iter()
-        .enumerate()
-        .map(|(index, (handle, material))| {
-            add_textures(&mut textures, material);
+    // Slots of textures that are still used stay, so the others keep their indices
+    let used: HashSet<_> = assets
+        .values()
+        .flat_map(ExtractedMaterial::textures)
+        .map(Handle::id)
+        .collect();
+    let render_assets = &mut *render_assets;
+    let textures = &mut render_assets.textures;
+    textures.retain(|texture| used.contains(&texture));
 
-            let get_index = |maybe_handle: &Option<Handle<Image>>| {
-                maybe_handle
-                    .as_ref()
-                    .and_then(|handle| textures.get_index_of(handle).map(|i| i as u32))
-                    .unwrap_or(u32::MAX)
-            };
+    let mut standard_materials = vec![];
+    let mut custom_materials = vec![];
+    let mut dropped_materials = 0;
+    for (handle, material) in assets.iter() {
+        let (material, base) = match material {
+            ExtractedMaterial::Standard(material) => {
+                let Some(index) = pack_material_index(0, standard_materials.len()) else {
+                    materials.remove(handle);
+                    dropped_materials += 1;
+                    continue;
+                };
+                standard_materials.push(GpuStandardMaterial::new(material, textures));
+                materials.insert(handle.clone_weak(), index);
+                continue;
+            }
+            ExtractedMaterial::Custom(material) => (material, None),
+            ExtractedMaterial::Extended(base, extension) => (extension, Some(base)),
+        };
 
-            let material = GpuStandardMaterial {
-                base_color: material.base_color.into(),
-                base_color_texture: get_index(&material.base_color_texture),
-                emissive: material.emissive.into(),
-                emissive_texture: get_index(&material.emissive_texture),
-                perceptual_roughness: material.perceptual_roughness,
-                metallic: material.metallic,
-                metallic_roughness_texture: get_index(&material.metallic_roughness_texture),
-                reflectance: material.reflectance,
-                normal_map_texture: get_index(&material.normal_map_texture),
-            };
-            materials.insert(handle.clone_weak(), index as u32);
-            material
-        })
-        .collect();
+        let Some(index) = pack_material_index(material.kind, custom_materials.len()) else {
+            materials.remove(handle);
+            dropped_materials += 1;
+            continue;
+        };
+        if let Some(base) = base {
+            standard_materials.push(GpuStandardMaterial::new(base, textures));
+            custom_materials.push(standard_materials.len() as u32 - 1);
+        }
+        custom_materials.extend(material.words(textures));
+        materials.insert(handle.clone_weak(), index);
+    }
+    if dropped_materials > 0 {
+        errors.send(RaytracerError::MaterialLimit { dropped_materials });
+    }
+    if textures.overflow() > 0 {
+        errors.send(RaytracerError::TextureLimit {
+            dropped_textures: textures.overflow(),
+            capacity: textures.capacity(),
+        });
+    }
+    // The buffer is bound even without raytraced materials
+    if custom_materials.is_empty() {
+        custom_materials.push(0);
+    }
 
-    render_assets.textures.clear();
-    render_assets.textures.extend(textures);
-    render_assets.materials.get_mut().data = materials;
+    render_assets.materials.get_mut().data = standard_materials;
     render_assets
         .materials
         .write_buffer(&render_device, &render_queue);
-}
-
-fn add_textures(textures: &mut IndexSet<Handle<Image>>, material: &StandardMaterial) {
-    let to_add = [
-        &material.base_color_texture,
-        &material.emissive_texture,
-        &material.metallic_roughness_texture,
-        &material.normal_map_texture,
-    ];
-    for texture in to_add.into_iter().flatten() {
-        textures.insert(texture.clone_weak());
-    }
+    render_assets.custom_materials.get_mut().data = custom_materials;
+    render_assets
+        .custom_materials
+        .write_buffer(&render_device, &render_queue);
 }
 
 #[derive(Debug, ShaderType)]
@@ -168,6 +661,29 @@ pub struct GpuStandardMaterial {
     pub normal_map_texture: u32,
 }
 
+impl GpuStandardMaterial {
+    /// Converts the material, adding its textures to the texture table.
+    pub fn new(material: &StandardMaterial, textures: &mut impl TextureSlots) -> Self {
+        let mut get_index = |maybe_handle: &Option<Handle<Image>>| {
+            maybe_handle
+                .as_ref()
+                .map_or(u32::MAX, |handle| textures.slot(handle))
+        };
+
+        Self {
+            base_color: material.base_color.into(),
+            base_color_texture: get_index(&material.base_color_texture),
+            emissive: material.emissive.into(),
+            emissive_texture: get_index(&material.emissive_texture),
+            perceptual_roughness: material.perceptual_roughness,
+            metallic: material.metallic,
+            metallic_roughness_texture: get_index(&material.metallic_roughness_texture),
+            reflectance: material.reflectance,
+            normal_map_texture: get_index(&material.normal_map_texture),
+        }
+    }
+}
+
 /// Container for vertex data
 #[derive(Default, ShaderType)]
 pub struct GpuStandardMaterialBuffer {
@@ -1,25 +1,40 @@
-use super::{GpuMeshIndex, GpuMeshes, GpuNode, GpuNodeBuffer, PrepareMeshError};
+use super::{
+    bvh_builder::{build_bvh, Bounds, BvhBuilder, BvhLayout, BvhPrimitive, BvhReport, BvhSettings},
+    deform::{morph_target_image, DeformedMesh, DeformedMeshes, MeshDeformation},
+    lbvh::{lbvh_node_count, placeholder_nodes, LbvhBuilds},
+    write_range, GpuMeshIndex, GpuMeshes, GpuNode, GpuNodeBuffer, PrepareMeshError,
+};
+use crate::error::{RaytracerError, RaytracerErrors};
 use bevy::{
     prelude::*,
     render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
         mesh::VertexAttributeValues,
+        primitives::Aabb,
         render_resource::*,
         renderer::{RenderDevice, RenderQueue},
+        settings::WgpuLimits,
         Extract, Render, RenderApp, RenderSet,
     },
-    utils::HashSet,
-};
-use bvh::{
-    aabb::{Bounded, AABB},
-    bounding_hierarchy::BHShape,
-    bvh::BVH,
+    tasks::{block_on, AsyncComputeTaskPool, Task},
+    utils::{HashMap, HashSet},
 };
+use half::f16;
 use itertools::Itertools;
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, mem, ops::Range, sync::Arc};
 
 pub struct MeshPlugin;
 impl Plugin for MeshPlugin {
     fn build(&self, app: &mut App) {
+        app.init_resource::<BvhSettings>()
+            .init_resource::<GeometrySettings>()
+            .init_resource::<MeshDiagnostics>()
+            .add_plugins((
+                ExtractResourcePlugin::<BvhSettings>::default(),
+                ExtractResourcePlugin::<GeometrySettings>::default(),
+            ))
+            .add_systems(PostUpdate, diagnose_meshes);
+
         if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
             render_app
                 .add_systems(
@@ -37,36 +52,634 @@ impl Plugin for MeshPlugin {
     }
 }
 
-#[derive(Default, Resource)]
+/// Encoding of the vertices and primitives in the buffers, matched by shader defs.
+/// The compact encodings let larger scenes fit in `max_storage_buffer_binding_size`.
+/// A change uploads all the geometry again.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq, Hash, ExtractResource)]
+pub struct GeometrySettings {
+    /// Primitives only hold the indices of their vertices: 12 bytes instead of 48
+    pub compact_primitives: bool,
+    /// Normals are octahedral encoded on 2x16 bits and UVs stored as halves: 20 bytes instead of 32
+    pub quantized_vertices: bool,
+}
+
+impl GeometrySettings {
+    pub fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        let mut shader_defs = vec![];
+        if self.compact_primitives {
+            shader_defs.push("COMPACT_PRIMITIVES".into());
+        }
+        if self.quantized_vertices {
+            shader_defs.push("QUANTIZED_VERTICES".into());
+        }
+        shader_defs
+    }
+}
+
+/// Most geometry chunks the raytracer binds at once
+pub const MAX_GEOMETRY_CHUNKS: usize = 4;
+/// Storage buffers of the raytracer besides the chunks:
+/// standard materials, instances, instance nodes and custom materials
+const SHARED_STORAGE_BUFFERS: u32 = 4;
+/// Storage buffers of a chunk: vertices, primitives, nodes and vertex attributes
+pub const CHUNK_BUFFERS: usize = 4;
+/// Bytes before the nodes in their buffer: the count, padded to the alignment of the nodes
+const NODE_BUFFER_HEADER: u64 = 16;
+
+/// How much geometry the device can bind, derived from its limits.
+/// The meshes are split into chunks, each with its own vertex, primitive, node and
+/// vertex attribute buffers no larger than a storage buffer binding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GeometryLimits {
+    /// Size in bytes of the largest storage buffer binding
+    pub max_binding_size: u64,
+    /// Chunks the raytracer can bind at once
+    pub max_chunks: usize,
+}
+
+impl GeometryLimits {
+    pub fn new(limits: &WgpuLimits) -> Self {
+        let max_binding_size = (limits.max_storage_buffer_binding_size as u64)
+            .min(limits.max_buffer_size)
+            .min(u32::MAX as u64);
+        let per_stage = limits.max_storage_buffers_per_shader_stage;
+        let max_chunks = per_stage.saturating_sub(SHARED_STORAGE_BUFFERS) / CHUNK_BUFFERS as u32;
+        Self {
+            max_binding_size,
+            max_chunks: (max_chunks as usize).clamp(1, MAX_GEOMETRY_CHUNKS),
+        }
+    }
+
+    /// Vertices, primitives, nodes and attribute words that fit in a chunk,
+    /// in the encoding of `geometry`
+    pub fn chunk_capacity(&self, geometry: GeometrySettings) -> [u32; CHUNK_BUFFERS] {
+        let vertex_size = if geometry.quantized_vertices {
+            4 * QUANTIZED_VERTEX_WORDS as u64
+        } else {
+            GpuVertexCompact::min_size().get()
+        };
+        let primitive_size = if geometry.compact_primitives {
+            4 * COMPACT_PRIMITIVE_WORDS as u64
+        } else {
+            GpuPrimitiveCompact::min_size().get()
+        };
+        let capacity = |header: u64, size: u64| {
+            (self.max_binding_size.saturating_sub(header) / size).min(u32::MAX as u64) as u32
+        };
+        [
+            capacity(0, vertex_size),
+            capacity(0, primitive_size),
+            capacity(NODE_BUFFER_HEADER, GpuNode::min_size().get()),
+            capacity(0, 4),
+        ]
+    }
+
+    /// Chunk of each mesh, the first one with room left in mesh order.
+    /// `None` for the meshes that do not fit in the chunks the device can bind.
+    pub fn assign_chunks<'a>(
+        &self,
+        geometry: GeometrySettings,
+        meshes: impl Iterator<Item = &'a GpuMesh>,
+    ) -> Vec<Option<usize>> {
+        let capacity = self.chunk_capacity(geometry);
+        let mut chunks: Vec<[u32; CHUNK_BUFFERS]> = vec![];
+        meshes
+            .map(|mesh| {
+                let len = mesh_len(mesh);
+                let fits = |used: &[u32; CHUNK_BUFFERS]| {
+                    (0..CHUNK_BUFFERS).all(|i| capacity[i] - used[i] >= len[i])
+                };
+                if !fits(&[0; CHUNK_BUFFERS]) {
+                    return None;
+                }
+                let chunk = match chunks.iter().position(fits) {
+                    Some(chunk) => chunk,
+                    None if chunks.len() < self.max_chunks => {
+                        chunks.push([0; CHUNK_BUFFERS]);
+                        chunks.len() - 1
+                    }
+                    None => return None,
+                };
+                (0..CHUNK_BUFFERS).for_each(|i| chunks[chunk][i] += len[i]);
+                Some(chunk)
+            })
+            .collect()
+    }
+}
+
+fn mesh_len(mesh: &GpuMesh) -> [u32; CHUNK_BUFFERS] {
+    [
+        mesh.vertices.len() as u32,
+        mesh.primitives.len() as u32,
+        mesh.nodes.len() as u32,
+        mesh.attributes.len(),
+    ]
+}
+
+/// The vertices, primitives and nodes of all meshes, sub-allocated from large buffers.
+/// Adding or removing a mesh only uploads its own ranges, unless the buffers must be compacted.
+/// The vertices and primitives are kept at full precision, and encoded when uploaded.
+/// Geometry beyond the binding size of the device is split into several [`GeometryChunk`]s.
+#[derive(Resource)]
 pub struct MeshRenderAssets {
-    pub vertex_buffer: StorageBuffer<GpuVertexBuffer>,
-    pub primitive_buffer: StorageBuffer<GpuPrimitiveBuffer>,
-    pub node_buffer: StorageBuffer<GpuNodeBuffer>,
+    chunks: Vec<GeometryChunk>,
+    geometry: GeometrySettings,
+    limits: GeometryLimits,
 }
 
-impl MeshRenderAssets {
-    pub fn set(
+impl FromWorld for MeshRenderAssets {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        Self {
+            chunks: vec![],
+            geometry: default(),
+            limits: GeometryLimits::new(&render_device.limits()),
+        }
+    }
+}
+
+/// Buffers holding a part of the meshes, addressed by [`GpuMeshIndex::chunk`]
+#[derive(Default)]
+pub struct GeometryChunk {
+    vertex_buffer: StorageBuffer<GpuVertexBuffer>,
+    primitive_buffer: StorageBuffer<GpuPrimitiveBuffer>,
+    node_buffer: StorageBuffer<GpuNodeBuffer>,
+    /// The encoded vertices and primitives, used instead of the buffers above in the
+    /// compact encodings of the [`GeometrySettings`]
+    vertex_words: StorageBuffer<GpuWordBuffer>,
+    primitive_words: StorageBuffer<GpuWordBuffer>,
+    /// The optional attributes of the meshes having some, see [`GpuVertexAttributes`]
+    attribute_buffer: StorageBuffer<GpuWordBuffer>,
+    free_vertices: FreeList,
+    free_primitives: FreeList,
+    free_nodes: FreeList,
+    free_attributes: FreeList,
+    /// Meshes written since the last upload
+    dirty: Vec<MeshRanges>,
+    /// The buffers were compacted or resized, so they are uploaded as a whole
+    repacked: bool,
+}
+
+/// Where the data of a mesh lives in the buffers of its chunk
+#[derive(Debug, Clone, PartialEq)]
+struct MeshRanges {
+    vertices: Range<u32>,
+    primitives: Range<u32>,
+    nodes: Range<u32>,
+    attributes: Range<u32>,
+}
+
+impl MeshRanges {
+    fn new(index: &GpuMeshIndex, mesh: &GpuMesh) -> Self {
+        Self {
+            vertices: index.vertex..index.vertex + mesh.vertices.len() as u32,
+            primitives: index.primitive..index.primitive + mesh.primitives.len() as u32,
+            nodes: index.node.x..index.node.x + index.node.y,
+            attributes: index.attributes.x..index.attributes.x + mesh.attributes.len(),
+        }
+    }
+}
+
+impl GeometryChunk {
+    fn allocate(&mut self, mesh: &GpuMesh) -> Option<GpuMeshIndex> {
+        let vertex = self.free_vertices.allocate(mesh.vertices.len() as u32);
+        let primitive = self.free_primitives.allocate(mesh.primitives.len() as u32);
+        let node = self.free_nodes.allocate(mesh.nodes.len() as u32);
+        let attribute = self.free_attributes.allocate(mesh.attributes.len());
+
+        let (Some(vertex), Some(primitive), Some(node), Some(attribute)) =
+            (vertex, primitive, node, attribute)
+        else {
+            // Give back the ranges that were found
+            let len = |start: Option<u32>, len: usize| {
+                start.map_or(0..0, |start| start..start + len as u32)
+            };
+            self.free_vertices.free(len(vertex, mesh.vertices.len()));
+            self.free_primitives
+                .free(len(primitive, mesh.primitives.len()));
+            self.free_nodes.free(len(node, mesh.nodes.len()));
+            self.free_attributes
+                .free(len(attribute, mesh.attributes.len() as usize));
+            return None;
+        };
+
+        Some(GpuMeshIndex {
+            vertex,
+            primitive,
+            node: UVec2::new(node, mesh.nodes.len() as u32),
+            chunk: 0,
+            attributes: UVec2::new(attribute, mesh.attributes.flags()),
+        })
+    }
+
+    fn update(&mut self, index: &GpuMeshIndex, mesh: &GpuMesh) {
+        let ranges = MeshRanges::new(index, mesh);
+        copy_into(
+            &mut self.vertex_buffer.get_mut().data,
+            &ranges.vertices,
+            &mesh.vertices,
+        );
+        copy_into(
+            &mut self.primitive_buffer.get_mut().data,
+            &ranges.primitives,
+            &mesh.primitives,
+        );
+        copy_into(
+            &mut self.node_buffer.get_mut().data,
+            &ranges.nodes,
+            &mesh.nodes,
+        );
+        copy_into(
+            &mut self.attribute_buffer.get_mut().data,
+            &ranges.attributes,
+            &mesh.attributes.words(),
+        );
+        if !self.dirty.contains(&ranges) {
+            self.dirty.push(ranges);
+        }
+    }
+
+    fn free(&mut self, index: &GpuMeshIndex, mesh: &GpuMesh) {
+        let ranges = MeshRanges::new(index, mesh);
+        self.dirty.retain(|dirty| *dirty != ranges);
+        self.free_vertices.free(ranges.vertices);
+        self.free_primitives.free(ranges.primitives);
+        self.free_nodes.free(ranges.nodes);
+        self.free_attributes.free(ranges.attributes);
+    }
+
+    /// Forgets all the meshes, and sizes the buffers for `len` vertices, primitives, nodes
+    /// and attribute words
+    fn reset(&mut self, len: [u32; CHUNK_BUFFERS], capacity: [u32; CHUNK_BUFFERS]) {
+        self.free_vertices.reset(len[0], capacity[0]);
+        self.free_primitives.reset(len[1], capacity[1]);
+        self.free_nodes.reset(len[2], capacity[2]);
+        self.free_attributes.reset(len[3], capacity[3]);
+
+        let capacity = |free: &FreeList| free.capacity as usize;
+        let vertex_capacity = capacity(&self.free_vertices);
+        let primitive_capacity = capacity(&self.free_primitives);
+        let node_capacity = capacity(&self.free_nodes);
+        self.vertex_buffer
+            .get_mut()
+            .data
+            .resize(vertex_capacity, default());
+        self.primitive_buffer
+            .get_mut()
+            .data
+            .resize(primitive_capacity, default());
+        self.node_buffer
+            .get_mut()
+            .data
+            .resize(node_capacity, default());
+        self.node_buffer.get_mut().count = node_capacity as u32;
+        // The buffer is bound even when no mesh has attributes
+        let attribute_capacity = capacity(&self.free_attributes).max(1);
+        self.attribute_buffer
+            .get_mut()
+            .data
+            .resize(attribute_capacity, 0);
+        self.repacked = true;
+    }
+
+    /// Releases the buffers of the encodings that are not used by `geometry`
+    fn set_geometry(&mut self, geometry: GeometrySettings) {
+        self.repacked = true;
+        if geometry.quantized_vertices {
+            let data = mem::take(&mut self.vertex_buffer.get_mut().data);
+            self.vertex_buffer = StorageBuffer::from(GpuVertexBuffer { data });
+        } else {
+            self.vertex_words = default();
+        }
+        if geometry.compact_primitives {
+            let data = mem::take(&mut self.primitive_buffer.get_mut().data);
+            self.primitive_buffer = StorageBuffer::from(GpuPrimitiveBuffer { data });
+        } else {
+            self.primitive_words = default();
+        }
+    }
+
+    fn vertex_binding(&self, geometry: GeometrySettings) -> Option<BindingResource<'_>> {
+        if geometry.quantized_vertices {
+            self.vertex_words.binding()
+        } else {
+            self.vertex_buffer.binding()
+        }
+    }
+
+    fn primitive_binding(&self, geometry: GeometrySettings) -> Option<BindingResource<'_>> {
+        if geometry.compact_primitives {
+            self.primitive_words.binding()
+        } else {
+            self.primitive_buffer.binding()
+        }
+    }
+
+    fn write_buffer(
         &mut self,
-        vertices: Vec<GpuVertexCompact>,
-        primitives: Vec<GpuPrimitiveCompact>,
-        nodes: Vec<GpuNode>,
+        geometry: GeometrySettings,
+        device: &RenderDevice,
+        queue: &RenderQueue,
     ) {
-        self.vertex_buffer.get_mut().data = vertices;
-        self.primitive_buffer.get_mut().data = primitives;
-        self.node_buffer.get_mut().count = nodes.len() as u32;
-        self.node_buffer.get_mut().data = nodes;
+        let GeometrySettings {
+            compact_primitives,
+            quantized_vertices,
+        } = geometry;
+        let buffers = (
+            if quantized_vertices {
+                self.vertex_words.buffer()
+            } else {
+                self.vertex_buffer.buffer()
+            },
+            if compact_primitives {
+                self.primitive_words.buffer()
+            } else {
+                self.primitive_buffer.buffer()
+            },
+            self.node_buffer.buffer(),
+            self.attribute_buffer.buffer(),
+        );
+        match buffers {
+            (
+                Some(vertex_buffer),
+                Some(primitive_buffer),
+                Some(node_buffer),
+                Some(attribute_buffer),
+            ) if !self.repacked => {
+                for ranges in self.dirty.drain(..) {
+                    let slice = |range: &Range<u32>| range.start as usize..range.end as usize;
+                    let vertex = ranges.vertices.start as usize;
+                    let vertices = &self.vertex_buffer.get().data[slice(&ranges.vertices)];
+                    if quantized_vertices {
+                        write_range::<GpuWordBuffer, _>(
+                            queue,
+                            vertex_buffer,
+                            QUANTIZED_VERTEX_WORDS * vertex,
+                            &quantize_vertices(vertices),
+                        );
+                    } else {
+                        write_range::<GpuVertexBuffer, _>(queue, vertex_buffer, vertex, vertices);
+                    }
+                    let primitive = ranges.primitives.start as usize;
+                    let primitives = &self.primitive_buffer.get().data[slice(&ranges.primitives)];
+                    if compact_primitives {
+                        write_range::<GpuWordBuffer, _>(
+                            queue,
+                            primitive_buffer,
+                            COMPACT_PRIMITIVE_WORDS * primitive,
+                            &compact_primitives_words(primitives),
+                        );
+                    } else {
+                        write_range::<GpuPrimitiveBuffer, _>(
+                            queue,
+                            primitive_buffer,
+                            primitive,
+                            primitives,
+                        );
+                    }
+                    write_range::<GpuNodeBuffer, _>(
+                        queue,
+                        node_buffer,
+                        ranges.nodes.start as usize,
+                        &self.node_buffer.get().data[slice(&ranges.nodes)],
+                    );
+                    write_range::<GpuWordBuffer, _>(
+                        queue,
+                        attribute_buffer,
+                        ranges.attributes.start as usize,
+                        &self.attribute_buffer.get().data[slice(&ranges.attributes)],
+                    );
+                }
+            }
+            _ => {
+                if quantized_vertices {
+                    self.vertex_words.get_mut().data =
+                        quantize_vertices(&self.vertex_buffer.get().data);
+                    self.vertex_words.write_buffer(device, queue);
+                } else {
+                    self.vertex_buffer.write_buffer(device, queue);
+                }
+                if compact_primitives {
+                    self.primitive_words.get_mut().data =
+                        compact_primitives_words(&self.primitive_buffer.get().data);
+                    self.primitive_words.write_buffer(device, queue);
+                } else {
+                    self.primitive_buffer.write_buffer(device, queue);
+                }
+                self.node_buffer.write_buffer(device, queue);
+                self.attribute_buffer.write_buffer(device, queue);
+                self.dirty.clear();
+                self.repacked = false;
+            }
+        }
+    }
+}
+
+impl MeshRenderAssets {
+    /// Places the mesh in the free space of the first chunk with room for it.
+    /// Returns `None` if it does not fit, in which case the buffers need a [`Self::repack`].
+    pub fn allocate(&mut self, mesh: &GpuMesh) -> Option<GpuMeshIndex> {
+        let index = self
+            .chunks
+            .iter_mut()
+            .enumerate()
+            .find_map(|(chunk, buffers)| {
+                let index = buffers.allocate(mesh)?;
+                Some(GpuMeshIndex {
+                    chunk: chunk as u32,
+                    ..index
+                })
+            })?;
+        self.update(&index, mesh);
+        Some(index)
+    }
+
+    /// Overwrites a mesh placed by [`Self::allocate`] with data of the same size.
+    pub fn update(&mut self, index: &GpuMeshIndex, mesh: &GpuMesh) {
+        self.chunks[index.chunk as usize].update(index, mesh);
+    }
+
+    /// Releases the ranges of a mesh placed by [`Self::allocate`].
+    pub fn free(&mut self, index: &GpuMeshIndex, mesh: &GpuMesh) {
+        self.chunks[index.chunk as usize].free(index, mesh);
+    }
+
+    /// Packs the meshes at the start of the chunks, growing or adding chunks if needed.
+    /// This moves every mesh, their new indices are returned in the same order.
+    /// `None` for the meshes beyond what the device can bind, see [`GeometryLimits`].
+    pub fn repack<'a>(
+        &mut self,
+        meshes: impl Iterator<Item = &'a GpuMesh> + Clone,
+    ) -> Vec<Option<GpuMeshIndex>> {
+        let chunks = self.limits.assign_chunks(self.geometry, meshes.clone());
+        let chunk_count = chunks.iter().flatten().map(|chunk| chunk + 1).max();
+        self.chunks.resize_with(chunk_count.unwrap_or(0), default);
+
+        let mut lens = vec![[0; CHUNK_BUFFERS]; self.chunks.len()];
+        for (mesh, chunk) in meshes.clone().zip(&chunks) {
+            if let Some(chunk) = chunk {
+                let len = mesh_len(mesh);
+                (0..CHUNK_BUFFERS).for_each(|i| lens[*chunk][i] += len[i]);
+            }
+        }
+        let capacity = self.limits.chunk_capacity(self.geometry);
+        for (buffers, len) in self.chunks.iter_mut().zip(lens) {
+            buffers.reset(len, capacity);
+        }
+
+        meshes
+            .zip(chunks)
+            .map(|(mesh, chunk)| {
+                let chunk = chunk?;
+                let index = self.chunks[chunk].allocate(mesh).unwrap();
+                let index = GpuMeshIndex {
+                    chunk: chunk as u32,
+                    ..index
+                };
+                self.update(&index, mesh);
+                Some(index)
+            })
+            .collect()
+    }
+
+    pub fn geometry(&self) -> GeometrySettings {
+        self.geometry
+    }
+
+    pub fn limits(&self) -> GeometryLimits {
+        self.limits
+    }
+
+    /// Switches the encoding of the geometry, which is uploaded again by the next
+    /// [`Self::write_buffer`]. The buffers of the previous encoding are released.
+    /// The chunks must be repacked, their capacity depends on the encoding.
+    pub fn set_geometry(&mut self, geometry: GeometrySettings) {
+        self.geometry = geometry;
+        for chunk in &mut self.chunks {
+            chunk.set_geometry(geometry);
+        }
+    }
+
+    /// Number of chunks holding meshes
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// The vertices of a chunk, in the encoding of the settings
+    pub fn vertex_binding(&self, chunk: usize) -> Option<BindingResource<'_>> {
+        self.chunks.get(chunk)?.vertex_binding(self.geometry)
+    }
+
+    /// The primitives of a chunk, in the encoding of the settings
+    pub fn primitive_binding(&self, chunk: usize) -> Option<BindingResource<'_>> {
+        self.chunks.get(chunk)?.primitive_binding(self.geometry)
+    }
+
+    /// The nodes of the mesh BVHs of a chunk
+    pub fn node_binding(&self, chunk: usize) -> Option<BindingResource<'_>> {
+        self.chunks.get(chunk)?.node_buffer.binding()
+    }
+
+    /// The optional vertex attributes of a chunk
+    pub fn attribute_binding(&self, chunk: usize) -> Option<BindingResource<'_>> {
+        self.chunks.get(chunk)?.attribute_buffer.binding()
     }
 
     pub fn write_buffer(&mut self, device: &RenderDevice, queue: &RenderQueue) {
-        self.vertex_buffer.write_buffer(device, queue);
-        self.primitive_buffer.write_buffer(device, queue);
-        self.node_buffer.write_buffer(device, queue);
+        for chunk in &mut self.chunks {
+            chunk.write_buffer(self.geometry, device, queue);
+        }
+    }
+}
+
+fn copy_into<T: Copy>(data: &mut [T], range: &Range<u32>, values: &[T]) {
+    data[range.start as usize..range.end as usize].copy_from_slice(values);
+}
+
+/// Free ranges of a sub-allocated buffer, sorted and merged with their neighbors
+#[derive(Debug, Default)]
+struct FreeList {
+    capacity: u32,
+    ranges: Vec<Range<u32>>,
+}
+
+impl FreeList {
+    /// First fit allocation of `len` elements
+    fn allocate(&mut self, len: u32) -> Option<u32> {
+        if len == 0 {
+            return Some(0);
+        }
+
+        let index = self
+            .ranges
+            .iter()
+            .position(|range| range.end - range.start >= len)?;
+        let range = &mut self.ranges[index];
+        let start = range.start;
+        range.start += len;
+        if range.start == range.end {
+            self.ranges.remove(index);
+        }
+        Some(start)
+    }
+
+    fn free(&mut self, range: Range<u32>) {
+        if range.start >= range.end {
+            return;
+        }
+
+        let index = self.ranges.partition_point(|free| free.start < range.start);
+        self.ranges.insert(index, range);
+        if index + 1 < self.ranges.len() && self.ranges[index].end == self.ranges[index + 1].start {
+            self.ranges[index].end = self.ranges.remove(index + 1).end;
+        }
+        if index > 0 && self.ranges[index - 1].end == self.ranges[index].start {
+            self.ranges[index - 1].end = self.ranges.remove(index).end;
+        }
+    }
+
+    /// Forgets all allocations, growing to hold at least `len` elements, up to `max`
+    fn reset(&mut self, len: u32, max: u32) {
+        if len > self.capacity || self.capacity > max {
+            self.capacity = len.checked_next_power_of_two().unwrap_or(max).min(max);
+        }
+        self.ranges.clear();
+        if self.capacity > 0 {
+            self.ranges.push(0..self.capacity);
+        }
+    }
+}
+
+/// The meshes the raytracer skips, with the reason
+#[derive(Resource, Debug, Default, Deref)]
+pub struct MeshDiagnostics(HashMap<AssetId<Mesh>, PrepareMeshError>);
+
+fn diagnose_meshes(
+    mut events: EventReader<AssetEvent<Mesh>>,
+    meshes: Res<Assets<Mesh>>,
+    mut diagnostics: ResMut<MeshDiagnostics>,
+) {
+    for event in events.read() {
+        match event {
+            AssetEvent::Added { id }
+            | AssetEvent::Modified { id }
+            | AssetEvent::LoadedWithDependencies { id } => {
+                match meshes.get(*id).map(check_topology) {
+                    Some(Err(err)) => diagnostics.0.insert(*id, err),
+                    _ => diagnostics.0.remove(id),
+                };
+            }
+            AssetEvent::Removed { id } => {
+                diagnostics.0.remove(id);
+            }
+        }
     }
 }
 
 #[derive(Default, Resource)]
 pub struct ExtractedMeshes {
-    extracted: Vec<(Handle<Mesh>, Mesh)>,
+    /// The meshes with their morph targets, if any
+    extracted: Vec<(Handle<Mesh>, Mesh, Option<Image>)>,
     removed: Vec<Handle<Mesh>>,
 }
 
@@ -74,6 +687,7 @@ fn extract_mesh_assets(
     mut commands: Commands,
     mut events: Extract<EventReader<AssetEvent<Mesh>>>,
     assets: Extract<Res<Assets<Mesh>>>,
+    images: Extract<Res<Assets<Image>>>,
 ) {
     let mut changed_assets = HashSet::new();
     let mut removed = vec![];
@@ -94,62 +708,217 @@ fn extract_mesh_assets(
     let mut extracted = vec![];
     for handle in changed_assets.drain() {
         if let Some(mesh) = assets.get(&handle) {
-            extracted.push((handle, mesh.clone()));
+            let morph_targets = morph_target_image(mesh).and_then(|image| images.get(image));
+            extracted.push((handle, mesh.clone(), morph_targets.cloned()));
         }
     }
 
     commands.insert_resource(ExtractedMeshes { extracted, removed });
 }
 
+type MeshTask = Task<Result<GpuMesh, PrepareMeshError>>;
+
+/// Builds the BVH of a mesh on the CPU, or leaves it to the GPU builder
+fn prepare_bvh(mesh: &mut GpuMesh, settings: &BvhSettings) {
+    if settings.builds_on_gpu() {
+        mesh.reserve_gpu_bvh();
+    } else {
+        mesh.rebuild_bvh(settings);
+    }
+}
+
+/// Converts the meshes and builds their BVH on the [`AsyncComputeTaskPool`].
+/// A mesh joins the buffers once its task is done, until then the previous version is kept.
+/// BVHs left to the GPU are built once their mesh is in the buffers.
+/// Deformed instances get a private copy of their mesh, placed next to the others.
+#[allow(clippy::too_many_arguments)]
 pub fn prepare_mesh_assets(
     mut extracted_assets: ResMut<ExtractedMeshes>,
     mut assets: Local<BTreeMap<Handle<Mesh>, GpuMesh>>,
+    mut tasks: Local<HashMap<Handle<Mesh>, MeshTask>>,
     mut meshes: ResMut<GpuMeshes>,
     mut render_assets: ResMut<MeshRenderAssets>,
+    mut builds: ResMut<LbvhBuilds>,
+    mut deformed: ResMut<DeformedMeshes>,
+    settings: Res<BvhSettings>,
+    geometry: Res<GeometrySettings>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
+    mut errors: ResMut<RaytracerErrors>,
 ) {
-    if extracted_assets.removed.is_empty() && extracted_assets.extracted.is_empty() {
-        return;
+    let mut changed = false;
+    // The chunks hold a different amount of geometry in another encoding
+    let mut fits = true;
+    if geometry.is_changed() {
+        render_assets.set_geometry(*geometry);
+        changed = true;
+        fits = false;
     }
 
+    let mut free = |handle: &Handle<Mesh>, mesh: &GpuMesh| {
+        if let Some(index) = meshes.remove(handle) {
+            render_assets.free(&index, mesh);
+            builds.cancel_mesh(&index);
+            // The copies follow the new version of their source
+            for copy in deformed.values_mut().filter(|copy| copy.source == *handle) {
+                copy.release(&mut render_assets, &mut builds);
+            }
+        }
+    };
+
     for handle in extracted_assets.removed.drain(..) {
-        assets.remove(&handle);
-        meshes.remove(&handle);
+        // Dropping the task cancels it
+        tasks.remove(&handle);
+        if let Some(mesh) = assets.remove(&handle) {
+            free(&handle, &mesh);
+            changed = true;
+        }
+    }
+
+    let thread_pool = AsyncComputeTaskPool::get();
+    let rebuild = |mut mesh: GpuMesh| {
+        let settings = settings.clone();
+        thread_pool.spawn(async move {
+            prepare_bvh(&mut mesh, &settings);
+            Ok(mesh)
+        })
+    };
+
+    if settings.is_changed() {
+        // The shader only traverses the current layout, the other meshes are hidden until rebuilt
+        let outdated = assets
+            .iter()
+            .filter(|(_, mesh)| mesh.bvh.layout != settings.layout)
+            .map(|(handle, _)| handle.clone_weak())
+            .collect_vec();
+        for handle in outdated {
+            let mesh = assets.remove(&handle).unwrap();
+            free(&handle, &mesh);
+            // A pending version of the mesh is checked when done
+            if !tasks.contains_key(&handle) {
+                tasks.insert(handle, rebuild(mesh));
+            }
+            changed = true;
+        }
+    }
+
+    for (handle, mesh, morph_targets) in extracted_assets.extracted.drain(..) {
+        // Replaces the task of an outdated version of the mesh
+        let settings = settings.clone();
+        let task = thread_pool.spawn(async move {
+            let deformation = MeshDeformation::new(&mesh, morph_targets.as_ref());
+            let mut mesh = GpuMesh::without_bvh(mesh)?;
+            mesh.deformation = deformation.map(Arc::new);
+            prepare_bvh(&mut mesh, &settings);
+            Ok(mesh)
+        });
+        tasks.insert(handle, task);
     }
-    for (handle, mesh) in extracted_assets.extracted.drain(..) {
-        match mesh.try_into() {
+
+    let finished = tasks
+        .iter()
+        .filter(|(_, task)| task.is_finished())
+        .map(|(handle, _)| handle.clone_weak())
+        .collect_vec();
+    let mut added = vec![];
+    for handle in finished {
+        let task = tasks.remove(&handle).unwrap();
+        match block_on(task) {
+            Ok(mesh) if mesh.bvh.layout != settings.layout => {
+                tasks.insert(handle, rebuild(mesh));
+            }
             Ok(mesh) => {
                 info!("Loaded mesh {}", assets.len());
-                assets.insert(handle, mesh);
-            }
-            Err(err) => {
-                warn!("Encounter an error when loading mesh: {:#?}", err);
+                debug!("BVH of mesh {}: {:?}", assets.len(), mesh.bvh);
+                if let Some(error) = RaytracerError::from_validation(handle.id(), &mesh.validation)
+                {
+                    errors.send(error);
+                }
+                if let Some(old_mesh) = assets.insert(handle.clone_weak(), mesh) {
+                    free(&handle, &old_mesh);
+                }
+                added.push(handle);
+                changed = true;
             }
+            Err(err) => errors.send(RaytracerError::Mesh(handle.id(), err)),
         }
     }
 
-    let mut vertices = vec![];
-    let mut primitives = vec![];
-    let mut nodes = vec![];
+    let copies = deformed
+        .iter()
+        .filter(|(_, copy)| copy.mesh().is_none() && assets.contains_key(&copy.source))
+        .map(|(entity, _)| *entity)
+        .collect_vec();
+    changed |= !copies.is_empty();
 
-    for (handle, mesh) in assets.iter() {
-        let vertex = vertices.len() as u32;
-        let primitive = primitives.len() as u32;
-        let node = UVec2::new(nodes.len() as u32, mesh.nodes.len() as u32);
+    if !changed {
+        return;
+    }
 
-        let index = GpuMeshIndex {
-            vertex,
-            primitive,
-            node,
-        };
-        meshes.insert(handle.clone_weak(), index);
+    for handle in added {
+        if !fits {
+            break;
+        }
+        let mesh = &assets[&handle];
+        match render_assets.allocate(mesh) {
+            Some(index) => {
+                if mesh.bvh.on_gpu {
+                    builds.build_mesh(index, mesh.primitives.len() as u32);
+                }
+                meshes.insert(handle, index);
+            }
+            None => fits = false,
+        }
+    }
+    for entity in copies {
+        let copy = deformed.get_mut(&entity).unwrap();
+        copy.set_bind_pose(&assets[&copy.source]);
+        if !fits {
+            continue;
+        }
+        // Copies are deformed, and their BVH built, by `deform_meshes`
+        match render_assets.allocate(copy.mesh().unwrap()) {
+            Some(index) => copy.place(index),
+            None => fits = false,
+        }
+    }
+    if !fits {
+        // Either fragmented or full: compact the chunks, growing them or adding some if needed
+        let copies = deformed.values().filter_map(DeformedMesh::mesh);
+        let indices = render_assets.repack(assets.values().chain(copies));
+        builds.cancel_meshes();
+        let hidden = indices.iter().filter(|index| index.is_none()).count();
+        if hidden > 0 {
+            let limits = render_assets.limits();
+            errors.send(RaytracerError::GeometryLimit {
+                hidden_meshes: hidden,
+                max_chunks: limits.max_chunks,
+                max_binding_size: limits.max_binding_size,
+            });
+        }
 
-        vertices.extend_from_slice(&mesh.vertices);
-        primitives.extend_from_slice(&mesh.primitives);
-        nodes.extend_from_slice(&mesh.nodes);
+        let (indices, copy_indices) = indices.split_at(assets.len());
+        for ((handle, mesh), index) in assets.iter().zip(indices) {
+            match index {
+                Some(index) => {
+                    if mesh.bvh.on_gpu {
+                        builds.build_mesh(*index, mesh.primitives.len() as u32);
+                    }
+                    meshes.insert(handle.clone_weak(), *index);
+                }
+                None => {
+                    meshes.remove(handle);
+                }
+            }
+        }
+        let copies = deformed.values_mut().filter(|copy| copy.mesh().is_some());
+        for (copy, index) in copies.zip(copy_indices) {
+            match index {
+                Some(index) => copy.place(*index),
+                None => copy.index = None,
+            }
+        }
     }
-    render_assets.set(vertices, primitives, nodes);
     render_assets.write_buffer(&render_device, &render_queue);
 }
 
@@ -158,35 +927,142 @@ pub struct GpuMesh {
     pub vertices: Vec<GpuVertexCompact>,
     pub primitives: Vec<GpuPrimitiveCompact>,
     pub nodes: Vec<GpuNode>,
+    pub attributes: GpuVertexAttributes,
+    pub bvh: BvhReport,
+    /// What was dropped or replaced while converting the mesh
+    pub validation: MeshValidation,
+    /// What deforms the mesh per instance, see [`MeshDeformation::deform`]
+    pub deformation: Option<Arc<MeshDeformation>>,
+}
+
+impl GpuMesh {
+    /// Appends the mesh to the universal buffers and returns where it was placed.
+    pub fn append(
+        &self,
+        vertices: &mut Vec<GpuVertexCompact>,
+        primitives: &mut Vec<GpuPrimitiveCompact>,
+        nodes: &mut Vec<GpuNode>,
+        attributes: &mut Vec<u32>,
+    ) -> GpuMeshIndex {
+        let index = GpuMeshIndex {
+            vertex: vertices.len() as u32,
+            primitive: primitives.len() as u32,
+            node: UVec2::new(nodes.len() as u32, self.nodes.len() as u32),
+            chunk: 0,
+            attributes: UVec2::new(attributes.len() as u32, self.attributes.flags()),
+        };
+
+        vertices.extend_from_slice(&self.vertices);
+        primitives.extend_from_slice(&self.primitives);
+        nodes.extend_from_slice(&self.nodes);
+        attributes.extend(self.attributes.words());
+        index
+    }
+
+    /// Bounds of the primitives
+    pub fn aabb(&self) -> Aabb {
+        let bounds = self
+            .primitives
+            .iter()
+            .fold(Bounds::EMPTY, |bounds, primitive| {
+                bounds.union(&primitive.bounds())
+            });
+        Aabb::from_min_max(bounds.min, bounds.max)
+    }
 }
 
 impl TryFrom<Mesh> for GpuMesh {
     type Error = PrepareMeshError;
 
     fn try_from(mesh: Mesh) -> Result<Self, Self::Error> {
+        Self::new(mesh, &BvhSettings::default())
+    }
+}
+
+impl GpuMesh {
+    /// Builds the BVH again with other settings
+    pub fn rebuild_bvh(&mut self, settings: &BvhSettings) {
+        (self.nodes, self.bvh) = build_bvh(&self.primitives, settings);
+    }
+
+    /// Leaves the BVH to the GPU builder, the nodes only reserve its place in the buffers
+    pub fn reserve_gpu_bvh(&mut self) {
+        let count = self.primitives.len();
+        self.nodes = placeholder_nodes(lbvh_node_count(count));
+        self.bvh = BvhReport {
+            layout: BvhLayout::Binary,
+            builder: BvhBuilder::Lbvh,
+            on_gpu: true,
+            nodes: self.nodes.len(),
+            leaves: count,
+            references: count,
+            ..default()
+        };
+    }
+
+    pub fn new(mesh: Mesh, settings: &BvhSettings) -> Result<Self, PrepareMeshError> {
+        let mut gpu_mesh = Self::without_bvh(mesh)?;
+        gpu_mesh.rebuild_bvh(settings);
+        Ok(gpu_mesh)
+    }
+
+    fn without_bvh(mesh: Mesh) -> Result<Self, PrepareMeshError> {
         let positions = mesh
             .attribute(Mesh::ATTRIBUTE_POSITION)
             .and_then(VertexAttributeValues::as_float3)
             .ok_or(PrepareMeshError::MissingAttributePosition)?;
+        check_topology(&mesh)?;
+        // Normals are generated from the primitives when missing, UVs are zero
         let normals = mesh
             .attribute(Mesh::ATTRIBUTE_NORMAL)
-            .and_then(VertexAttributeValues::as_float3)
-            .ok_or(PrepareMeshError::MissingAttributeNormal)?;
-        let uvs = mesh
+            .and_then(read_normals)
+            .filter(|normals| normals.len() == positions.len());
+        let mut uvs = mesh
             .attribute(Mesh::ATTRIBUTE_UV_0)
-            .and_then(|attribute| match attribute {
-                VertexAttributeValues::Float32x2(value) => Some(value),
-                _ => None,
-            })
-            .ok_or(PrepareMeshError::MissingAttributeUV)?;
+            .and_then(read_uvs)
+            .filter(|uvs| uvs.len() == positions.len())
+            .unwrap_or_else(|| vec![Vec2::ZERO; positions.len()]);
+
+        let colors = match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+            Some(VertexAttributeValues::Float32x4(colors)) if colors.len() == positions.len() => {
+                colors
+                    .iter()
+                    .map(|color| Vec4::from_array(*color))
+                    .collect()
+            }
+            _ => vec![],
+        };
+        let uvs_1 = match mesh.attribute(Mesh::ATTRIBUTE_UV_1) {
+            Some(VertexAttributeValues::Float32x2(uvs)) if uvs.len() == positions.len() => {
+                uvs.iter().map(|uv| Vec2::from_array(*uv)).collect()
+            }
+            _ => vec![],
+        };
+        let mut validation = MeshValidation::default();
+        let mut attributes = GpuVertexAttributes { colors, uvs_1 };
+        for color in &mut attributes.colors {
+            if !color.is_finite() {
+                *color = Vec4::ONE;
+                validation.non_finite_attributes += 1;
+            }
+        }
+        for uv in uvs.iter_mut().chain(&mut attributes.uvs_1) {
+            if !uv.is_finite() {
+                *uv = Vec2::ZERO;
+                validation.non_finite_attributes += 1;
+            }
+        }
 
         let mut vertices = vec![];
-        for (position, normal, uv) in itertools::multizip((positions, normals, uvs)) {
+        for (index, (position, uv)) in positions.iter().zip(uvs).enumerate() {
+            let normal = normals
+                .as_ref()
+                .map_or(Vec3::ZERO, |normals| normals[index]);
             vertices.push(GpuVertexCompact {
                 position: Vec3::from_slice(position),
-                normal: Vec3::from_slice(normal),
-                u: uv[0],
-                v: uv[1],
+                normal,
+                u: uv.x,
+                v: uv.y,
             });
         }
 
@@ -195,71 +1071,179 @@ impl TryFrom<Mesh> for GpuMesh {
             None => vertices.iter().enumerate().map(|(id, _)| id).collect_vec(),
         };
 
-        let primitives = match mesh.primitive_topology() {
+        let triangles: Vec<[usize; 3]> = match mesh.primitive_topology() {
             PrimitiveTopology::TriangleList => {
-                let mut primitives = vec![];
-                for chunk in &indices.iter().chunks(3) {
-                    let (v0, v1, v2) = chunk
-                        .cloned()
-                        .next_tuple()
-                        .ok_or(PrepareMeshError::IncompatiblePrimitiveTopology)?;
-                    let mut vertices = [v0, v1, v2].into_iter().map(|i| GpuPrimitiveVertex {
-                        position: vertices[i].position,
-                        index: i as u32,
-                    });
-                    let vertices = std::array::from_fn(|_| vertices.next().unwrap());
-                    primitives.push(GpuPrimitiveCompact { vertices });
+                if indices.len() % 3 != 0 {
+                    return Err(PrepareMeshError::IncompatiblePrimitiveTopology);
                 }
-                Ok(primitives)
+                indices
+                    .chunks_exact(3)
+                    .map(|chunk| [chunk[0], chunk[1], chunk[2]])
+                    .collect()
             }
-            PrimitiveTopology::TriangleStrip => {
-                let primitives = indices
-                    .iter()
-                    .cloned()
-                    .tuple_windows()
-                    .enumerate()
-                    .map(|(id, (v0, v1, v2))| {
-                        let indices = if id & 1 == 0 {
-                            [v0, v1, v2]
-                        } else {
-                            [v1, v0, v2]
-                        };
-                        let mut vertices = indices
-                            .into_iter()
-                            .map(|id| vertices[id].position)
-                            .zip(indices.iter())
-                            .map(|(pos, &i)| GpuPrimitiveVertex {
-                                position: pos,
-                                index: i as u32,
-                            });
-                        let vertices = std::array::from_fn(|_| vertices.next().unwrap());
-                        GpuPrimitiveCompact { vertices }
-                    })
-                    .collect();
-                Ok(primitives)
+            PrimitiveTopology::TriangleStrip => indices
+                .iter()
+                .cloned()
+                .tuple_windows()
+                .enumerate()
+                .map(|(id, (v0, v1, v2))| {
+                    if id & 1 == 0 {
+                        [v0, v1, v2]
+                    } else {
+                        [v1, v0, v2]
+                    }
+                })
+                .collect(),
+            topology => return Err(PrepareMeshError::UnsupportedTopology(topology)),
+        };
+
+        let mut primitives = vec![];
+        for triangle in triangles {
+            if triangle.iter().any(|&index| index >= vertices.len()) {
+                validation.out_of_range_primitives += 1;
+                continue;
+            }
+            let [v0, v1, v2] = triangle.map(|index| vertices[index].position);
+            if !(v0.is_finite() && v1.is_finite() && v2.is_finite()) {
+                validation.non_finite_primitives += 1;
+                continue;
+            }
+            // Also catches repeated indices, and NaN from overflowing products
+            let area = (v1 - v0).cross(v2 - v0).length_squared();
+            if area.is_nan() || area == 0.0 {
+                validation.degenerate_primitives += 1;
+                continue;
             }
-            _ => Err(PrepareMeshError::IncompatiblePrimitiveTopology),
-        }?;
+            let vertices = triangle.map(|index| GpuPrimitiveVertex {
+                position: vertices[index].position,
+                index: index as u32,
+            });
+            primitives.push(GpuPrimitiveCompact { vertices });
+        }
 
         if primitives.is_empty() {
             return Err(PrepareMeshError::NoPrimitive);
         }
+        for vertex in &mut vertices {
+            if !vertex.position.is_finite() {
+                vertex.position = Vec3::ZERO;
+            }
+        }
 
-        let mut shapes = primitives
-            .iter()
-            .map(|&p| GpuPrimitiveShape(p, 0))
-            .collect_vec();
-        let bvh = BVH::build(&mut shapes);
-        let nodes = bvh.flatten_custom(&GpuNode::pack);
+        // Missing normals are zero, so they are all generated
+        let invalid = |normal: Vec3| !normal.is_finite() || normal == Vec3::ZERO;
+        if vertices.iter().any(|vertex| invalid(vertex.normal)) {
+            let generated = generate_normals(vertices.len(), &primitives);
+            for (vertex, normal) in vertices.iter_mut().zip(generated) {
+                if invalid(vertex.normal) {
+                    vertex.normal = normal;
+                    validation.invalid_normals += normals.is_some() as usize;
+                }
+            }
+        }
 
         Ok(Self {
             vertices,
             primitives,
-            nodes,
+            attributes,
+            validation,
+            ..default()
         })
     }
 }
 
+/// Fails for the topologies the raytracer cannot trace: lines and points
+pub fn check_topology(mesh: &Mesh) -> Result<(), PrepareMeshError> {
+    match mesh.primitive_topology() {
+        PrimitiveTopology::TriangleList | PrimitiveTopology::TriangleStrip => Ok(()),
+        topology => Err(PrepareMeshError::UnsupportedTopology(topology)),
+    }
+}
+
+/// Normals in `Float32x3`, or in the xyz of `Snorm16x4` and `Snorm8x4`
+fn read_normals(values: &VertexAttributeValues) -> Option<Vec<Vec3>> {
+    let snorm = |x: f32, max: f32| (x / max).max(-1.0);
+    let normals = match values {
+        VertexAttributeValues::Float32x3(normals) => normals
+            .iter()
+            .map(|normal| Vec3::from_array(*normal))
+            .collect(),
+        VertexAttributeValues::Snorm16x4(normals) => normals
+            .iter()
+            .map(|n| Vec3::from_array([n[0], n[1], n[2]].map(|x| snorm(x as f32, 32767.0))))
+            .collect(),
+        VertexAttributeValues::Snorm8x4(normals) => normals
+            .iter()
+            .map(|n| Vec3::from_array([n[0], n[1], n[2]].map(|x| snorm(x as f32, 127.0))))
+            .collect(),
+        _ => return None,
+    };
+    Some(normals)
+}
+
+/// UVs in `Float32x2`, `Unorm16x2` or `Unorm8x2`.
+/// Bevy has no half float attribute values, so `Float16x2` cannot reach the raytracer.
+fn read_uvs(values: &VertexAttributeValues) -> Option<Vec<Vec2>> {
+    let uvs = match values {
+        VertexAttributeValues::Float32x2(uvs) => {
+            uvs.iter().map(|uv| Vec2::from_array(*uv)).collect()
+        }
+        VertexAttributeValues::Unorm16x2(uvs) => uvs
+            .iter()
+            .map(|uv| Vec2::from_array(uv.map(|x| x as f32 / 65535.0)))
+            .collect(),
+        VertexAttributeValues::Unorm8x2(uvs) => uvs
+            .iter()
+            .map(|uv| Vec2::from_array(uv.map(|x| x as f32 / 255.0)))
+            .collect(),
+        _ => return None,
+    };
+    Some(uvs)
+}
+
+/// Smooth normals, the area weighted sum of the normals of the primitives around each vertex.
+/// They are flat when the primitives share no vertex, e.g. without indices.
+fn generate_normals(count: usize, primitives: &[GpuPrimitiveCompact]) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::ZERO; count];
+    for primitive in primitives {
+        let [v0, v1, v2] = primitive.vertices.map(|vertex| vertex.position);
+        let normal = (v1 - v0).cross(v2 - v0);
+        for vertex in &primitive.vertices {
+            normals[vertex.index as usize] += normal;
+        }
+    }
+    normals
+        .into_iter()
+        .map(|normal| normal.try_normalize().unwrap_or(Vec3::Y))
+        .collect()
+}
+
+/// Counts of the invalid data [`GpuMesh::new`] dropped or replaced
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MeshValidation {
+    /// Primitives with an index past the last vertex, dropped
+    pub out_of_range_primitives: usize,
+    /// Primitives with a non-finite position, dropped; the positions are zeroed
+    pub non_finite_primitives: usize,
+    /// Primitives of zero area, dropped
+    pub degenerate_primitives: usize,
+    /// Non-finite or zero normals, replaced by generated ones
+    pub invalid_normals: usize,
+    /// Non-finite UVs and colors, replaced by zero and white
+    pub non_finite_attributes: usize,
+}
+
+impl MeshValidation {
+    pub fn dropped_primitives(&self) -> usize {
+        self.out_of_range_primitives + self.non_finite_primitives + self.degenerate_primitives
+    }
+
+    /// Whether the mesh was converted as is
+    pub fn is_clean(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
 /// Container for vertex data
 #[derive(Default, ShaderType)]
 pub struct GpuVertexBuffer {
@@ -274,6 +1258,103 @@ pub struct GpuPrimitiveBuffer {
     pub data: Vec<GpuPrimitiveCompact>,
 }
 
+/// Container for the encoded vertices or primitives, see [`GeometrySettings`]
+#[derive(Default, ShaderType)]
+pub struct GpuWordBuffer {
+    #[size(runtime)]
+    pub data: Vec<u32>,
+}
+
+/// Flag of [`GpuVertexAttributes::flags`] for vertex colors
+pub const VERTEX_COLOR: u32 = 1;
+/// Flag of [`GpuVertexAttributes::flags`] for the second UV set
+pub const VERTEX_UV_1: u32 = 2;
+
+/// Vertex attributes only some meshes have, stored in a side buffer.
+/// Each is either empty or has one value per vertex.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GpuVertexAttributes {
+    /// `Mesh::ATTRIBUTE_COLOR`, multiplied into the base color
+    pub colors: Vec<Vec4>,
+    /// `Mesh::ATTRIBUTE_UV_1`
+    pub uvs_1: Vec<Vec2>,
+}
+
+impl GpuVertexAttributes {
+    /// The attributes present, [`VERTEX_COLOR`] and [`VERTEX_UV_1`]
+    pub fn flags(&self) -> u32 {
+        let mut flags = 0;
+        if !self.colors.is_empty() {
+            flags |= VERTEX_COLOR;
+        }
+        if !self.uvs_1.is_empty() {
+            flags |= VERTEX_UV_1;
+        }
+        flags
+    }
+
+    /// Words per vertex
+    pub fn stride(&self) -> u32 {
+        attribute_stride(self.flags())
+    }
+
+    /// Words of all the vertices
+    pub fn len(&self) -> u32 {
+        let vertices = self.colors.len().max(self.uvs_1.len());
+        vertices as u32 * self.stride()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The present attributes interleaved per vertex: the color, then the second UVs
+    pub fn words(&self) -> Vec<u32> {
+        let vertices = self.colors.len().max(self.uvs_1.len());
+        let mut words = Vec::with_capacity(self.len() as usize);
+        for vertex in 0..vertices {
+            if let Some(color) = self.colors.get(vertex) {
+                words.extend(color.to_array().map(f32::to_bits));
+            }
+            if let Some(uv) = self.uvs_1.get(vertex) {
+                words.extend(uv.to_array().map(f32::to_bits));
+            }
+        }
+        words
+    }
+}
+
+/// Words per vertex of the attributes in `flags`
+pub fn attribute_stride(flags: u32) -> u32 {
+    let mut stride = 0;
+    if flags & VERTEX_COLOR != 0 {
+        stride += 4;
+    }
+    if flags & VERTEX_UV_1 != 0 {
+        stride += 2;
+    }
+    stride
+}
+
+/// Words of a vertex when [`GeometrySettings::quantized_vertices`] is set
+pub const QUANTIZED_VERTEX_WORDS: usize = 5;
+/// Words of a primitive when [`GeometrySettings::compact_primitives`] is set
+pub const COMPACT_PRIMITIVE_WORDS: usize = 3;
+
+fn quantize_vertices(vertices: &[GpuVertexCompact]) -> Vec<u32> {
+    vertices
+        .iter()
+        .flat_map(GpuVertexCompact::quantize)
+        .collect()
+}
+
+fn compact_primitives_words(primitives: &[GpuPrimitiveCompact]) -> Vec<u32> {
+    primitives
+        .iter()
+        .flat_map(GpuPrimitiveCompact::indices)
+        .collect()
+}
+
 /// A single vertex
 /// This must match the Vertex definition on the shader
 #[derive(Debug, Default, Clone, Copy, ShaderType)]
@@ -284,6 +1365,63 @@ pub struct GpuVertexCompact {
     pub v: f32,
 }
 
+impl GpuVertexCompact {
+    /// The position at full precision, the octahedral normal packed like `pack2x16snorm`
+    /// and the UV packed like `pack2x16float`
+    pub fn quantize(&self) -> [u32; QUANTIZED_VERTEX_WORDS] {
+        let [x, y, z] = self.position.to_array().map(f32::to_bits);
+        let normal = octahedral_encode(self.normal);
+        let snorm = |value: f32| (value.clamp(-1.0, 1.0) * 32767.0).round() as i16 as u16 as u32;
+        let half = |value: f32| f16::from_f32(value).to_bits() as u32;
+        [
+            x,
+            y,
+            z,
+            snorm(normal.x) | snorm(normal.y) << 16,
+            half(self.u) | half(self.v) << 16,
+        ]
+    }
+
+    /// Decodes [`Self::quantize`] like the shader
+    pub fn dequantize(words: [u32; QUANTIZED_VERTEX_WORDS]) -> Self {
+        let snorm = |bits: u32| (bits as u16 as i16 as f32 / 32767.0).max(-1.0);
+        let half = |bits: u32| f16::from_bits(bits as u16).to_f32();
+        Self {
+            position: Vec3::new(
+                f32::from_bits(words[0]),
+                f32::from_bits(words[1]),
+                f32::from_bits(words[2]),
+            ),
+            normal: octahedral_decode(Vec2::new(snorm(words[3]), snorm(words[3] >> 16))),
+            u: half(words[4]),
+            v: half(words[4] >> 16),
+        }
+    }
+}
+
+/// Maps a direction to the unit square, through the octahedron
+fn octahedral_encode(normal: Vec3) -> Vec2 {
+    let length = normal.x.abs() + normal.y.abs() + normal.z.abs();
+    if length == 0.0 {
+        return Vec2::ZERO;
+    }
+    let normal = normal / length;
+    if normal.z >= 0.0 {
+        normal.xy()
+    } else {
+        let sign = Vec2::select(normal.xy().cmpge(Vec2::ZERO), Vec2::ONE, Vec2::NEG_ONE);
+        (1.0 - normal.yx().abs()) * sign
+    }
+}
+
+fn octahedral_decode(encoded: Vec2) -> Vec3 {
+    let mut normal = encoded.extend(1.0 - encoded.x.abs() - encoded.y.abs());
+    let fold = (-normal.z).max(0.0);
+    normal.x += if normal.x >= 0.0 { -fold } else { fold };
+    normal.y += if normal.y >= 0.0 { -fold } else { fold };
+    normal.normalize()
+}
+
 /// Only contains the local position of the vertex and its index in the vertex buffer
 /// This must match the Primitive definition on the shader
 #[derive(Debug, Default, Clone, Copy, ShaderType)]
@@ -297,24 +1435,43 @@ pub struct GpuPrimitiveCompact {
     pub vertices: [GpuPrimitiveVertex; 3],
 }
 
-// Used to create BVH
-struct GpuPrimitiveShape(GpuPrimitiveCompact, usize);
-
-impl Bounded for GpuPrimitiveShape {
-    fn aabb(&self) -> AABB {
-        AABB::empty()
-            .grow(&self.0.vertices[0].position.to_array().into())
-            .grow(&self.0.vertices[1].position.to_array().into())
-            .grow(&self.0.vertices[2].position.to_array().into())
+impl GpuPrimitiveCompact {
+    /// The primitive when [`GeometrySettings::compact_primitives`] is set
+    pub fn indices(&self) -> [u32; COMPACT_PRIMITIVE_WORDS] {
+        self.vertices.map(|vertex| vertex.index)
     }
 }
 
-impl BHShape for GpuPrimitiveShape {
-    fn set_bh_node_index(&mut self, index: usize) {
-        self.1 = index;
+impl BvhPrimitive for GpuPrimitiveCompact {
+    fn bounds(&self) -> Bounds {
+        let mut bounds = Bounds::EMPTY;
+        for vertex in &self.vertices {
+            bounds.grow(vertex.position);
+        }
+        bounds
     }
 
-    fn bh_node_index(&self) -> usize {
-        self.1
+    fn split(&self, bounds: &Bounds, axis: usize, position: f32) -> (Bounds, Bounds) {
+        let mut left = Bounds::EMPTY;
+        let mut right = Bounds::EMPTY;
+        for (index, vertex) in self.vertices.iter().enumerate() {
+            let start = vertex.position;
+            let end = self.vertices[(index + 1) % 3].position;
+            if start[axis] <= position {
+                left.grow(start);
+            }
+            if start[axis] >= position {
+                right.grow(start);
+            }
+            // The edge crosses the plane
+            if (start[axis] < position) != (end[axis] < position) && start[axis] != end[axis] {
+                let t = (position - start[axis]) / (end[axis] - start[axis]);
+                let mut point = start.lerp(end, t.clamp(0.0, 1.0));
+                point[axis] = position;
+                left.grow(point);
+                right.grow(point);
+            }
+        }
+        (left.intersection(bounds), right.intersection(bounds))
     }
 }
@@ -1,7 +1,12 @@
 use crate::{
-    mesh_material::{MeshMaterialBindGroup, MeshMaterialBindGroupLayout, TextureBindGroupLayout},
-    view::{ViewBindGroup, ViewBindGroupLayout},
-    ColorBuffer, RtSettings, COLOR_BUFFER_FORMAT, RT_SHADER_HANDLE, SIZE, WORKGROUP_SIZE,
+    error::{RaytracerError, RaytracerErrors},
+    mesh_material::{
+        material::RaytracedMaterials, mesh::MeshRenderAssets, MeshMaterialBindGroup,
+        MeshMaterialBindGroupLayout, TextureBindGroupLayout,
+    },
+    view::{RaytracerViewUniformOffset, ViewBindGroup, ViewBindGroupLayout},
+    BvhLayout, BvhSettings, BvhTraversal, ColorBuffer, GeometrySettings, RtSettings,
+    COLOR_BUFFER_FORMAT, RT_SHADER_HANDLE, SIZE, WORKGROUP_SIZE,
 };
 use bevy::{
     ecs::query::WorldQuery,
@@ -23,12 +28,6 @@ impl Plugin for RaytracerPipelinePlugin {
         if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
             render_app
                 .init_resource::<SpecializedComputePipelines<RaytracerPipelineLayout>>()
-                .add_systems(
-                    Render,
-                    queue_raytracer_pipeline_layout
-                        .in_set(RenderSet::PrepareResources)
-                        .before(queue_raytracer_pipeline),
-                )
                 .add_systems(
                     Render,
                     queue_raytracer_pipeline.in_set(RenderSet::PrepareResources),
@@ -81,8 +80,13 @@ fn prepare_color_buffer_bind_group(
     color_buffer: Res<ColorBuffer>,
     render_device: Res<RenderDevice>,
     layout: Res<ColorBufferBindGroupLayout>,
+    mut errors: ResMut<RaytracerErrors>,
 ) {
-    let view = gpu_images.get(&**color_buffer).unwrap();
+    let Some(view) = gpu_images.get(&**color_buffer) else {
+        errors.send(RaytracerError::MissingColorBuffer);
+        commands.remove_resource::<ColorBufferBindGroup>();
+        return;
+    };
     let bind_group = render_device.create_bind_group(
         None,
         &layout,
@@ -94,68 +98,51 @@ fn prepare_color_buffer_bind_group(
 #[derive(Resource)]
 pub struct RaytracerPipelineLayout {
     mesh_material_layout: BindGroupLayout,
-    texture_layout: TextureBindGroupLayout,
+    texture_layout: BindGroupLayout,
     color_buffer_layout: BindGroupLayout,
     view_buffer_layout: BindGroupLayout,
 }
 
-impl RaytracerPipelineLayout {
-    fn get_layout(
-        mesh_material_layout: &MeshMaterialBindGroupLayout,
-        texture_layout: &TextureBindGroupLayout,
-        color_buffer_layout: &ColorBufferBindGroupLayout,
-        view_buffer_layout: &ViewBindGroupLayout,
-    ) -> Self {
-        Self {
-            mesh_material_layout: mesh_material_layout.0.clone(),
-            texture_layout: texture_layout.clone(),
-            color_buffer_layout: color_buffer_layout.0.clone(),
-            view_buffer_layout: view_buffer_layout.0.clone(),
-        }
-    }
-}
-
 impl FromWorld for RaytracerPipelineLayout {
     fn from_world(world: &mut World) -> Self {
-        let mesh_material_layout = world.resource::<MeshMaterialBindGroupLayout>();
-        let texture_layout = world.resource::<TextureBindGroupLayout>();
-        let color_buffer_layout = world.resource::<ColorBufferBindGroupLayout>();
-        let view_buffer_layout = world.resource::<ViewBindGroupLayout>();
-        Self::get_layout(
-            mesh_material_layout,
-            texture_layout,
-            color_buffer_layout,
-            view_buffer_layout,
-        )
+        Self {
+            mesh_material_layout: world.resource::<MeshMaterialBindGroupLayout>().0.clone(),
+            texture_layout: world.resource::<TextureBindGroupLayout>().layout.clone(),
+            color_buffer_layout: world.resource::<ColorBufferBindGroupLayout>().0.clone(),
+            view_buffer_layout: world.resource::<ViewBindGroupLayout>().0.clone(),
+        }
     }
 }
 
-fn queue_raytracer_pipeline_layout(
-    mut layout: ResMut<RaytracerPipelineLayout>,
-    mesh_material_layout: Res<MeshMaterialBindGroupLayout>,
-    texture_layout: Res<TextureBindGroupLayout>,
-    color_buffer_layout: Res<ColorBufferBindGroupLayout>,
-    view_buffer_layout: Res<ViewBindGroupLayout>,
-) {
-    *layout = RaytracerPipelineLayout::get_layout(
-        &mesh_material_layout,
-        &texture_layout,
-        &color_buffer_layout,
-        &view_buffer_layout,
-    );
-}
-
 #[derive(Hash, Clone, Eq, PartialEq)]
 pub struct RaytracerPipelineKey {
     max_bounces: u32,
-    texture_count: u32,
+    bvh_layout: BvhLayout,
+    bvh_traversal: BvhTraversal,
+    bvh_stats: bool,
+    geometry: GeometrySettings,
+    /// Chunks of geometry bound by the layout, this depends only on the device
+    geometry_chunks: u32,
+    /// Whether [`crate::RaytracedMaterial`]s are registered
+    custom_materials: bool,
 }
 
 impl RaytracerPipelineKey {
-    fn new(max_bounces: u32, texture_count: u32) -> Self {
+    fn new(
+        max_bounces: u32,
+        bvh_settings: &BvhSettings,
+        geometry: GeometrySettings,
+        geometry_chunks: u32,
+        custom_materials: bool,
+    ) -> Self {
         Self {
             max_bounces,
-            texture_count: texture_count.next_power_of_two(),
+            bvh_layout: bvh_settings.layout,
+            bvh_traversal: bvh_settings.traversal,
+            bvh_stats: bvh_settings.show_traversal_stats,
+            geometry,
+            geometry_chunks,
+            custom_materials,
         }
     }
 }
@@ -164,17 +151,35 @@ impl SpecializedComputePipeline for RaytracerPipelineLayout {
     type Key = RaytracerPipelineKey;
 
     fn specialize(&self, key: Self::Key) -> ComputePipelineDescriptor {
+        let mut shader_defs = vec![
+            ShaderDefVal::UInt("MAX_BOUNCES".into(), key.max_bounces),
+            ShaderDefVal::UInt("GEOMETRY_CHUNKS".into(), key.geometry_chunks),
+        ];
+        if let Some(width) = key.bvh_layout.width() {
+            shader_defs.push(ShaderDefVal::UInt("BVH_WIDTH".into(), width as u32));
+        }
+        if key.bvh_traversal == BvhTraversal::Ordered {
+            shader_defs.push("BVH_ORDERED".into());
+        }
+        if key.bvh_stats {
+            shader_defs.push("BVH_STATS".into());
+        }
+        shader_defs.extend(key.geometry.shader_defs());
+        if key.custom_materials {
+            shader_defs.push("CUSTOM_MATERIALS".into());
+        }
+
         ComputePipelineDescriptor {
             label: Some(Cow::Borrowed("rt_compute_pipeline")),
             layout: vec![
                 self.color_buffer_layout.clone(),
                 self.mesh_material_layout.clone(),
-                self.texture_layout.layout.clone(),
+                self.texture_layout.clone(),
                 self.view_buffer_layout.clone(),
             ],
             push_constant_ranges: vec![],
             shader: RT_SHADER_HANDLE.clone(),
-            shader_defs: vec![ShaderDefVal::UInt("MAX_BOUNCES".into(), key.max_bounces)],
+            shader_defs,
             entry_point: Cow::from("main"),
         }
     }
@@ -183,18 +188,31 @@ impl SpecializedComputePipeline for RaytracerPipelineLayout {
 #[derive(Resource, Deref, DerefMut)]
 pub struct RaytracerPipeline(CachedComputePipelineId);
 
+#[allow(clippy::too_many_arguments)]
 fn queue_raytracer_pipeline(
     mut commands: Commands,
     pipeline_cache: Res<PipelineCache>,
     mut pipelines: ResMut<SpecializedComputePipelines<RaytracerPipelineLayout>>,
     rt_pipeline_layout: Res<RaytracerPipelineLayout>,
     settings: Res<RtSettings>,
+    bvh_settings: Res<BvhSettings>,
+    geometry: Res<GeometrySettings>,
+    meshes: Res<MeshRenderAssets>,
+    materials: Res<RaytracedMaterials>,
+    mut errors: ResMut<RaytracerErrors>,
 ) {
     let key = RaytracerPipelineKey::new(
         settings.max_bounces,
-        rt_pipeline_layout.texture_layout.texture_count,
+        &bvh_settings,
+        *geometry,
+        meshes.limits().max_chunks as u32,
+        !materials.is_empty(),
     );
     let pipeline_id = pipelines.specialize(&pipeline_cache, &rt_pipeline_layout, key);
+    let state = pipeline_cache.get_compute_pipeline_state(pipeline_id);
+    if let Some(error) = RaytracerError::from_pipeline(state) {
+        errors.send(error);
+    }
     commands.insert_resource(RaytracerPipeline(pipeline_id));
 }
 
@@ -202,17 +220,26 @@ fn queue_raytracer_pipeline(
 pub struct RaytracerNode;
 impl render_graph::ViewNode for RaytracerNode {
     // ViewTargets are cameras
-    type ViewQuery = (&'static ViewTarget, &'static ViewUniformOffset);
+    type ViewQuery = (
+        &'static ViewTarget,
+        &'static ViewUniformOffset,
+        &'static RaytracerViewUniformOffset,
+    );
 
     fn run(
         &self,
         _graph: &mut render_graph::RenderGraphContext,
         render_context: &mut RenderContext,
-        (_target, view_uniform_offset): <Self::ViewQuery as WorldQuery>::Item<'_>,
+        (_target, view_uniform_offset, raytracer_view_offset): <Self::ViewQuery as WorldQuery>::Item<'_>,
         world: &World,
     ) -> Result<(), render_graph::NodeRunError> {
-        let color_buffer_bind_group = world.resource::<ColorBufferBindGroup>();
-        let mesh_material_bind_group = world.resource::<MeshMaterialBindGroup>();
+        // The frame is skipped while a bind group is missing
+        let (Some(color_buffer_bind_group), Some(mesh_material_bind_group)) = (
+            world.get_resource::<ColorBufferBindGroup>(),
+            world.get_resource::<MeshMaterialBindGroup>(),
+        ) else {
+            return Ok(());
+        };
         let view_bind_group = world.resource::<ViewBindGroup>();
         let pipeline_cache = world.resource::<PipelineCache>();
         let pipeline = world.resource::<RaytracerPipeline>();
@@ -225,7 +252,11 @@ impl render_graph::ViewNode for RaytracerNode {
             compute_pass.set_bind_group(0, color_buffer_bind_group, &[]);
             compute_pass.set_bind_group(1, &mesh_material_bind_group.mesh_material, &[]);
             compute_pass.set_bind_group(2, &mesh_material_bind_group.textures, &[]);
-            compute_pass.set_bind_group(3, view_bind_group, &[view_uniform_offset.offset]);
+            compute_pass.set_bind_group(
+                3,
+                view_bind_group,
+                &[view_uniform_offset.offset, raytracer_view_offset.offset],
+            );
             compute_pass.dispatch_workgroups(SIZE.0 / WORKGROUP_SIZE, SIZE.1 / WORKGROUP_SIZE, 1);
         }
 
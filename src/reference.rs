@@ -0,0 +1,1171 @@
+//! CPU mirror of `raytracer.wgsl`.
+//!
+//! The integrator consumes the same flattened buffers that are uploaded to the GPU, and each
+//! function here has the name of the shader function it mirrors. Any change to the shader
+//! must be reflected here, otherwise the reference stops being one.
+use crate::export::{HdrImage, RenderMetadata};
+pub use crate::mesh_material::{
+    bvh_builder::{build_bvh, Bounds, BvhLayout, BvhPrimitive, BvhSettings, BvhTraversal},
+    deform::{deform_mesh, MeshDeformation, MeshPose},
+    dirty_ranges, element_offset,
+    instance::{
+        build_instance_nodes, instance_bvh_cost, layer_mask, refit_instance_nodes, GpuInstance,
+        GpuInstanceBuffer, REBUILD_COST_GROWTH,
+    },
+    lbvh::{karras_children, morton_code},
+    material::GpuStandardMaterial,
+    mesh::{
+        attribute_stride, GeometrySettings, GpuMesh, GpuPrimitiveCompact, GpuPrimitiveVertex,
+        GpuVertexAttributes, GpuVertexCompact, VERTEX_COLOR,
+    },
+    GpuMeshIndex, GpuNode, GpuNodeBuffer, PrepareMeshError,
+};
+use bevy::{
+    prelude::*,
+    render::{camera::CameraProjection, primitives, render_resource::TextureFormat},
+};
+use indexmap::IndexSet;
+use std::{
+    cell::Cell,
+    f32::consts::{FRAC_1_PI, PI},
+    sync::Arc,
+    thread,
+    time::Instant,
+};
+
+const F32_MAX: f32 = f32::MAX;
+const U32_MAX: u32 = u32::MAX;
+const INV_PI: f32 = FRAC_1_PI;
+const BVH_LEAF_FLAG: u32 = 0x80000000;
+/// Kinds of rays, see `RayVisibility`
+pub const RAY_CAMERA: u32 = 1;
+pub const RAY_DIFFUSE: u32 = 2;
+pub const RAY_SPECULAR: u32 = 4;
+const BVH_EMPTY_CHILD: u32 = 0xFFFFFFFF;
+const BVH_STACK_SIZE: usize = 64;
+const BVH_SHORT_STACK_SIZE: usize = 8;
+/// Same lower bound as the PBR shaders of Bevy, smoother metals alias
+const MIN_PERCEPTUAL_ROUGHNESS: f32 = 0.089;
+/// Mirrors the `CULLING` define of the shader
+const CULLING: bool = true;
+
+/// The buffers bound to the raytracer pipeline, kept on the CPU
+#[derive(Default)]
+pub struct ReferenceScene {
+    pub vertices: Vec<GpuVertexCompact>,
+    pub primitives: Vec<GpuPrimitiveCompact>,
+    pub primitive_nodes: Vec<GpuNode>,
+    /// Optional vertex attributes, see [`GpuVertexAttributes`]
+    pub attributes: Vec<u32>,
+    pub materials: Vec<GpuStandardMaterial>,
+    pub instances: Vec<GpuInstance>,
+    pub instance_nodes: Vec<GpuNode>,
+    pub textures: Vec<Image>,
+    /// Settings of the mesh BVHs. The layout and traversal select the traversal functions
+    /// like the `BVH_WIDTH` and `BVH_ORDERED` defines.
+    pub bvh: BvhSettings,
+    /// Encoding of the geometry, like the `COMPACT_PRIMITIVES` and `QUANTIZED_VERTICES` defines.
+    /// Compact primitives read the same positions, quantized vertices round their attributes.
+    pub geometry: GeometrySettings,
+    texture_handles: IndexSet<Handle<Image>>,
+}
+
+/// A mesh that was added to a [`ReferenceScene`]
+#[derive(Debug, Clone, Copy)]
+pub struct ReferenceMesh {
+    pub index: GpuMeshIndex,
+    pub aabb: primitives::Aabb,
+}
+
+impl ReferenceScene {
+    /// Converts the mesh like `prepare_mesh_assets` does and appends it to the buffers.
+    pub fn add_mesh(&mut self, mesh: Mesh) -> Result<ReferenceMesh, PrepareMeshError> {
+        let aabb = mesh
+            .compute_aabb()
+            .ok_or(PrepareMeshError::MissingAttributePosition)?;
+        let mesh = GpuMesh::new(mesh, &self.bvh)?;
+        let index = mesh.append(
+            &mut self.vertices,
+            &mut self.primitives,
+            &mut self.primitive_nodes,
+            &mut self.attributes,
+        );
+        Ok(ReferenceMesh { index, aabb })
+    }
+
+    /// Adds the material and returns its index.
+    /// Textures missing from `images` are replaced by white, like the GPU does.
+    pub fn add_material(&mut self, material: &StandardMaterial, images: &Assets<Image>) -> u32 {
+        self.materials.push(GpuStandardMaterial::new(
+            material,
+            &mut self.texture_handles,
+        ));
+        for handle in self.texture_handles.iter().skip(self.textures.len()) {
+            self.textures
+                .push(images.get(handle).cloned().unwrap_or_default());
+        }
+        self.materials.len() as u32 - 1
+    }
+
+    /// Adds an instance and rebuilds the instance BVH.
+    pub fn add_instance(
+        &mut self,
+        mesh: &ReferenceMesh,
+        material: u32,
+        transform: impl Into<GlobalTransform>,
+    ) -> u32 {
+        let transform = transform.into();
+        self.instances.push(GpuInstance::new(
+            &mesh.aabb, &transform, mesh.index, material,
+        ));
+        self.instance_nodes = build_instance_nodes(&self.instances, self.bvh.builder);
+        self.instances.len() as u32 - 1
+    }
+
+    /// Adds an instance of a private copy of the mesh, deformed by the pose like
+    /// `deform_meshes` does, and rebuilds the instance BVH.
+    /// `morph_targets` is the image set by [`Mesh::set_morph_targets`].
+    pub fn add_deformed_instance(
+        &mut self,
+        mesh: Mesh,
+        morph_targets: Option<&Image>,
+        pose: &MeshPose,
+        material: u32,
+        transform: impl Into<GlobalTransform>,
+    ) -> Result<u32, PrepareMeshError> {
+        let deformation = MeshDeformation::new(&mesh, morph_targets);
+        let mut bind_pose = GpuMesh::new(mesh, &self.bvh)?;
+        bind_pose.deformation = deformation.map(Arc::new);
+
+        let mut copy = bind_pose.clone();
+        let transform = if deform_mesh(&bind_pose, pose, &mut copy) {
+            GlobalTransform::IDENTITY
+        } else {
+            transform.into()
+        };
+        let index = copy.append(
+            &mut self.vertices,
+            &mut self.primitives,
+            &mut self.primitive_nodes,
+            &mut self.attributes,
+        );
+        self.instances
+            .push(GpuInstance::new(&copy.aabb(), &transform, index, material));
+        self.instance_nodes = build_instance_nodes(&self.instances, self.bvh.builder);
+        Ok(self.instances.len() as u32 - 1)
+    }
+
+    /// Equivalent of `textureSampleLevel` with a linear, clamp to edge sampler
+    fn sample_texture(&self, index: u32, uv: Vec2) -> Vec4 {
+        let image = &self.textures[index as usize];
+        let size = image.size();
+        if size.x == 0 || size.y == 0 {
+            return Vec4::ONE;
+        }
+
+        let position = uv * size.as_vec2() - 0.5;
+        let base = position.floor();
+        let weight = position - base;
+        let texel = |offset: IVec2| {
+            let coords = (base.as_ivec2() + offset).clamp(IVec2::ZERO, size.as_ivec2() - 1);
+            load_texel(image, coords.as_uvec2())
+        };
+
+        let top = texel(IVec2::new(0, 0)).lerp(texel(IVec2::new(1, 0)), weight.x);
+        let bottom = texel(IVec2::new(0, 1)).lerp(texel(IVec2::new(1, 1)), weight.x);
+        top.lerp(bottom, weight.y)
+    }
+}
+
+fn load_texel(image: &Image, coords: UVec2) -> Vec4 {
+    let index = (coords.y * image.size().x + coords.x) as usize;
+    match image.texture_descriptor.format {
+        TextureFormat::Rgba8Unorm => {
+            let texel = &image.data[index * 4..index * 4 + 4];
+            Vec4::from_array(std::array::from_fn(|i| texel[i] as f32 / 255.0))
+        }
+        TextureFormat::Rgba8UnormSrgb => {
+            let texel = &image.data[index * 4..index * 4 + 4];
+            Vec4::from_array(
+                Color::rgba_u8(texel[0], texel[1], texel[2], texel[3]).as_linear_rgba_f32(),
+            )
+        }
+        TextureFormat::Rgba32Float => {
+            let texel = &image.data[index * 16..index * 16 + 16];
+            Vec4::from_array(std::array::from_fn(|i| {
+                f32::from_le_bytes(texel[i * 4..i * 4 + 4].try_into().unwrap())
+            }))
+        }
+        // TODO: other formats
+        _ => Vec4::ONE,
+    }
+}
+
+/// The parts of bevy's `View` uniform, and of the `RaytracerView` one, that the shader reads
+#[derive(Debug, Clone, Copy)]
+pub struct ReferenceView {
+    pub size: UVec2,
+    /// Camera to world
+    pub view: Mat4,
+    pub inverse_projection: Mat4,
+    /// `RenderLayers` masks of the camera, for its primary rays and for the other ones
+    pub primary_layers: u32,
+    pub secondary_layers: u32,
+}
+
+impl ReferenceView {
+    pub fn new(size: UVec2, transform: &GlobalTransform, projection: &Projection) -> Self {
+        let mut projection = projection.clone();
+        projection.update(size.x as f32, size.y as f32);
+        Self {
+            size,
+            view: transform.compute_matrix(),
+            inverse_projection: projection.get_projection_matrix().inverse(),
+            primary_layers: layer_mask(None),
+            secondary_layers: layer_mask(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReferenceSettings {
+    pub samples_per_pixel: u32,
+    pub max_bounces: u32,
+}
+
+impl Default for ReferenceSettings {
+    /// Matches the constants currently hardcoded in the shader
+    fn default() -> Self {
+        Self {
+            samples_per_pixel: 1,
+            max_bounces: 5,
+        }
+    }
+}
+
+/// Renders the scene on the CPU.
+/// Unlike the color buffer of the GPU pipeline, the radiance is not clamped.
+pub fn render(
+    scene: &ReferenceScene,
+    view: &ReferenceView,
+    settings: &ReferenceSettings,
+) -> HdrImage {
+    let start = Instant::now();
+    let width = view.size.x as usize;
+    let mut pixels = vec![Vec4::ZERO; width * view.size.y as usize];
+
+    let threads = thread::available_parallelism().map_or(1, usize::from);
+    let rows_per_thread = (view.size.y as usize).div_ceil(threads).max(1);
+    thread::scope(|scope| {
+        for (chunk_index, chunk) in pixels
+            .chunks_mut(rows_per_thread * width.max(1))
+            .enumerate()
+        {
+            scope.spawn(move || {
+                for (index, pixel) in chunk.iter_mut().enumerate() {
+                    let index = chunk_index * rows_per_thread * width + index;
+                    let screen_pos = UVec2::new((index % width) as u32, (index / width) as u32);
+                    *pixel = main(scene, view, settings, screen_pos);
+                }
+            });
+        }
+    });
+
+    let metadata = RenderMetadata {
+        samples: settings.samples_per_pixel,
+        max_bounces: settings.max_bounces,
+        render_time: start.elapsed(),
+        camera_transform: view.view,
+    };
+    HdrImage::new(view.size, &pixels, metadata)
+}
+
+/// Work done by the traversals, counted like the `BVH_STATS` define of the shader
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TraversalStats {
+    pub rays: u64,
+    /// Nodes read, over both levels of the BVH
+    pub nodes: u64,
+    /// Primitives whose intersection was computed
+    pub primitives: u64,
+}
+
+thread_local! {
+    /// The private `traversal_stats` of the shader
+    static TRAVERSAL_STATS: Cell<TraversalStats> = Cell::default();
+}
+
+fn count_visits(nodes: u64, primitives: u64) {
+    TRAVERSAL_STATS.with(|stats| {
+        let mut value = stats.get();
+        value.nodes += nodes;
+        value.primitives += primitives;
+        stats.set(value);
+    });
+}
+
+/// Traces a primary ray through every pixel and counts the work of the traversals
+pub fn traversal_stats(scene: &ReferenceScene, view: &ReferenceView) -> TraversalStats {
+    TRAVERSAL_STATS.with(Cell::take);
+    for y in 0..view.size.y {
+        for x in 0..view.size.x {
+            let mut rng = Rng::new(y * view.size.x + x);
+            let ray = get_ray(view, &mut rng, UVec2::new(x, y));
+            traverse_instances(scene, &ray, RAY_CAMERA, view.primary_layers, 0.0, F32_MAX);
+        }
+    }
+
+    let mut stats = TRAVERSAL_STATS.with(Cell::take);
+    stats.rays = view.size.x as u64 * view.size.y as u64;
+    stats
+}
+
+fn main(
+    scene: &ReferenceScene,
+    view: &ReferenceView,
+    settings: &ReferenceSettings,
+    screen_pos: UVec2,
+) -> Vec4 {
+    // Initialize RNG
+    let mut rng = Rng { seed: screen_pos.y };
+    rng.seed = screen_pos.x.wrapping_add(rng.randi());
+    rng.randi();
+
+    let mut pixel_color = Vec4::ZERO;
+    for _ in 0..settings.samples_per_pixel {
+        pixel_color += per_pixel(scene, view, settings, &mut rng, screen_pos);
+    }
+    pixel_color / settings.samples_per_pixel as f32
+}
+
+fn per_pixel(
+    scene: &ReferenceScene,
+    view: &ReferenceView,
+    settings: &ReferenceSettings,
+    rng: &mut Rng,
+    screen_pos: UVec2,
+) -> Vec4 {
+    let mut ray = get_ray(view, rng, screen_pos);
+
+    let mut light = Vec3::ZERO;
+    let mut contribution = Vec3::ONE;
+
+    let mut kind = RAY_CAMERA;
+    for bounces in 0..settings.max_bounces {
+        let layers = if bounces == 0 {
+            view.primary_layers
+        } else {
+            view.secondary_layers
+        };
+        let hit = trace_ray(scene, &ray, kind, layers);
+        if hit.instance_index == U32_MAX {
+            // Miss
+            break;
+        }
+
+        let material = &scene.materials[hit.material_index as usize];
+        let instance = &scene.instances[hit.instance_index as usize];
+
+        // Albedo, tinted by the vertex colors and the instance
+        let mut albedo = material.base_color.xyz() * hit.color.xyz() * instance.tint.xyz();
+        let albedo_idx = material.base_color_texture;
+        if albedo_idx != U32_MAX {
+            albedo *= scene.sample_texture(albedo_idx, hit.uv).xyz();
+        }
+
+        // Emissive
+        let mut emissive = material.emissive.xyz() * instance.emissive_multiplier;
+        let emissive_idx = material.emissive_texture;
+        if emissive_idx != U32_MAX {
+            emissive *= scene.sample_texture(emissive_idx, hit.uv).xyz();
+        }
+        light += emissive * contribution;
+
+        // Metallic and roughness, scaled by the blue and green channels of their texture
+        let mut metallic = material.metallic;
+        let mut perceptual_roughness = material.perceptual_roughness;
+        let metallic_roughness_idx = material.metallic_roughness_texture;
+        if metallic_roughness_idx != U32_MAX {
+            let metallic_roughness = scene.sample_texture(metallic_roughness_idx, hit.uv);
+            metallic *= metallic_roughness.z;
+            perceptual_roughness *= metallic_roughness.y;
+        }
+        perceptual_roughness = (perceptual_roughness + instance.roughness_offset).clamp(0.0, 1.0);
+
+        let wo = -ray.dir;
+        let (t, b) = branchless_onb(hit.normal);
+        let wo_onb = world_to_local_onb(wo, t, b, hit.normal);
+        let sample = sample_standard_bsdf(rng, albedo, metallic, perceptual_roughness, wo_onb);
+        let wi = local_to_world_onb(sample.wi, t, b, hit.normal);
+        contribution *= bsdf_weight(&sample);
+
+        ray = Ray::new(hit.position + hit.normal * 0.0001, wi);
+        kind = sample.kind;
+
+        // Russian Roulette
+        if bounces > 3 {
+            let p = contribution.max_element();
+            if rng.rand() > p {
+                break;
+            }
+            contribution *= 1.0 / p;
+        }
+    }
+    light.extend(1.0)
+}
+
+/// Estimates the fraction of the light arriving from `wo` that the material scatters,
+/// by running the sampling step of `per_pixel` without tracing the outgoing rays.
+/// `wo` is in the local shading frame.
+pub fn directional_albedo(material: &GpuStandardMaterial, wo: Vec3, samples: u32) -> Vec3 {
+    let mut rng = Rng::new(samples);
+    let albedo = material.base_color.xyz();
+
+    let mut sum = Vec3::ZERO;
+    for _ in 0..samples {
+        let sample = sample_standard_bsdf(
+            &mut rng,
+            albedo,
+            material.metallic,
+            material.perceptual_roughness,
+            wo,
+        );
+        sum += bsdf_weight(&sample);
+    }
+    sum / samples as f32
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Ray {
+    dir: Vec3,
+    inv_dir: Vec3,
+    orig: Vec3,
+}
+
+impl Ray {
+    fn new(orig: Vec3, dir: Vec3) -> Self {
+        Self {
+            dir,
+            inv_dir: 1.0 / dir,
+            orig,
+        }
+    }
+}
+
+struct Aabb {
+    min: Vec3,
+    max: Vec3,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Intersection {
+    uv: Vec2,
+    distance: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Hit {
+    intersection: Intersection,
+    instance_index: u32,
+    primitive_index: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct HitInfo {
+    position: Vec3,
+    normal: Vec3,
+    uv: Vec2,
+    color: Vec4,
+    instance_index: u32,
+    material_index: u32,
+}
+
+fn trace_ray(scene: &ReferenceScene, ray: &Ray, kind: u32, layers: u32) -> HitInfo {
+    let new_render_state = traverse_instances(scene, ray, kind, layers, 0.0, F32_MAX);
+    if new_render_state.instance_index != U32_MAX {
+        return closest_hit(scene, ray, &new_render_state);
+    }
+    miss()
+}
+
+fn load_vertex(scene: &ReferenceScene, index: u32) -> GpuVertexCompact {
+    let vertex = scene.vertices[index as usize];
+    if scene.geometry.quantized_vertices {
+        GpuVertexCompact::dequantize(vertex.quantize())
+    } else {
+        vertex
+    }
+}
+
+fn closest_hit(scene: &ReferenceScene, ray: &Ray, hit: &Hit) -> HitInfo {
+    let instance = &scene.instances[hit.instance_index as usize];
+    let primitive = &scene.primitives[hit.primitive_index as usize].vertices;
+
+    let vertex = |i: usize| load_vertex(scene, instance.mesh.vertex + primitive[i].index);
+    let (vertex0, vertex1, vertex2) = (vertex(0), vertex(1), vertex(2));
+
+    let uv0 = Vec2::new(vertex0.u, vertex0.v);
+    let uv1 = Vec2::new(vertex1.u, vertex1.v);
+    let uv2 = Vec2::new(vertex2.u, vertex2.v);
+
+    let uv = hit.intersection.uv;
+    let normal =
+        uv.x * vertex1.normal + uv.y * vertex2.normal + (1.0 - uv.x - uv.y) * vertex0.normal;
+
+    // White without vertex colors. The second UVs are only read by raytraced materials,
+    // which are not mirrored here.
+    let mesh = &instance.mesh;
+    let weights = [1.0 - uv.x - uv.y, uv.x, uv.y];
+    let mut color = Vec4::ONE;
+    if mesh.attributes.y & VERTEX_COLOR != 0 {
+        color = (0..3)
+            .map(|i| weights[i] * load_color(scene, mesh, primitive[i].index))
+            .sum();
+    }
+
+    HitInfo {
+        position: ray.orig + ray.dir * hit.intersection.distance,
+        normal: instance_direction_local_to_world(instance, normal),
+        uv: uv.x * uv1 + uv.y * uv2 + (1.0 - uv.x - uv.y) * uv0,
+        color,
+        instance_index: hit.instance_index,
+        material_index: instance.material,
+    }
+}
+
+fn load_attribute_word(scene: &ReferenceScene, mesh: &GpuMeshIndex, vertex: u32, word: u32) -> f32 {
+    let stride = attribute_stride(mesh.attributes.y);
+    f32::from_bits(scene.attributes[(mesh.attributes.x + vertex * stride + word) as usize])
+}
+
+fn load_color(scene: &ReferenceScene, mesh: &GpuMeshIndex, vertex: u32) -> Vec4 {
+    Vec4::from_array(std::array::from_fn(|i| {
+        load_attribute_word(scene, mesh, vertex, i as u32)
+    }))
+}
+
+fn miss() -> HitInfo {
+    HitInfo {
+        instance_index: U32_MAX,
+        material_index: U32_MAX,
+        ..default()
+    }
+}
+
+/// A direction sampled from a BSDF, in the local shading frame where the normal is +Z
+#[derive(Debug, Clone, Copy)]
+pub struct BsdfSample {
+    pub color: Vec3,
+    pub wi: Vec3,
+    pub pdf: f32,
+    /// Kind of the sampled ray, see `RayVisibility`
+    pub kind: u32,
+}
+
+pub fn sample_lambertian(rng: &mut Rng, albedo: Vec3, wo: Vec3) -> BsdfSample {
+    // Sample cosine-weighted hemisphere to compute _wi_ and _pdf_
+    let mut wi = sample_cosine_hemisphere(rng);
+    if wo.z < 0.0 {
+        wi.z *= -1.0;
+    }
+    let pdf = cosine_hemisphere_pdf(wi.z.abs());
+
+    BsdfSample {
+        color: albedo * INV_PI,
+        wi,
+        pdf,
+        kind: RAY_DIFFUSE,
+    }
+}
+
+/// Metals reflect with a GGX lobe, picked with the probability of metallic.
+/// The other materials only have the Lambertian lobe.
+pub fn sample_standard_bsdf(
+    rng: &mut Rng,
+    albedo: Vec3,
+    metallic: f32,
+    perceptual_roughness: f32,
+    wo: Vec3,
+) -> BsdfSample {
+    if metallic > 0.0 && rng.rand() < metallic {
+        let roughness = perceptual_roughness.clamp(MIN_PERCEPTUAL_ROUGHNESS, 1.0);
+        return sample_ggx(rng, albedo, roughness * roughness, wo);
+    }
+    sample_lambertian(rng, albedo, wo)
+}
+
+/// The light carried by the sampled direction, none if it could not be sampled
+pub fn bsdf_weight(sample: &BsdfSample) -> Vec3 {
+    if sample.pdf <= 0.0 {
+        return Vec3::ZERO;
+    }
+    sample.color * sample.wi.z.abs() / sample.pdf
+}
+
+/// Cook-Torrance reflection with the Schlick Fresnel of `f0`, sampling the visible normals
+pub fn sample_ggx(rng: &mut Rng, f0: Vec3, alpha: f32, wo: Vec3) -> BsdfSample {
+    // Sampled above the surface, then mirrored like sample_lambertian does
+    let flip = if wo.z < 0.0 { -1.0 } else { 1.0 };
+    let v = Vec3::new(wo.x, wo.y, wo.z * flip);
+    let none = |wi: Vec3| BsdfSample {
+        color: Vec3::ZERO,
+        wi,
+        pdf: 0.0,
+        kind: RAY_SPECULAR,
+    };
+    if v.z <= 0.0 {
+        return none(Vec3::new(0.0, 0.0, flip));
+    }
+    let h = sample_ggx_vndf(rng, v, alpha);
+    let v_dot_h = v.dot(h);
+    let mut wi = 2.0 * v_dot_h * h - v;
+    if wi.z <= 0.0 {
+        return none(Vec3::new(wi.x, wi.y, wi.z * flip));
+    }
+
+    let d = ggx_distribution(h.z, alpha);
+    let lambda_v = smith_lambda(v.z, alpha);
+    let lambda_l = smith_lambda(wi.z, alpha);
+    let g1 = 1.0 / (1.0 + lambda_v);
+    let g2 = 1.0 / (1.0 + lambda_v + lambda_l);
+    let fresnel = f0 + (1.0 - f0) * (1.0 - v_dot_h).max(0.0).powf(5.0);
+
+    let pdf = d * g1 / (4.0 * v.z);
+    let color = fresnel * d * g2 / (4.0 * v.z * wi.z);
+    wi.z *= flip;
+    BsdfSample {
+        color,
+        wi,
+        pdf,
+        kind: RAY_SPECULAR,
+    }
+}
+
+/// <https://jcgt.org/published/0007/04/01/>
+fn sample_ggx_vndf(rng: &mut Rng, v: Vec3, alpha: f32) -> Vec3 {
+    let vh = Vec3::new(alpha * v.x, alpha * v.y, v.z).normalize();
+    let len_sq = vh.x * vh.x + vh.y * vh.y;
+    let t1 = if len_sq > 0.0 {
+        Vec3::new(-vh.y, vh.x, 0.0) / len_sq.sqrt()
+    } else {
+        Vec3::X
+    };
+    let t2 = vh.cross(t1);
+
+    let r = rng.rand().sqrt();
+    let phi = 2.0 * PI * rng.rand();
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+    let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+    Vec3::new(alpha * nh.x, alpha * nh.y, nh.z.max(0.0)).normalize()
+}
+
+fn ggx_distribution(cos_theta: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let k = cos_theta * cos_theta * (a2 - 1.0) + 1.0;
+    a2 * INV_PI / (k * k)
+}
+
+/// Height correlated Smith masking: G1 = 1 / (1 + lambda(v)), G2 = 1 / (1 + lambda(v) + lambda(l))
+fn smith_lambda(cos_theta: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    0.5 * ((a2 + (1.0 - a2) * cos_theta * cos_theta).sqrt() / cos_theta - 1.0)
+}
+
+fn sample_cosine_hemisphere(rng: &mut Rng) -> Vec3 {
+    let d = sample_uniform_disk_concentric(rng);
+    let z = (1.0 - d.dot(d)).max(0.0).sqrt();
+    Vec3::new(d.x, d.y, z)
+}
+
+pub fn cosine_hemisphere_pdf(cos_theta: f32) -> f32 {
+    cos_theta * INV_PI
+}
+
+fn sample_uniform_disk_concentric(rng: &mut Rng) -> Vec2 {
+    // Map u to [-1,1]^2 and handle degeneracy at the origin
+    let u_offset = Vec2::new(2.0 * rng.rand() - 1.0, 2.0 * rng.rand() - 1.0);
+    if u_offset.x == 0.0 && u_offset.y == 0.0 {
+        return Vec2::ZERO;
+    }
+
+    // Apply concentric mapping to point
+    let (r, theta) = if u_offset.x.abs() > u_offset.y.abs() {
+        (u_offset.x, 0.25 * PI * (u_offset.y / u_offset.x))
+    } else {
+        (u_offset.y, 0.5 * PI - 0.25 * PI * (u_offset.x / u_offset.y))
+    };
+    Vec2::new(r * theta.cos(), r * theta.sin())
+}
+
+// https://graphics.pixar.com/library/OrthonormalB/paper.pdf
+fn branchless_onb(n: Vec3) -> (Vec3, Vec3) {
+    let sign = if n.z >= 0.0 { 1.0 } else { -1.0 };
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    (
+        Vec3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        Vec3::new(b, sign + n.y * n.y * a, -n.y),
+    )
+}
+
+fn world_to_local_onb(v: Vec3, t: Vec3, b: Vec3, n: Vec3) -> Vec3 {
+    Vec3::new(v.dot(t), v.dot(b), v.dot(n))
+}
+
+fn local_to_world_onb(v: Vec3, t: Vec3, b: Vec3, n: Vec3) -> Vec3 {
+    v.x * t + v.y * b + v.z * n
+}
+
+fn get_ray(view: &ReferenceView, rng: &mut Rng, screen_pos: UVec2) -> Ray {
+    let pixel_center = screen_pos.as_vec2() + Vec2::new(rng.rand() - 0.5, rng.rand() - 0.5);
+    let in_uv = pixel_center / view.size.as_vec2();
+    let d = in_uv * 2.0 - 1.0;
+
+    let origin = view.view * Vec4::new(0.0, 0.0, 0.0, 1.0);
+    let pixel_center = view.inverse_projection * Vec4::new(d.x, -d.y, 1.0, 1.0);
+    let direction = view.view * pixel_center.xyz().normalize().extend(0.0);
+
+    Ray::new(origin.xyz(), direction.xyz())
+}
+
+fn traverse_instances(
+    scene: &ReferenceScene,
+    ray: &Ray,
+    kind: u32,
+    layers: u32,
+    early_distance: f32,
+    max_distance: f32,
+) -> Hit {
+    let mut hit = Hit {
+        intersection: Intersection {
+            uv: Vec2::ZERO,
+            distance: max_distance,
+        },
+        instance_index: U32_MAX,
+        primitive_index: U32_MAX,
+    };
+
+    let nodes = &scene.instance_nodes;
+    let visit_nodes = |hit: &mut Hit, start, end| {
+        traverse_instance_nodes(scene, hit, ray, kind, layers, start, end, early_distance)
+    };
+    match scene.bvh.traversal {
+        BvhTraversal::Stackless => visit_nodes(&mut hit, 0, nodes.len() as u32),
+        BvhTraversal::Ordered => traverse_ordered(nodes, &mut hit, ray, visit_nodes),
+    };
+
+    hit
+}
+
+/// Follows the skip links through the nodes in `start..end`, returns true when the hit is
+/// closer than `early_distance`
+#[allow(clippy::too_many_arguments)]
+fn traverse_instance_nodes(
+    scene: &ReferenceScene,
+    hit: &mut Hit,
+    ray: &Ray,
+    kind: u32,
+    layers: u32,
+    start: u32,
+    end: u32,
+    early_distance: f32,
+) -> bool {
+    let mut index = start;
+    while index < end {
+        let node = &scene.instance_nodes[index as usize];
+        count_visits(1, 0);
+
+        if node.entry_index >= BVH_LEAF_FLAG {
+            let instance_index = node.entry_index - BVH_LEAF_FLAG;
+            let instance = &scene.instances[instance_index as usize];
+            let aabb = Aabb {
+                min: instance.min,
+                max: instance.max,
+            };
+
+            let visible = instance.visibility & kind != 0 && instance.layers & layers != 0;
+            if visible && intersects_aabb(ray, &aabb) < hit.intersection.distance {
+                let r = Ray::new(
+                    instance_position_world_to_local(instance, ray.orig),
+                    instance_direction_world_to_local(instance, ray.dir),
+                );
+
+                if traverse_mesh(scene, hit, &r, &instance.mesh, early_distance) {
+                    hit.instance_index = instance_index;
+                    if hit.intersection.distance < early_distance {
+                        return true;
+                    }
+                }
+            }
+
+            index = node.exit_index;
+        } else {
+            let aabb = Aabb {
+                min: node.min,
+                max: node.max,
+            };
+            index = if intersects_aabb(ray, &aabb) < hit.intersection.distance {
+                node.entry_index
+            } else {
+                node.exit_index
+            };
+        }
+    }
+
+    false
+}
+
+/// The `BVH_ORDERED` traversal of the shader, written once for both levels.
+/// `visit_nodes` follows the skip links through a range of `nodes`, and returns true when the
+/// hit is closer than the early distance.
+fn traverse_ordered(
+    nodes: &[GpuNode],
+    hit: &mut Hit,
+    ray: &Ray,
+    mut visit_nodes: impl FnMut(&mut Hit, u32, u32) -> bool,
+) -> bool {
+    // Subtrees left for later: the node holding their box, and their entry distance
+    let mut stack = Vec::with_capacity(BVH_SHORT_STACK_SIZE);
+    // Nodes of the subtree being visited
+    let mut start = 0;
+    let mut end = nodes.len() as u32;
+    while start < end {
+        let first = &nodes[start as usize];
+        if first.entry_index >= BVH_LEAF_FLAG {
+            if visit_nodes(hit, start, end) {
+                return true;
+            }
+        } else {
+            // Inner node: the boxes of both children, the second one after the subtree of the first
+            let second = &nodes[first.exit_index as usize];
+            count_visits(2, 0);
+            let first_distance = intersects_aabb(
+                ray,
+                &Aabb {
+                    min: first.min,
+                    max: first.max,
+                },
+            );
+            let second_distance = intersects_aabb(
+                ray,
+                &Aabb {
+                    min: second.min,
+                    max: second.max,
+                },
+            );
+            let mut near = (start, first.exit_index, first_distance);
+            let mut far = (first.exit_index, second.exit_index, second_distance);
+            if second_distance < first_distance {
+                std::mem::swap(&mut near, &mut far);
+            }
+
+            if near.2 < hit.intersection.distance {
+                if far.2 < hit.intersection.distance {
+                    if stack.len() < BVH_SHORT_STACK_SIZE {
+                        stack.push((far.0, far.2));
+                    } else if visit_nodes(hit, far.0, far.1) {
+                        // The stack is full, the far child is visited right away in build order
+                        return true;
+                    }
+                }
+                start = near.0 + 1;
+                end = near.1;
+                continue;
+            }
+        }
+
+        // Next subtree that is not behind the closest hit
+        start = end;
+        while let Some((index, distance)) = stack.pop() {
+            if distance < hit.intersection.distance {
+                let node = &nodes[index as usize];
+                start = node.entry_index;
+                end = node.exit_index;
+                break;
+            }
+        }
+    }
+
+    false
+}
+
+fn traverse_mesh(
+    scene: &ReferenceScene,
+    hit: &mut Hit,
+    ray: &Ray,
+    mesh: &GpuMeshIndex,
+    early_distance: f32,
+) -> bool {
+    if let Some(width) = scene.bvh.layout.width() {
+        return traverse_mesh_wide(scene, hit, ray, mesh, early_distance, width as u32);
+    }
+
+    match scene.bvh.traversal {
+        BvhTraversal::Stackless => {
+            traverse_mesh_nodes(scene, hit, ray, mesh, 0, mesh.node.y, early_distance)
+        }
+        BvhTraversal::Ordered => {
+            let nodes = &scene.primitive_nodes[mesh.node.x as usize..][..mesh.node.y as usize];
+            let mut intersected = false;
+            traverse_ordered(nodes, hit, ray, |hit, start, end| {
+                if traverse_mesh_nodes(scene, hit, ray, mesh, start, end, early_distance) {
+                    intersected = true;
+                    hit.intersection.distance < early_distance
+                } else {
+                    false
+                }
+            });
+            intersected
+        }
+    }
+}
+
+/// Follows the skip links through the nodes in `start..end` of the mesh
+fn traverse_mesh_nodes(
+    scene: &ReferenceScene,
+    hit: &mut Hit,
+    ray: &Ray,
+    mesh: &GpuMeshIndex,
+    start: u32,
+    end: u32,
+    early_distance: f32,
+) -> bool {
+    let mut intersected = false;
+    let mut index = start;
+    while index < end {
+        let node_index = mesh.node.x + index;
+        let node = &scene.primitive_nodes[node_index as usize];
+        count_visits(1, 0);
+        if node.entry_index >= BVH_LEAF_FLAG {
+            let primitive_index = mesh.primitive + node.entry_index - BVH_LEAF_FLAG;
+            let vertices = &scene.primitives[primitive_index as usize].vertices;
+
+            let aabb = Aabb {
+                min: vertices[0]
+                    .position
+                    .min(vertices[1].position.min(vertices[2].position)),
+                max: vertices[0]
+                    .position
+                    .max(vertices[1].position.max(vertices[2].position)),
+            };
+
+            if intersects_aabb(ray, &aabb) < hit.intersection.distance {
+                let intersection = intersects_triangle(ray, vertices);
+                count_visits(0, 1);
+                if intersection.distance < hit.intersection.distance {
+                    hit.intersection = intersection;
+                    hit.primitive_index = primitive_index;
+                    intersected = true;
+
+                    if intersection.distance < early_distance {
+                        return intersected;
+                    }
+                }
+            }
+
+            index = node.exit_index;
+        } else {
+            let aabb = Aabb {
+                min: node.min,
+                max: node.max,
+            };
+            index = if intersects_aabb(ray, &aabb) < hit.intersection.distance {
+                node.entry_index
+            } else {
+                node.exit_index
+            };
+        }
+    }
+
+    intersected
+}
+
+/// The `traverse_mesh` of the shader when `BVH_WIDTH` is defined
+fn traverse_mesh_wide(
+    scene: &ReferenceScene,
+    hit: &mut Hit,
+    ray: &Ray,
+    mesh: &GpuMeshIndex,
+    early_distance: f32,
+    width: u32,
+) -> bool {
+    let mut intersected = false;
+    let mut stack = Vec::with_capacity(BVH_STACK_SIZE);
+    stack.push(0);
+    while let Some(offset) = stack.pop() {
+        let base = mesh.node.x + offset;
+        count_visits(1, 0);
+        let word = |word| wide_node_word(scene, base, word);
+        let origin = Vec3::new(
+            f32::from_bits(word(0)),
+            f32::from_bits(word(1)),
+            f32::from_bits(word(2)),
+        );
+        let scale = Vec3::new(
+            f32::from_bits((word(3) & 0xFF) << 23),
+            f32::from_bits(((word(3) >> 8) & 0xFF) << 23),
+            f32::from_bits(((word(3) >> 16) & 0xFF) << 23),
+        );
+
+        for child in 0..width {
+            let entry = word(4 + child);
+            if entry == BVH_EMPTY_CHILD {
+                continue;
+            }
+
+            let plane = |plane| wide_child_plane(scene, base, width, plane, child);
+            let aabb = Aabb {
+                min: origin + scale * Vec3::new(plane(0), plane(1), plane(2)),
+                max: origin + scale * Vec3::new(plane(3), plane(4), plane(5)),
+            };
+            if intersects_aabb(ray, &aabb) >= hit.intersection.distance {
+                continue;
+            }
+
+            if entry >= BVH_LEAF_FLAG {
+                let primitive_index = mesh.primitive + entry - BVH_LEAF_FLAG;
+                let vertices = &scene.primitives[primitive_index as usize].vertices;
+                let intersection = intersects_triangle(ray, vertices);
+                count_visits(0, 1);
+                if intersection.distance < hit.intersection.distance {
+                    hit.intersection = intersection;
+                    hit.primitive_index = primitive_index;
+                    intersected = true;
+
+                    if intersection.distance < early_distance {
+                        return intersected;
+                    }
+                }
+            } else if stack.len() < BVH_STACK_SIZE {
+                stack.push(entry);
+            }
+        }
+    }
+
+    intersected
+}
+
+/// `base` is in nodes, `word` counts the words of the wide node
+fn wide_node_word(scene: &ReferenceScene, base: u32, word: u32) -> u32 {
+    scene.primitive_nodes[(base + word / 8) as usize].to_words()[(word % 8) as usize]
+}
+
+fn wide_child_plane(scene: &ReferenceScene, base: u32, width: u32, plane: u32, child: u32) -> f32 {
+    let word = wide_node_word(scene, base, 4 + width + plane * (width / 4) + child / 4);
+    ((word >> (8 * (child % 4))) & 0xFF) as f32
+}
+
+fn instance_position_world_to_local(instance: &GpuInstance, p: Vec3) -> Vec3 {
+    let inverse_model = instance.inverse_transpose_model.transpose();
+    let position = inverse_model * p.extend(1.0);
+    position.xyz() / position.w
+}
+
+fn instance_direction_world_to_local(instance: &GpuInstance, p: Vec3) -> Vec3 {
+    let inverse_model = instance.inverse_transpose_model.transpose();
+    (inverse_model * p.extend(0.0)).xyz()
+}
+
+fn instance_direction_local_to_world(instance: &GpuInstance, p: Vec3) -> Vec3 {
+    (instance.transform * p.extend(0.0)).xyz()
+}
+
+fn intersects_aabb(ray: &Ray, aabb: &Aabb) -> f32 {
+    let t1 = (aabb.min - ray.orig) * ray.inv_dir;
+    let t2 = (aabb.max - ray.orig) * ray.inv_dir;
+
+    let mut t_min = t1.x.min(t2.x);
+    let mut t_max = t1.x.max(t2.x);
+
+    t_min = t_min.max(t1.y.min(t2.y));
+    t_max = t_max.min(t1.y.max(t2.y));
+
+    t_min = t_min.max(t1.z.min(t2.z));
+    t_max = t_max.min(t1.z.max(t2.z));
+
+    if t_max >= t_min && t_max >= 0.0 {
+        t_min
+    } else {
+        F32_MAX
+    }
+}
+
+fn intersects_triangle(ray: &Ray, triangle: &[GpuPrimitiveVertex; 3]) -> Intersection {
+    let mut hit = Intersection {
+        uv: Vec2::ZERO,
+        distance: F32_MAX,
+    };
+
+    let e1 = triangle[1].position - triangle[0].position;
+    let e2 = triangle[2].position - triangle[0].position;
+    let h = ray.dir.cross(e2);
+    let a = e1.dot(h);
+
+    if CULLING {
+        if a < 0.00001 {
+            return hit; // The ray is nearly parallel to the triangle
+        }
+    } else if a.abs() < 0.00001 {
+        return hit; // The ray is nearly parallel to the triangle
+    }
+
+    let f = 1.0 / a;
+    let s = ray.orig - triangle[0].position;
+    let u = f * s.dot(h);
+
+    if !(0.0..=1.0).contains(&u) {
+        return hit; // The intersection point is outside the triangle
+    }
+
+    let q = s.cross(e1);
+    let v = f * ray.dir.dot(q);
+
+    if v < 0.0 || u + v > 1.0 {
+        return hit; // The intersection point is outside the triangle
+    }
+
+    let t = f * e2.dot(q);
+
+    if t > 0.0001 {
+        hit.distance = t;
+        hit.uv = Vec2::new(u, v);
+    }
+    hit
+}
+
+/// Random number generator
+pub struct Rng {
+    seed: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        Self { seed }
+    }
+
+    // Returns a random integer
+    fn randi(&mut self) -> u32 {
+        self.seed = triple32(self.seed);
+        self.seed
+    }
+
+    // Returns a random real in [0,1).
+    pub fn rand(&mut self) -> f32 {
+        self.randi() as f32 / 0xffffffffu32 as f32
+    }
+}
+
+// bias: 0.020888578919738908 = minimal theoretic limit
+fn triple32(seed: u32) -> u32 {
+    let mut x = seed;
+    x ^= x >> 17;
+    x = x.wrapping_mul(0xed5ad4bb);
+    x ^= x >> 11;
+    x = x.wrapping_mul(0xac4c1b51);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x31848bab);
+    x ^= x >> 14;
+    x
+}
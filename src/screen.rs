@@ -1,4 +1,7 @@
-use crate::{ColorBuffer, FORMAT, SCREEN_SHADER_HANDLE};
+use crate::{
+    error::{RaytracerError, RaytracerErrors},
+    ColorBuffer, FORMAT, SCREEN_SHADER_HANDLE,
+};
 use bevy::{
     ecs::query::WorldQuery,
     prelude::*,
@@ -67,14 +70,26 @@ impl FromWorld for ScreenBindGroupLayout {
 #[derive(Resource, Deref, DerefMut)]
 pub struct ScreenBindGroup(BindGroup);
 
+#[allow(clippy::too_many_arguments)]
 fn prepare_screen_bind_group(
     mut commands: Commands,
     gpu_images: Res<RenderAssets<Image>>,
     color_buffer: Res<ColorBuffer>,
     render_device: Res<RenderDevice>,
     layout: Res<ScreenBindGroupLayout>,
+    pipeline_cache: Res<PipelineCache>,
+    pipeline: Res<ScreenPipeline>,
+    mut errors: ResMut<RaytracerErrors>,
 ) {
-    let view = gpu_images.get(&**color_buffer).unwrap();
+    let state = pipeline_cache.get_render_pipeline_state(**pipeline);
+    if let Some(error) = RaytracerError::from_pipeline(state) {
+        errors.send(error);
+    }
+    // Reported by `prepare_color_buffer_bind_group`
+    let Some(view) = gpu_images.get(&**color_buffer) else {
+        commands.remove_resource::<ScreenBindGroup>();
+        return;
+    };
     let bind_group = render_device.create_bind_group(
         None,
         &layout,
@@ -146,7 +161,9 @@ impl render_graph::ViewNode for ScreenNode {
         view_query: <Self::ViewQuery as WorldQuery>::Item<'_>,
         world: &World,
     ) -> Result<(), render_graph::NodeRunError> {
-        let screen_bind_group = world.resource::<ScreenBindGroup>();
+        let Some(screen_bind_group) = world.get_resource::<ScreenBindGroup>() else {
+            return Ok(());
+        };
         let pipeline_cache = world.resource::<PipelineCache>();
         let pipeline = world.resource::<ScreenPipeline>();
 
@@ -0,0 +1,498 @@
+// Linear BVH builder (Karras 2012): Morton codes of the centroids, radix sort,
+// hierarchy from the sorted codes, bounds from the leaves up, then the nodes in the layout
+// the raytracer traverses. The CPU mirror in bvh_builder.rs must build the same nodes.
+
+struct Aabb {
+    min: vec3<f32>,
+    max: vec3<f32>,
+}
+
+struct PrimitiveVertex {
+    position: vec3<f32>,
+    index: u32,
+}
+
+struct Primitive {
+    vertices: array<PrimitiveVertex, 3>,
+}
+
+struct Vertex {
+    position: vec3<f32>,
+    u: f32,
+    normal: vec3<f32>,
+    v: f32,
+}
+
+struct MeshIndex {
+    vertex: u32,
+    primitive: u32,
+    node: vec2<u32>,
+    chunk: u32,
+    attributes: vec2<u32>,
+}
+
+struct Instance {
+    min: vec3<f32>,
+    material: u32,
+    max: vec3<f32>,
+    // RenderLayers mask
+    layers: u32,
+    model: mat4x4<f32>,
+    inverse_transpose_model: mat4x4<f32>,
+    mesh: MeshIndex,
+    // RayVisibility bits, the kinds of rays that hit the instance
+    visibility: u32,
+    // InstanceMaterialOverride
+    emissive_multiplier: f32,
+    roughness_offset: f32,
+    tint: vec4<f32>,
+    // InstanceUserData
+    user_data: vec4<f32>,
+}
+
+struct Node {
+    min: vec3<f32>,
+    entry_index: u32,
+    max: vec3<f32>,
+    exit_index: u32,
+}
+
+struct Nodes {
+    count: u32,
+    data: array<Node>,
+}
+
+struct Params {
+    // Number of primitives
+    count: u32,
+    // Offset of the primitives in their buffer
+    source_offset: u32,
+    // Offset of the nodes in their buffer, the indices they hold are relative to it
+    node_offset: u32,
+    // First bit of the digit sorted by this pass
+    shift: u32,
+    // Offset of the vertices of the mesh in their buffer
+    vertex_offset: u32,
+}
+
+// A node of the hierarchy: the count - 1 inner nodes come first, then the leaves in sorted order
+struct TreeNode {
+    min: vec3<f32>,
+    parent: u32,
+    max: vec3<f32>,
+    left: u32,
+    right: u32,
+    // Range of the leaves below the node
+    first: u32,
+    last: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+// Two halves of count elements, the sort passes go back and forth between them
+@group(0) @binding(1) var<storage, read_write> keys: array<u32>;
+@group(0) @binding(2) var<storage, read_write> values: array<u32>;
+// Number of keys of each digit in each workgroup, digit major
+@group(0) @binding(3) var<storage, read_write> histograms: array<u32>;
+@group(0) @binding(4) var<storage, read_write> tree: array<TreeNode>;
+// Children of each inner node that reached it in the bottom-up pass
+@group(0) @binding(5) var<storage, read_write> counters: array<atomic<u32>>;
+// Bounds of the centroids, as ordered integers: min then max
+@group(0) @binding(6) var<storage, read_write> centroid_bounds: array<atomic<u32>, 6>;
+@group(0) @binding(7) var<storage, read_write> primitive_boxes: array<Aabb>;
+
+#ifdef LBVH_INSTANCES
+@group(1) @binding(0) var<storage, read> instance_buffer: array<Instance>;
+#else ifdef COMPACT_PRIMITIVES
+@group(1) @binding(0) var<storage, read> primitive_buffer: array<u32>;
+#else
+@group(1) @binding(0) var<storage, read> primitive_buffer: array<Primitive>;
+#endif
+@group(1) @binding(1) var<storage, read_write> node_buffer: Nodes;
+// Only read by compact primitives, in the encoding of the raytracer
+#ifdef QUANTIZED_VERTICES
+@group(1) @binding(2) var<storage, read> vertex_buffer: array<u32>;
+#else
+@group(1) @binding(2) var<storage, read> vertex_buffer: array<Vertex>;
+#endif
+
+const WORKGROUP_SIZE: u32 = 256u;
+const RADIX: u32 = 16u;
+const RADIX_BITS: u32 = 4u;
+const NONE: u32 = 0xFFFFFFFFu;
+const BVH_LEAF_FLAG: u32 = 0x80000000u;
+// Cells of the Morton grid along each axis
+const MORTON_CELLS: f32 = 1024.0;
+
+var<workgroup> group_bounds: array<atomic<u32>, 6>;
+var<workgroup> digit_counts: array<atomic<u32>, 16>;
+var<workgroup> partial_sums: array<u32, 256>;
+var<workgroup> group_digits: array<u32, 256>;
+
+// Maps floats to integers of the same order
+fn ordered(value: f32) -> u32 {
+    let bits = bitcast<u32>(value);
+    return select(bits | 0x80000000u, ~bits, (bits & 0x80000000u) != 0u);
+}
+
+fn unordered(value: u32) -> f32 {
+    return bitcast<f32>(select(~value, value & 0x7FFFFFFFu, (value & 0x80000000u) != 0u));
+}
+
+fn group_count() -> u32 {
+    return (params.count + WORKGROUP_SIZE - 1u) / WORKGROUP_SIZE;
+}
+
+#ifdef LBVH_INSTANCES
+fn source_aabb(index: u32) -> Aabb {
+    let instance = instance_buffer[index];
+    return Aabb(instance.min, instance.max);
+}
+#else ifdef COMPACT_PRIMITIVES
+fn load_position(index: u32) -> vec3<f32> {
+#ifdef QUANTIZED_VERTICES
+    let base = 5u * (params.vertex_offset + index);
+    return bitcast<vec3<f32>>(vec3<u32>(
+        vertex_buffer[base],
+        vertex_buffer[base + 1u],
+        vertex_buffer[base + 2u],
+    ));
+#else
+    return vertex_buffer[params.vertex_offset + index].position;
+#endif
+}
+
+fn source_aabb(index: u32) -> Aabb {
+    let base = 3u * (params.source_offset + index);
+    let a = load_position(primitive_buffer[base]);
+    let b = load_position(primitive_buffer[base + 1u]);
+    let c = load_position(primitive_buffer[base + 2u]);
+    return Aabb(min(min(a, b), c), max(max(a, b), c));
+}
+#else
+fn source_aabb(index: u32) -> Aabb {
+    let vertices = primitive_buffer[params.source_offset + index].vertices;
+    let a = vertices[0].position;
+    let b = vertices[1].position;
+    let c = vertices[2].position;
+    return Aabb(min(min(a, b), c), max(max(a, b), c));
+}
+#endif
+
+@compute @workgroup_size(1)
+fn reset() {
+    for (var axis = 0u; axis < 3u; axis += 1u) {
+        atomicStore(&centroid_bounds[axis], NONE);
+        atomicStore(&centroid_bounds[axis + 3u], 0u);
+    }
+}
+
+@compute @workgroup_size(256)
+fn leaf_bounds(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(local_invocation_index) local: u32,
+) {
+    if local < 3u {
+        atomicStore(&group_bounds[local], NONE);
+        atomicStore(&group_bounds[local + 3u], 0u);
+    }
+    workgroupBarrier();
+
+    if id.x < params.count {
+        let aabb = source_aabb(id.x);
+        primitive_boxes[id.x] = aabb;
+        let centroid = (aabb.min + aabb.max) * 0.5;
+        for (var axis = 0u; axis < 3u; axis += 1u) {
+            atomicMin(&group_bounds[axis], ordered(centroid[axis]));
+            atomicMax(&group_bounds[axis + 3u], ordered(centroid[axis]));
+        }
+    }
+    workgroupBarrier();
+
+    if local < 3u {
+        atomicMin(&centroid_bounds[local], atomicLoad(&group_bounds[local]));
+        atomicMax(&centroid_bounds[local + 3u], atomicLoad(&group_bounds[local + 3u]));
+    }
+}
+
+// Power of two step that covers the extent in MORTON_CELLS steps, inverted.
+// Scaling by a power of two is exact, so the CPU finds the same cells.
+fn morton_scale(extent: f32) -> f32 {
+    let bits = bitcast<u32>(extent);
+    let biased = (bits >> 23u) & 0xFFu;
+    if biased == 0u {
+        return 0.0;
+    }
+    var exponent = i32(biased) - 127 - 10;
+    if (bits & 0x7FFFFFu) != 0u {
+        exponent += 1;
+    }
+    return bitcast<f32>(u32(min(127 - exponent, 254)) << 23u);
+}
+
+// Spreads 10 bits two bits apart
+fn expand_bits(value: u32) -> u32 {
+    var bits = value & 0x3FFu;
+    bits = (bits | (bits << 16u)) & 0x030000FFu;
+    bits = (bits | (bits << 8u)) & 0x0300F00Fu;
+    bits = (bits | (bits << 4u)) & 0x030C30C3u;
+    bits = (bits | (bits << 2u)) & 0x09249249u;
+    return bits;
+}
+
+@compute @workgroup_size(256)
+fn morton_codes(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.count {
+        return;
+    }
+
+    let aabb = primitive_boxes[id.x];
+    let centroid = (aabb.min + aabb.max) * 0.5;
+    var code = 0u;
+    for (var axis = 0u; axis < 3u; axis += 1u) {
+        let lower = unordered(atomicLoad(&centroid_bounds[axis]));
+        let upper = unordered(atomicLoad(&centroid_bounds[axis + 3u]));
+        let cell = u32(min((centroid[axis] - lower) * morton_scale(upper - lower), MORTON_CELLS - 1.0));
+        code |= expand_bits(cell) << (2u - axis);
+    }
+    keys[id.x] = code;
+    values[id.x] = id.x;
+}
+
+// Half of the keys read by this sort pass
+fn sort_source() -> u32 {
+    return (params.shift / RADIX_BITS) % 2u * params.count;
+}
+
+fn sort_target() -> u32 {
+    return params.count - sort_source();
+}
+
+@compute @workgroup_size(256)
+fn sort_histogram(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(local_invocation_index) local: u32,
+    @builtin(workgroup_id) group: vec3<u32>,
+) {
+    if local < RADIX {
+        atomicStore(&digit_counts[local], 0u);
+    }
+    workgroupBarrier();
+
+    if id.x < params.count {
+        let digit = (keys[sort_source() + id.x] >> params.shift) & (RADIX - 1u);
+        atomicAdd(&digit_counts[digit], 1u);
+    }
+    workgroupBarrier();
+
+    if local < RADIX {
+        histograms[local * group_count() + group.x] = atomicLoad(&digit_counts[local]);
+    }
+}
+
+// Exclusive prefix sum of the histograms, in a single workgroup
+@compute @workgroup_size(256)
+fn sort_scan(@builtin(local_invocation_index) local: u32) {
+    let length = RADIX * group_count();
+    let chunk = (length + WORKGROUP_SIZE - 1u) / WORKGROUP_SIZE;
+    let start = min(local * chunk, length);
+    let end = min(start + chunk, length);
+
+    var sum = 0u;
+    for (var index = start; index < end; index += 1u) {
+        sum += histograms[index];
+    }
+    partial_sums[local] = sum;
+    workgroupBarrier();
+
+    for (var offset = 1u; offset < WORKGROUP_SIZE; offset *= 2u) {
+        var value = partial_sums[local];
+        if local >= offset {
+            value += partial_sums[local - offset];
+        }
+        workgroupBarrier();
+        partial_sums[local] = value;
+        workgroupBarrier();
+    }
+
+    var running = partial_sums[local] - sum;
+    for (var index = start; index < end; index += 1u) {
+        let count = histograms[index];
+        histograms[index] = running;
+        running += count;
+    }
+}
+
+// Keys keep their order within a digit, so the passes sort by the whole code
+@compute @workgroup_size(256)
+fn sort_scatter(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(local_invocation_index) local: u32,
+    @builtin(workgroup_id) group: vec3<u32>,
+) {
+    // Invocations past the end take a digit no key has
+    var key = 0u;
+    var digit = RADIX;
+    if id.x < params.count {
+        key = keys[sort_source() + id.x];
+        digit = (key >> params.shift) & (RADIX - 1u);
+    }
+    group_digits[local] = digit;
+    workgroupBarrier();
+
+    if id.x < params.count {
+        var rank = 0u;
+        for (var index = 0u; index < local; index += 1u) {
+            if group_digits[index] == digit {
+                rank += 1u;
+            }
+        }
+        let target_index = sort_target() + histograms[digit * group_count() + group.x] + rank;
+        keys[target_index] = key;
+        values[target_index] = values[sort_source() + id.x];
+    }
+}
+
+// Length of the common prefix of two sorted keys, their index breaks ties
+fn common_prefix(i: i32, j: i32) -> i32 {
+    if j < 0 || j >= i32(params.count) {
+        return -1;
+    }
+    let a = keys[i];
+    let b = keys[j];
+    if a == b {
+        return 32 + i32(countLeadingZeros(u32(i) ^ u32(j)));
+    }
+    return i32(countLeadingZeros(a ^ b));
+}
+
+fn leaf_node(leaf: i32) -> u32 {
+    return params.count - 1u + u32(leaf);
+}
+
+@compute @workgroup_size(256)
+fn hierarchy(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.count {
+        return;
+    }
+    if id.x == 0u {
+        tree[0].parent = NONE;
+    }
+
+    let leaf = leaf_node(i32(id.x));
+    let aabb = primitive_boxes[values[id.x]];
+    tree[leaf].min = aabb.min;
+    tree[leaf].max = aabb.max;
+    tree[leaf].first = id.x;
+    tree[leaf].last = id.x;
+
+    if id.x + 1u >= params.count {
+        return;
+    }
+
+    // Inner node: the range of keys sharing its prefix extends in one direction
+    let i = i32(id.x);
+    atomicStore(&counters[id.x], 0u);
+    let direction = select(-1, 1, common_prefix(i, i + 1) > common_prefix(i, i - 1));
+    let min_prefix = common_prefix(i, i - direction);
+    var max_length = 2;
+    while common_prefix(i, i + max_length * direction) > min_prefix {
+        max_length *= 2;
+    }
+    var length = 0;
+    for (var step = max_length / 2; step >= 1; step /= 2) {
+        if common_prefix(i, i + (length + step) * direction) > min_prefix {
+            length += step;
+        }
+    }
+    let j = i + length * direction;
+
+    // Split where the prefix of the range ends
+    let node_prefix = common_prefix(i, j);
+    var split = 0;
+    var divisor = 2;
+    var step = (length + divisor - 1) / divisor;
+    loop {
+        if common_prefix(i, i + (split + step) * direction) > node_prefix {
+            split += step;
+        }
+        if step <= 1 {
+            break;
+        }
+        divisor *= 2;
+        step = (length + divisor - 1) / divisor;
+    }
+    let gamma = i + split * direction + min(direction, 0);
+
+    let first = min(i, j);
+    let last = max(i, j);
+    let left = select(u32(gamma), leaf_node(gamma), first == gamma);
+    let right = select(u32(gamma + 1), leaf_node(gamma + 1), last == gamma + 1);
+    tree[id.x].left = left;
+    tree[id.x].right = right;
+    tree[id.x].first = u32(first);
+    tree[id.x].last = u32(last);
+    tree[left].parent = id.x;
+    tree[right].parent = id.x;
+}
+
+// Walks up from each leaf, the second child to reach a node has both boxes
+@compute @workgroup_size(256)
+fn node_bounds(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.count {
+        return;
+    }
+
+    var node = leaf_node(i32(id.x));
+    loop {
+        let parent = tree[node].parent;
+        if parent == NONE || atomicAdd(&counters[parent], 1u) == 0u {
+            break;
+        }
+        let left = tree[parent].left;
+        let right = tree[parent].right;
+        tree[parent].min = min(tree[left].min, tree[right].min);
+        tree[parent].max = max(tree[left].max, tree[right].max);
+        node = parent;
+    }
+}
+
+fn write_node(index: u32, min: vec3<f32>, entry_index: u32, max: vec3<f32>, exit_index: u32) {
+    node_buffer.data[params.node_offset + index] = Node(min, entry_index, max, exit_index);
+}
+
+// A subtree of k leaves takes 3k - 2 nodes: every leaf, and the nodes holding the bounds of
+// the children of each inner node. So a node starts after 3 nodes per leaf on its left, and
+// one bounds node per ancestor it is the left child of.
+@compute @workgroup_size(256)
+fn flatten(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= 2u * params.count - 1u {
+        return;
+    }
+
+    var offset = 3u * tree[id.x].first;
+    var node = id.x;
+    loop {
+        let parent = tree[node].parent;
+        if parent == NONE {
+            break;
+        }
+        if tree[parent].left == node {
+            offset += 1u;
+        }
+        node = parent;
+    }
+
+    let current = tree[id.x];
+    if id.x + 1u < params.count {
+        let left = tree[current.left];
+        let right = tree[current.right];
+        let left_leaves = left.last - left.first + 1u;
+        let leaves = current.last - current.first + 1u;
+        let right_offset = offset + 3u * left_leaves - 1u;
+        write_node(offset, left.min, offset + 1u, left.max, right_offset);
+        write_node(right_offset, right.min, right_offset + 1u, right.max, offset + 3u * leaves - 2u);
+    } else {
+        let leaf = id.x - (params.count - 1u);
+        write_node(offset, current.min, values[leaf] | BVH_LEAF_FLAG, current.max, offset + 1u);
+    }
+}
@@ -36,6 +36,8 @@ struct HitInfo {
     position: vec3<f32>,
     normal: vec3<f32>,
     uv: vec2<f32>,
+    color: vec4<f32>,
+    uv_1: vec2<f32>,
     instance_index: u32,
     material_index: u32,
 }
@@ -60,15 +62,27 @@ struct MeshIndex {
     vertex: u32,
     primitive: u32,
     node: vec2<u32>,    // x: offset, y: size
+    chunk: u32,
+    attributes: vec2<u32>,  // x: offset, y: flags
 }
 
 struct Instance {
     min: vec3<f32>,
     material: u32,
     max: vec3<f32>,
+    // RenderLayers mask
+    layers: u32,
     model: mat4x4<f32>,
     inverse_transpose_model: mat4x4<f32>,
     mesh: MeshIndex,
+    // RayVisibility bits, the kinds of rays that hit the instance
+    visibility: u32,
+    // InstanceMaterialOverride
+    emissive_multiplier: f32,
+    roughness_offset: f32,
+    tint: vec4<f32>,
+    // InstanceUserData
+    user_data: vec4<f32>,
 }
 
 struct Node {
@@ -83,6 +97,16 @@ struct Nodes {
     data: array<Node>,
 }
 
+// The same buffer as Nodes, seen as raw words.
+// A wide node is made of 2 (BVH4) or 3 (BVH8) Nodes:
+// - origin of the node (vec3<f32>), biased exponents of the quantization steps (one byte per axis)
+// - one word per child: BVH_LEAF_FLAG | primitive, the offset of a wide node, or BVH_EMPTY_CHILD
+// - the quantized min then max planes of the children, one byte per child and plane
+struct WideNodes {
+    count: u32,
+    data: array<vec4<u32>>,
+}
+
 struct Material {
     base_color: vec4<f32>,
     base_color_texture: u32,
@@ -97,22 +121,105 @@ struct Material {
 
 @group(0) @binding(0) var color_buffer: texture_storage_2d<rgba8unorm, write>;
 
-@group(1) @binding(0) var<storage, read> vertex_buffer: array<Vertex>;
-@group(1) @binding(1) var<storage, read> primitive_buffer: array<Primitive>;
-@group(1) @binding(2) var<storage, read> primitive_node_buffer: Nodes;
+#ifdef QUANTIZED_VERTICES
+// Position, octahedral normal and UV of each vertex in 5 words
+alias VertexData = u32;
+#else
+alias VertexData = Vertex;
+#endif
+#ifdef COMPACT_PRIMITIVES
+// The indices of the 3 vertices of each primitive
+alias PrimitiveData = u32;
+#else
+alias PrimitiveData = Primitive;
+#endif
+#ifdef BVH_WIDTH
+alias MeshNodes = WideNodes;
+alias MeshNode = vec4<u32>;
+#else
+alias MeshNodes = Nodes;
+alias MeshNode = Node;
+#endif
+
+// The geometry is split in GEOMETRY_CHUNKS chunks, read through chunk_vertex and co.
+@group(1) @binding(0) var<storage, read> vertex_buffer: array<VertexData>;
+@group(1) @binding(1) var<storage, read> primitive_buffer: array<PrimitiveData>;
+@group(1) @binding(2) var<storage, read> primitive_node_buffer: MeshNodes;
 @group(1) @binding(3) var<storage, read> material_buffer: array<Material>;
 @group(1) @binding(4) var<storage, read> instance_buffer: array<Instance>;
 @group(1) @binding(5) var<storage, read> instance_node_buffer: Nodes;
+@group(1) @binding(6) var<storage, read> custom_material_buffer: array<u32>;
+@group(1) @binding(7) var<storage, read> attribute_buffer: array<u32>;
+#if GEOMETRY_CHUNKS > 1
+@group(1) @binding(8) var<storage, read> vertex_buffer_1: array<VertexData>;
+@group(1) @binding(9) var<storage, read> primitive_buffer_1: array<PrimitiveData>;
+@group(1) @binding(10) var<storage, read> primitive_node_buffer_1: MeshNodes;
+@group(1) @binding(11) var<storage, read> attribute_buffer_1: array<u32>;
+#endif
+#if GEOMETRY_CHUNKS > 2
+@group(1) @binding(12) var<storage, read> vertex_buffer_2: array<VertexData>;
+@group(1) @binding(13) var<storage, read> primitive_buffer_2: array<PrimitiveData>;
+@group(1) @binding(14) var<storage, read> primitive_node_buffer_2: MeshNodes;
+@group(1) @binding(15) var<storage, read> attribute_buffer_2: array<u32>;
+#endif
+#if GEOMETRY_CHUNKS > 3
+@group(1) @binding(16) var<storage, read> vertex_buffer_3: array<VertexData>;
+@group(1) @binding(17) var<storage, read> primitive_buffer_3: array<PrimitiveData>;
+@group(1) @binding(18) var<storage, read> primitive_node_buffer_3: MeshNodes;
+@group(1) @binding(19) var<storage, read> attribute_buffer_3: array<u32>;
+#endif
 
 @group(2) @binding(0) var textures: binding_array<texture_2d<f32>>;
 @group(2) @binding(1) var samplers: binding_array<sampler>;
 
+// The RenderLayers masks of the camera, for its primary rays and for the other ones
+struct RaytracerView {
+    primary_layers: u32,
+    secondary_layers: u32,
+}
+
 @group(3) @binding(0) var<uniform> view: View;
+@group(3) @binding(1) var<uniform> raytracer_view: RaytracerView;
 
 const F32_MAX: f32 = 3.4028235e38;
 const U32_MAX: u32 = 0xFFFFFFFFu;
 const INV_PI: f32 = 0.318309886184;
 const BVH_LEAF_FLAG: u32 = 0x80000000u;
+// Kinds of rays, see RayVisibility
+const RAY_CAMERA: u32 = 1u;
+const RAY_DIFFUSE: u32 = 2u;
+const RAY_SPECULAR: u32 = 4u;
+const RAY_SHADOW: u32 = 8u;
+const RAY_TRANSMISSION: u32 = 16u;
+// Same lower bound as the PBR shaders of Bevy, smoother metals alias
+const MIN_PERCEPTUAL_ROUGHNESS: f32 = 0.089;
+const MATERIAL_KIND_SHIFT: u32 = 24u;
+const MATERIAL_OFFSET_MASK: u32 = 0xFFFFFFu;
+const VERTEX_COLOR: u32 = 1u;
+const VERTEX_UV_1: u32 = 2u;
+#ifdef BVH_WIDTH
+const BVH_WIDTH: u32 = #{BVH_WIDTH}u;
+const BVH_EMPTY_CHILD: u32 = 0xFFFFFFFFu;
+// Deeper subtrees are skipped
+const BVH_STACK_SIZE: u32 = 64u;
+#endif
+#ifdef BVH_ORDERED
+// Beyond this, subtrees are visited in build order with the skip links
+const BVH_SHORT_STACK_SIZE: u32 = 8u;
+#endif
+#ifdef BVH_STATS
+// Number of visits for which the heatmap saturates
+const BVH_STATS_NODES: f32 = 512.0;
+const BVH_STATS_PRIMITIVES: f32 = 64.0;
+#endif
+
+struct TraversalStats {
+    nodes: u32,
+    primitives: u32,
+}
+
+// Work done by the traversals of the current pixel
+var<private> traversal_stats: TraversalStats;
 
 // TODO: this should be by instance
 #define CULLING
@@ -139,6 +246,16 @@ fn main(@builtin(global_invocation_id) GlobalInvocationID: vec3<u32>) {
     }
     pixel_color /= f32(samples_per_pixel);
 
+#ifdef BVH_STATS
+    // Nodes read in red, primitives tested in green
+    pixel_color = vec4<f32>(
+        f32(traversal_stats.nodes) / BVH_STATS_NODES,
+        f32(traversal_stats.primitives) / BVH_STATS_PRIMITIVES,
+        0.0,
+        1.0
+    );
+#endif
+
     textureStore(color_buffer, screen_pos, pixel_color);
 }
 
@@ -150,8 +267,10 @@ fn per_pixel(screen_pos: vec2<i32>, screen_size: vec2<i32>) -> vec4<f32> {
 
     // TODO: this
     let MAX_BOUNCES = 5;
+    var kind = RAY_CAMERA;
     for (var bounces = 0; bounces < MAX_BOUNCES; bounces++) {
-        let hit = trace_ray(ray);
+        let layers = select(raytracer_view.secondary_layers, raytracer_view.primary_layers, bounces == 0);
+        let hit = trace_ray(ray, kind, layers);
         if hit.instance_index == U32_MAX {
             // Miss
             let unit_dir = normalize(ray.dir);
@@ -162,35 +281,15 @@ fn per_pixel(screen_pos: vec2<i32>, screen_size: vec2<i32>) -> vec4<f32> {
             break;
         }
 
-        let material = material_buffer[hit.material_index];
-
-        // Albedo
-        var albedo = material.base_color.xyz;
-        let albedo_idx = material.base_color_texture;
-        if albedo_idx != U32_MAX {
-            albedo *= textureSampleLevel(textures[albedo_idx], samplers[albedo_idx], hit.uv, 0.0).xyz;
-        }
-
-        // Emissive
-        var emissive = material.emissive.xyz;
-        let emissive_idx = material.emissive_texture;
-        if emissive_idx != U32_MAX {
-            emissive *= textureSampleLevel(textures[emissive_idx], samplers[emissive_idx], hit.uv, 0.0).xyz;
-        }
-        light += emissive * contribution;
-
         let wo = -ray.dir;
-        var t: vec3<f32>;
-        var b: vec3<f32>;
-        branchless_onb(hit.normal, &t, &b);
-        let wo_onb = world_to_local_onb(wo, t, b, hit.normal);
-        let sample = sample_lambertian(albedo, wo_onb);
-        let wi = local_to_world_onb(sample.wi, t, b, hit.normal);
-        contribution *= sample.color * abs(sample.wi.z) / sample.pdf;
+        let sample = sample_material(hit, wo);
+        light += sample.emissive * contribution;
+        contribution *= sample.weight;
 
         ray.orig = hit.position + hit.normal * 0.0001;
-        ray.dir = wi;
+        ray.dir = sample.wi;
         ray.inv_dir = 1.0 / ray.dir;
+        kind = sample.kind;
 
         // Russian Roulette
         if bounces > 3 {
@@ -204,24 +303,211 @@ fn per_pixel(screen_pos: vec2<i32>, screen_size: vec2<i32>) -> vec4<f32> {
     return vec4<f32>(light, 1.0);
 }
 
-fn trace_ray(ray: Ray) -> HitInfo {
-    let new_render_state = traverse_instances(ray, 0.0, F32_MAX);
+// Light emitted towards wo, and the direction of the next ray with the weight of its light.
+// The kind of the next ray is one of the RAY_ constants.
+struct MaterialSample {
+    emissive: vec3<f32>,
+    wi: vec3<f32>,
+    weight: vec3<f32>,
+    kind: u32,
+}
+
+fn sample_material(hit: HitInfo, wo: vec3<f32>) -> MaterialSample {
+#ifdef CUSTOM_MATERIALS
+    let kind = hit.material_index >> MATERIAL_KIND_SHIFT;
+    if kind != 0u {
+        return sample_custom_material(kind, hit.material_index & MATERIAL_OFFSET_MASK, hit, wo);
+    }
+#endif
+    return sample_standard_material(hit.material_index, hit, wo);
+}
+
+fn sample_standard_material(material_index: u32, hit: HitInfo, wo: vec3<f32>) -> MaterialSample {
+    let material = material_buffer[material_index];
+    let instance = instance_buffer[hit.instance_index];
+
+    // Albedo, tinted by the vertex colors and the instance
+    var albedo = material.base_color.xyz * hit.color.xyz * instance.tint.xyz;
+    let albedo_idx = material.base_color_texture;
+    if albedo_idx != U32_MAX {
+        albedo *= textureSampleLevel(textures[albedo_idx], samplers[albedo_idx], hit.uv, 0.0).xyz;
+    }
+
+    // Emissive
+    var emissive = material.emissive.xyz * instance.emissive_multiplier;
+    let emissive_idx = material.emissive_texture;
+    if emissive_idx != U32_MAX {
+        emissive *= textureSampleLevel(textures[emissive_idx], samplers[emissive_idx], hit.uv, 0.0).xyz;
+    }
+
+    // Metallic and roughness, scaled by the blue and green channels of their texture
+    var metallic = material.metallic;
+    var perceptual_roughness = material.perceptual_roughness;
+    let metallic_roughness_idx = material.metallic_roughness_texture;
+    if metallic_roughness_idx != U32_MAX {
+        let metallic_roughness = textureSampleLevel(textures[metallic_roughness_idx], samplers[metallic_roughness_idx], hit.uv, 0.0);
+        metallic *= metallic_roughness.b;
+        perceptual_roughness *= metallic_roughness.g;
+    }
+    perceptual_roughness = clamp(perceptual_roughness + instance.roughness_offset, 0.0, 1.0);
+
+    var t: vec3<f32>;
+    var b: vec3<f32>;
+    branchless_onb(hit.normal, &t, &b);
+    let wo_onb = world_to_local_onb(wo, t, b, hit.normal);
+    let sample = sample_standard_bsdf(albedo, metallic, perceptual_roughness, wo_onb);
+    let wi = local_to_world_onb(sample.wi, t, b, hit.normal);
+    return MaterialSample(emissive, wi, bsdf_weight(sample), sample.kind);
+}
+
+// Data of the instance for custom shading code, see InstanceUserData
+fn instance_user_data(hit: HitInfo) -> vec4<f32> {
+    return instance_buffer[hit.instance_index].user_data;
+}
+
+#ifdef CUSTOM_MATERIALS
+// A word of a raytraced material, its texture indices come first
+fn custom_material_word(material: u32, word: u32) -> u32 {
+    return custom_material_buffer[material + word];
+}
+
+// Samples the texture in a slot of a raytraced material, white if the slot is empty
+fn sample_custom_texture(material: u32, slot: u32, uv: vec2<f32>) -> vec4<f32> {
+    let index = custom_material_word(material, slot);
+    if index == U32_MAX {
+        return vec4<f32>(1.0);
+    }
+    return textureSampleLevel(textures[index], samplers[index], uv, 0.0);
+}
+#endif
+
+// Only the instances visible to the kind of ray, and on one of the layers, are hit
+fn trace_ray(ray: Ray, kind: u32, layers: u32) -> HitInfo {
+    let new_render_state = traverse_instances(ray, kind, layers, 0.0, F32_MAX);
     if new_render_state.instance_index != U32_MAX {
         return closest_hit(ray, new_render_state);
     }
     return miss(ray);
 }
 
+// The bindings of the chunks cannot be indexed dynamically
+fn chunk_vertex(chunk: u32, index: u32) -> VertexData {
+    switch chunk {
+#if GEOMETRY_CHUNKS > 1
+        case 1u: { return vertex_buffer_1[index]; }
+#endif
+#if GEOMETRY_CHUNKS > 2
+        case 2u: { return vertex_buffer_2[index]; }
+#endif
+#if GEOMETRY_CHUNKS > 3
+        case 3u: { return vertex_buffer_3[index]; }
+#endif
+        default: { return vertex_buffer[index]; }
+    }
+}
+
+fn chunk_primitive(chunk: u32, index: u32) -> PrimitiveData {
+    switch chunk {
+#if GEOMETRY_CHUNKS > 1
+        case 1u: { return primitive_buffer_1[index]; }
+#endif
+#if GEOMETRY_CHUNKS > 2
+        case 2u: { return primitive_buffer_2[index]; }
+#endif
+#if GEOMETRY_CHUNKS > 3
+        case 3u: { return primitive_buffer_3[index]; }
+#endif
+        default: { return primitive_buffer[index]; }
+    }
+}
+
+fn chunk_node(chunk: u32, index: u32) -> MeshNode {
+    switch chunk {
+#if GEOMETRY_CHUNKS > 1
+        case 1u: { return primitive_node_buffer_1.data[index]; }
+#endif
+#if GEOMETRY_CHUNKS > 2
+        case 2u: { return primitive_node_buffer_2.data[index]; }
+#endif
+#if GEOMETRY_CHUNKS > 3
+        case 3u: { return primitive_node_buffer_3.data[index]; }
+#endif
+        default: { return primitive_node_buffer.data[index]; }
+    }
+}
+
+fn chunk_attribute(chunk: u32, index: u32) -> u32 {
+    switch chunk {
+#if GEOMETRY_CHUNKS > 1
+        case 1u: { return attribute_buffer_1[index]; }
+#endif
+#if GEOMETRY_CHUNKS > 2
+        case 2u: { return attribute_buffer_2[index]; }
+#endif
+#if GEOMETRY_CHUNKS > 3
+        case 3u: { return attribute_buffer_3[index]; }
+#endif
+        default: { return attribute_buffer[index]; }
+    }
+}
+
+#ifdef QUANTIZED_VERTICES
+fn octahedral_decode(encoded: vec2<f32>) -> vec3<f32> {
+    var normal = vec3<f32>(encoded, 1.0 - abs(encoded.x) - abs(encoded.y));
+    let fold = max(-normal.z, 0.0);
+    normal.x += select(fold, -fold, normal.x >= 0.0);
+    normal.y += select(fold, -fold, normal.y >= 0.0);
+    return normalize(normal);
+}
+
+fn load_position(chunk: u32, index: u32) -> vec3<f32> {
+    let base = 5u * index;
+    return bitcast<vec3<f32>>(vec3<u32>(
+        chunk_vertex(chunk, base),
+        chunk_vertex(chunk, base + 1u),
+        chunk_vertex(chunk, base + 2u),
+    ));
+}
+
+fn load_vertex(chunk: u32, index: u32) -> Vertex {
+    let uv = unpack2x16float(chunk_vertex(chunk, 5u * index + 4u));
+    let normal = octahedral_decode(unpack2x16snorm(chunk_vertex(chunk, 5u * index + 3u)));
+    return Vertex(load_position(chunk, index), uv.x, normal, uv.y);
+}
+#else
+fn load_position(chunk: u32, index: u32) -> vec3<f32> {
+    return chunk_vertex(chunk, index).position;
+}
+
+fn load_vertex(chunk: u32, index: u32) -> Vertex {
+    return chunk_vertex(chunk, index);
+}
+#endif
+
+// The vertices of a primitive, their indices are relative to the mesh
+fn load_primitive(mesh: MeshIndex, primitive_index: u32) -> array<PrimitiveVertex, 3> {
+#ifdef COMPACT_PRIMITIVES
+    var vertices: array<PrimitiveVertex, 3>;
+    for (var i = 0u; i < 3u; i++) {
+        let index = chunk_primitive(mesh.chunk, 3u * primitive_index + i);
+        vertices[i] = PrimitiveVertex(load_position(mesh.chunk, mesh.vertex + index), index);
+    }
+    return vertices;
+#else
+    return chunk_primitive(mesh.chunk, primitive_index).vertices;
+#endif
+}
+
 fn closest_hit(ray: Ray, hit: Hit) -> HitInfo {
     var info: HitInfo;
     info.instance_index = hit.instance_index;
 
     let instance = instance_buffer[hit.instance_index];
-    let primitive = primitive_buffer[hit.primitive_index].vertices;
+    let primitive = load_primitive(instance.mesh, hit.primitive_index);
 
-    let vertex0 = vertex_buffer[instance.mesh.vertex + primitive[0].index];
-    let vertex1 = vertex_buffer[instance.mesh.vertex + primitive[1].index];
-    let vertex2 = vertex_buffer[instance.mesh.vertex + primitive[2].index];
+    let vertex0 = load_vertex(instance.mesh.chunk, instance.mesh.vertex + primitive[0].index);
+    let vertex1 = load_vertex(instance.mesh.chunk, instance.mesh.vertex + primitive[1].index);
+    let vertex2 = load_vertex(instance.mesh.chunk, instance.mesh.vertex + primitive[2].index);
 
     let uv0 = vec2<f32>(vertex0.u, vertex0.v);
     let uv1 = vec2<f32>(vertex1.u, vertex1.v);
@@ -232,12 +518,52 @@ fn closest_hit(ray: Ray, hit: Hit) -> HitInfo {
     let normal = uv.x * vertex1.normal + uv.y * vertex2.normal + (1.0 - uv.x - uv.y) * vertex0.normal;
     info.normal = instance_direction_local_to_world(instance, normal);
 
+    // Optional attributes, white and zero when missing
+    let mesh = instance.mesh;
+    let weights = vec3<f32>(1.0 - uv.x - uv.y, uv.x, uv.y);
+    info.color = vec4<f32>(1.0);
+    if (mesh.attributes.y & VERTEX_COLOR) != 0u {
+        info.color = weights.x * load_color(mesh, primitive[0].index)
+            + weights.y * load_color(mesh, primitive[1].index)
+            + weights.z * load_color(mesh, primitive[2].index);
+    }
+    info.uv_1 = vec2<f32>(0.0);
+    if (mesh.attributes.y & VERTEX_UV_1) != 0u {
+        info.uv_1 = weights.x * load_uv_1(mesh, primitive[0].index)
+            + weights.y * load_uv_1(mesh, primitive[1].index)
+            + weights.z * load_uv_1(mesh, primitive[2].index);
+    }
+
     info.position = ray.orig + ray.dir * hit.intersection.distance;
     info.material_index = instance.material;
 
     return info;
 }
 
+// Vertex colors then second UVs, for the vertices of meshes having them
+fn attribute_stride(flags: u32) -> u32 {
+    return select(0u, 4u, (flags & VERTEX_COLOR) != 0u) + select(0u, 2u, (flags & VERTEX_UV_1) != 0u);
+}
+
+fn load_attribute_word(mesh: MeshIndex, vertex: u32, word: u32) -> f32 {
+    let stride = attribute_stride(mesh.attributes.y);
+    return bitcast<f32>(chunk_attribute(mesh.chunk, mesh.attributes.x + vertex * stride + word));
+}
+
+fn load_color(mesh: MeshIndex, vertex: u32) -> vec4<f32> {
+    return vec4<f32>(
+        load_attribute_word(mesh, vertex, 0u),
+        load_attribute_word(mesh, vertex, 1u),
+        load_attribute_word(mesh, vertex, 2u),
+        load_attribute_word(mesh, vertex, 3u),
+    );
+}
+
+fn load_uv_1(mesh: MeshIndex, vertex: u32) -> vec2<f32> {
+    let word = select(0u, 4u, (mesh.attributes.y & VERTEX_COLOR) != 0u);
+    return vec2<f32>(load_attribute_word(mesh, vertex, word), load_attribute_word(mesh, vertex, word + 1u));
+}
+
 fn miss(ray: Ray) -> HitInfo {
     var info: HitInfo;
     info.instance_index = U32_MAX;
@@ -249,6 +575,8 @@ struct BSDFSample {
     color: vec3<f32>,
     wi: vec3<f32>,
     pdf: f32,
+    // Kind of the sampled ray
+    kind: u32,
 }
 
 fn sample_lambertian(albedo: vec3<f32>, wo: vec3<f32>) -> BSDFSample {
@@ -259,7 +587,84 @@ fn sample_lambertian(albedo: vec3<f32>, wo: vec3<f32>) -> BSDFSample {
     }
     let pdf = cosine_hemisphere_pdf(abs(wi.z));
 
-    return BSDFSample(albedo * INV_PI, wi, pdf);
+    return BSDFSample(albedo * INV_PI, wi, pdf, RAY_DIFFUSE);
+}
+
+// Metals reflect with a GGX lobe, picked with the probability of metallic.
+// The other materials only have the Lambertian lobe.
+fn sample_standard_bsdf(albedo: vec3<f32>, metallic: f32, perceptual_roughness: f32, wo: vec3<f32>) -> BSDFSample {
+    if metallic > 0.0 && rand() < metallic {
+        let roughness = clamp(perceptual_roughness, MIN_PERCEPTUAL_ROUGHNESS, 1.0);
+        return sample_ggx(albedo, roughness * roughness, wo);
+    }
+    return sample_lambertian(albedo, wo);
+}
+
+// The light carried by the sampled direction, none if it could not be sampled
+fn bsdf_weight(sample: BSDFSample) -> vec3<f32> {
+    if sample.pdf <= 0.0 {
+        return vec3<f32>(0.0);
+    }
+    return sample.color * abs(sample.wi.z) / sample.pdf;
+}
+
+// Cook-Torrance reflection with the Schlick Fresnel of f0, sampling the visible normals
+fn sample_ggx(f0: vec3<f32>, alpha: f32, wo: vec3<f32>) -> BSDFSample {
+    // Sampled above the surface, then mirrored like sample_lambertian does
+    let flip = select(1.0, -1.0, wo.z < 0.0);
+    let v = vec3<f32>(wo.xy, wo.z * flip);
+    if v.z <= 0.0 {
+        return BSDFSample(vec3<f32>(0.0), vec3<f32>(0.0, 0.0, flip), 0.0, RAY_SPECULAR);
+    }
+    let h = sample_ggx_vndf(v, alpha);
+    let v_dot_h = dot(v, h);
+    var wi = 2.0 * v_dot_h * h - v;
+    if wi.z <= 0.0 {
+        return BSDFSample(vec3<f32>(0.0), vec3<f32>(wi.xy, wi.z * flip), 0.0, RAY_SPECULAR);
+    }
+
+    let d = ggx_distribution(h.z, alpha);
+    let lambda_v = smith_lambda(v.z, alpha);
+    let lambda_l = smith_lambda(wi.z, alpha);
+    let g1 = 1.0 / (1.0 + lambda_v);
+    let g2 = 1.0 / (1.0 + lambda_v + lambda_l);
+    let fresnel = f0 + (1.0 - f0) * pow(max(1.0 - v_dot_h, 0.0), 5.0);
+
+    let pdf = d * g1 / (4.0 * v.z);
+    let color = fresnel * d * g2 / (4.0 * v.z * wi.z);
+    wi.z *= flip;
+    return BSDFSample(color, wi, pdf, RAY_SPECULAR);
+}
+
+// https://jcgt.org/published/0007/04/01/
+fn sample_ggx_vndf(v: vec3<f32>, alpha: f32) -> vec3<f32> {
+    let vh = normalize(vec3<f32>(alpha * v.x, alpha * v.y, v.z));
+    let len_sq = vh.x * vh.x + vh.y * vh.y;
+    var t1 = vec3<f32>(1.0, 0.0, 0.0);
+    if len_sq > 0.0 {
+        t1 = vec3<f32>(-vh.y, vh.x, 0.0) / sqrt(len_sq);
+    }
+    let t2 = cross(vh, t1);
+
+    let r = sqrt(rand());
+    let phi = 2.0 * PI * rand();
+    let p1 = r * cos(phi);
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * sqrt(1.0 - p1 * p1) + s * r * sin(phi);
+    let nh = p1 * t1 + p2 * t2 + sqrt(max(1.0 - p1 * p1 - p2 * p2, 0.0)) * vh;
+    return normalize(vec3<f32>(alpha * nh.x, alpha * nh.y, max(nh.z, 0.0)));
+}
+
+fn ggx_distribution(cos_theta: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let k = cos_theta * cos_theta * (a2 - 1.0) + 1.0;
+    return a2 * INV_PI / (k * k);
+}
+
+// Height correlated Smith masking: G1 = 1 / (1 + lambda(v)), G2 = 1 / (1 + lambda(v) + lambda(l))
+fn smith_lambda(cos_theta: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    return 0.5 * (sqrt(a2 + (1.0 - a2) * cos_theta * cos_theta) / cos_theta - 1.0);
 }
 
 fn sample_cosine_hemisphere() -> vec3<f32> {
@@ -325,15 +730,92 @@ fn get_ray(screen_pos: vec2<i32>, screen_size: vec2<i32>) -> Ray {
     return ray;
 }
 
-fn traverse_instances(ray: Ray, early_distance: f32, max_distance: f32) -> Hit {
+fn traverse_instances(ray: Ray, kind: u32, layers: u32, early_distance: f32, max_distance: f32) -> Hit {
     var hit: Hit;
     hit.intersection.distance = max_distance;
     hit.instance_index = U32_MAX;
     hit.primitive_index = U32_MAX;
 
-    var index = 0u;
-    for (; index < instance_node_buffer.count;) {
+#ifdef BVH_ORDERED
+    // Subtrees left for later: the node holding their box, and their entry distance
+    var stack: array<vec2<u32>, 8>; // BVH_SHORT_STACK_SIZE
+    var stack_size = 0u;
+    // Nodes of the subtree being visited
+    var start = 0u;
+    var end = instance_node_buffer.count;
+    while start < end {
+        let first = instance_node_buffer.data[start];
+        if first.entry_index >= BVH_LEAF_FLAG {
+            if traverse_instance_nodes(&hit, ray, kind, layers, start, end, early_distance) {
+                return hit;
+            }
+        } else {
+            // Inner node: the boxes of both children, the second one after the subtree of the first
+            let second = instance_node_buffer.data[first.exit_index];
+            traversal_stats.nodes += 2u;
+            let first_distance = intersects_aabb(ray, Aabb(first.min, first.max));
+            let second_distance = intersects_aabb(ray, Aabb(second.min, second.max));
+            var near = vec2<u32>(start, first.exit_index);
+            var far = vec2<u32>(first.exit_index, second.exit_index);
+            var near_distance = first_distance;
+            var far_distance = second_distance;
+            if second_distance < first_distance {
+                near = far;
+                far = vec2<u32>(start, first.exit_index);
+                near_distance = second_distance;
+                far_distance = first_distance;
+            }
+
+            if near_distance < hit.intersection.distance {
+                if far_distance < hit.intersection.distance {
+                    if stack_size < BVH_SHORT_STACK_SIZE {
+                        stack[stack_size] = vec2<u32>(far.x, bitcast<u32>(far_distance));
+                        stack_size += 1u;
+                    } else if traverse_instance_nodes(&hit, ray, kind, layers, far.x, far.y, early_distance) {
+                        // The stack is full, the far child is visited right away in build order
+                        return hit;
+                    }
+                }
+                start = near.x + 1u;
+                end = near.y;
+                continue;
+            }
+        }
+
+        // Next subtree that is not behind the closest hit
+        start = end;
+        while stack_size > 0u {
+            stack_size -= 1u;
+            if bitcast<f32>(stack[stack_size].y) < hit.intersection.distance {
+                let node = instance_node_buffer.data[stack[stack_size].x];
+                start = node.entry_index;
+                end = node.exit_index;
+                break;
+            }
+        }
+    }
+#else
+    traverse_instance_nodes(&hit, ray, kind, layers, 0u, instance_node_buffer.count, early_distance);
+#endif
+
+    return hit;
+}
+
+// Follows the skip links through the nodes in [start, end), returns true when the hit is
+// closer than early_distance
+fn traverse_instance_nodes(
+    hit: ptr<function, Hit>,
+    ray: Ray,
+    kind: u32,
+    layers: u32,
+    start: u32,
+    end: u32,
+    early_distance: f32
+) -> bool {
+    var index = start;
+    for (; index < end;) {
         let node = instance_node_buffer.data[index];
+        traversal_stats.nodes += 1u;
         var aabb: Aabb;
 
         if node.entry_index >= BVH_LEAF_FLAG {
@@ -342,16 +824,17 @@ fn traverse_instances(ray: Ray, early_distance: f32, max_distance: f32) -> Hit {
             aabb.min = instance.min;
             aabb.max = instance.max;
 
-            if intersects_aabb(ray, aabb) < hit.intersection.distance {
+            let visible = (instance.visibility & kind) != 0u && (instance.layers & layers) != 0u;
+            if visible && intersects_aabb(ray, aabb) < (*hit).intersection.distance {
                 var r: Ray;
                 r.orig = instance_position_world_to_local(instance, ray.orig);
                 r.dir = instance_direction_world_to_local(instance, ray.dir);
                 r.inv_dir = 1.0 / r.dir;
 
-                if traverse_mesh(&hit, r, instance.mesh, early_distance) {
-                    hit.instance_index = instance_index;
-                    if hit.intersection.distance < early_distance {
-                        return hit;
+                if traverse_mesh(hit, r, instance.mesh, early_distance) {
+                    (*hit).instance_index = instance_index;
+                    if (*hit).intersection.distance < early_distance {
+                        return true;
                     }
                 }
             }
@@ -363,30 +846,185 @@ fn traverse_instances(ray: Ray, early_distance: f32, max_distance: f32) -> Hit {
             index = select(
                 node.exit_index,
                 node.entry_index,
-                intersects_aabb(ray, aabb) < hit.intersection.distance
+                intersects_aabb(ray, aabb) < (*hit).intersection.distance
             );
         }
     }
 
-    return hit;
+    return false;
 }
 
+#ifdef BVH_WIDTH
 fn traverse_mesh(hit: ptr<function, Hit>, ray: Ray, mesh: MeshIndex, early_distance: f32) -> bool {
     var intersected = false;
-    var index = 0u;
-    for (; index < mesh.node.y;) {
+    var stack: array<u32, 64>; // BVH_STACK_SIZE
+    stack[0] = 0u;
+    var stack_size = 1u;
+    while stack_size > 0u {
+        stack_size -= 1u;
+        let base = 2u * (mesh.node.x + stack[stack_size]);
+        traversal_stats.nodes += 1u;
+        let header = chunk_node(mesh.chunk, base);
+        let origin = bitcast<vec3<f32>>(header.xyz);
+        let scale = vec3<f32>(
+            bitcast<f32>((header.w & 0xFFu) << 23u),
+            bitcast<f32>(((header.w >> 8u) & 0xFFu) << 23u),
+            bitcast<f32>(((header.w >> 16u) & 0xFFu) << 23u),
+        );
+
+        for (var child = 0u; child < BVH_WIDTH; child++) {
+            let entry = wide_node_word(mesh.chunk, base, 4u + child);
+            if entry == BVH_EMPTY_CHILD {
+                continue;
+            }
+
+            var aabb: Aabb;
+            aabb.min = origin + scale * vec3<f32>(
+                wide_child_plane(mesh.chunk, base, 0u, child),
+                wide_child_plane(mesh.chunk, base, 1u, child),
+                wide_child_plane(mesh.chunk, base, 2u, child),
+            );
+            aabb.max = origin + scale * vec3<f32>(
+                wide_child_plane(mesh.chunk, base, 3u, child),
+                wide_child_plane(mesh.chunk, base, 4u, child),
+                wide_child_plane(mesh.chunk, base, 5u, child),
+            );
+            if intersects_aabb(ray, aabb) >= (*hit).intersection.distance {
+                continue;
+            }
+
+            if entry >= BVH_LEAF_FLAG {
+                let primitive_index = mesh.primitive + entry - BVH_LEAF_FLAG;
+                let vertices = load_primitive(mesh, primitive_index);
+                let intersection = intersects_triangle(ray, vertices);
+                traversal_stats.primitives += 1u;
+                if intersection.distance < (*hit).intersection.distance {
+                    (*hit).intersection = intersection;
+                    (*hit).primitive_index = primitive_index;
+                    intersected = true;
+
+                    if intersection.distance < early_distance {
+                        return intersected;
+                    }
+                }
+            } else if stack_size < BVH_STACK_SIZE {
+                stack[stack_size] = entry;
+                stack_size += 1u;
+            }
+        }
+    }
+
+    return intersected;
+}
+
+fn wide_node_word(chunk: u32, base: u32, word: u32) -> u32 {
+    return chunk_node(chunk, base + word / 4u)[word % 4u];
+}
+
+// Quantized plane of a child: min x, y, z then max x, y, z
+fn wide_child_plane(chunk: u32, base: u32, plane: u32, child: u32) -> f32 {
+    let word = wide_node_word(chunk, base, 4u + BVH_WIDTH + plane * (BVH_WIDTH / 4u) + child / 4u);
+    return f32((word >> (8u * (child % 4u))) & 0xFFu);
+}
+#else
+fn traverse_mesh(hit: ptr<function, Hit>, ray: Ray, mesh: MeshIndex, early_distance: f32) -> bool {
+#ifdef BVH_ORDERED
+    var intersected = false;
+    // Subtrees left for later: the node holding their box, and their entry distance
+    var stack: array<vec2<u32>, 8>; // BVH_SHORT_STACK_SIZE
+    var stack_size = 0u;
+    // Nodes of the subtree being visited
+    var start = 0u;
+    var end = mesh.node.y;
+    while start < end {
+        let first = chunk_node(mesh.chunk, mesh.node.x + start);
+        if first.entry_index >= BVH_LEAF_FLAG {
+            if traverse_mesh_nodes(hit, ray, mesh, start, end, early_distance) {
+                intersected = true;
+                if (*hit).intersection.distance < early_distance {
+                    return intersected;
+                }
+            }
+        } else {
+            // Inner node: the boxes of both children, the second one after the subtree of the first
+            let second = chunk_node(mesh.chunk, mesh.node.x + first.exit_index);
+            traversal_stats.nodes += 2u;
+            let first_distance = intersects_aabb(ray, Aabb(first.min, first.max));
+            let second_distance = intersects_aabb(ray, Aabb(second.min, second.max));
+            var near = vec2<u32>(start, first.exit_index);
+            var far = vec2<u32>(first.exit_index, second.exit_index);
+            var near_distance = first_distance;
+            var far_distance = second_distance;
+            if second_distance < first_distance {
+                near = far;
+                far = vec2<u32>(start, first.exit_index);
+                near_distance = second_distance;
+                far_distance = first_distance;
+            }
+
+            if near_distance < (*hit).intersection.distance {
+                if far_distance < (*hit).intersection.distance {
+                    if stack_size < BVH_SHORT_STACK_SIZE {
+                        stack[stack_size] = vec2<u32>(far.x, bitcast<u32>(far_distance));
+                        stack_size += 1u;
+                    } else if traverse_mesh_nodes(hit, ray, mesh, far.x, far.y, early_distance) {
+                        // The stack is full, the far child is visited right away in build order
+                        intersected = true;
+                        if (*hit).intersection.distance < early_distance {
+                            return intersected;
+                        }
+                    }
+                }
+                start = near.x + 1u;
+                end = near.y;
+                continue;
+            }
+        }
+
+        // Next subtree that is not behind the closest hit
+        start = end;
+        while stack_size > 0u {
+            stack_size -= 1u;
+            if bitcast<f32>(stack[stack_size].y) < (*hit).intersection.distance {
+                let node = chunk_node(mesh.chunk, mesh.node.x + stack[stack_size].x);
+                start = node.entry_index;
+                end = node.exit_index;
+                break;
+            }
+        }
+    }
+    return intersected;
+#else
+    return traverse_mesh_nodes(hit, ray, mesh, 0u, mesh.node.y, early_distance);
+#endif
+}
+
+// Follows the skip links through the nodes in [start, end) of the mesh
+fn traverse_mesh_nodes(
+    hit: ptr<function, Hit>,
+    ray: Ray,
+    mesh: MeshIndex,
+    start: u32,
+    end: u32,
+    early_distance: f32
+) -> bool {
+    var intersected = false;
+    var index = start;
+    for (; index < end;) {
         let node_index = mesh.node.x + index;
-        let node = primitive_node_buffer.data[node_index];
+        let node = chunk_node(mesh.chunk, node_index);
+        traversal_stats.nodes += 1u;
         var aabb: Aabb;
         if node.entry_index >= BVH_LEAF_FLAG {
             let primitive_index = mesh.primitive + node.entry_index - BVH_LEAF_FLAG;
-            let vertices = primitive_buffer[primitive_index].vertices;
+            let vertices = load_primitive(mesh, primitive_index);
 
             aabb.min = min(vertices[0].position, min(vertices[1].position, vertices[2].position));
             aabb.max = max(vertices[0].position, max(vertices[1].position, vertices[2].position));
 
             if intersects_aabb(ray, aabb) < (*hit).intersection.distance {
                 let intersection = intersects_triangle(ray, vertices);
+                traversal_stats.primitives += 1u;
                 if intersection.distance < (*hit).intersection.distance {
                     (*hit).intersection = intersection;
                     (*hit).primitive_index = primitive_index;
@@ -412,6 +1050,7 @@ fn traverse_mesh(hit: ptr<function, Hit>, ray: Ray, mesh: MeshIndex, early_dista
 
     return intersected;
 }
+#endif
 
 fn instance_position_world_to_local(instance: Instance, p: vec3<f32>) -> vec3<f32> {
     let inverse_model = transpose(instance.inverse_transpose_model);
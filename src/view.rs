@@ -1,9 +1,10 @@
+use crate::{mesh_material::instance::layer_mask, RenderLayersMode, RtSettings};
 use bevy::{
     prelude::*,
     render::{
         render_resource::*,
-        renderer::RenderDevice,
-        view::{ViewUniform, ViewUniforms},
+        renderer::{RenderDevice, RenderQueue},
+        view::{ExtractedView, RenderLayers, ViewUniform, ViewUniforms},
         Render, RenderApp, RenderSet,
     },
 };
@@ -12,10 +13,16 @@ pub struct ViewPlugin;
 impl Plugin for ViewPlugin {
     fn build(&self, app: &mut App) {
         if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
-            render_app.add_systems(
-                Render,
-                queue_view_bind_group.in_set(RenderSet::PrepareBindGroups),
-            );
+            render_app
+                .init_resource::<RaytracerViewUniforms>()
+                .add_systems(
+                    Render,
+                    prepare_raytracer_view_uniforms.in_set(RenderSet::PrepareResources),
+                )
+                .add_systems(
+                    Render,
+                    queue_view_bind_group.in_set(RenderSet::PrepareBindGroups),
+                );
         }
     }
 
@@ -33,22 +40,81 @@ impl FromWorld for ViewBindGroupLayout {
         let render_device = world.resource::<RenderDevice>();
         let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: Some("view_bind_group_layout"),
-            entries: &[BindGroupLayoutEntry {
-                binding: 0,
-                visibility: ShaderStages::COMPUTE,
-                ty: BindingType::Buffer {
-                    ty: BufferBindingType::Uniform,
-                    has_dynamic_offset: true,
-                    min_binding_size: Some(ViewUniform::min_size()),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: Some(ViewUniform::min_size()),
+                    },
+                    count: None,
                 },
-                count: None,
-            }],
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: Some(GpuRaytracerView::min_size()),
+                    },
+                    count: None,
+                },
+            ],
         });
 
         Self(layout)
     }
 }
 
+/// What the raytracer needs per view, besides Bevy's [`ViewUniform`]
+#[derive(Clone, ShaderType)]
+pub struct GpuRaytracerView {
+    /// Mask of the `RenderLayers` of the camera, for the rays leaving it
+    pub primary_layers: u32,
+    /// Mask for the other rays, see [`RenderLayersMode`]
+    pub secondary_layers: u32,
+}
+
+#[derive(Resource, Default)]
+pub struct RaytracerViewUniforms {
+    pub uniforms: DynamicUniformBuffer<GpuRaytracerView>,
+}
+
+#[derive(Component)]
+pub struct RaytracerViewUniformOffset {
+    pub offset: u32,
+}
+
+fn prepare_raytracer_view_uniforms(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut view_uniforms: ResMut<RaytracerViewUniforms>,
+    settings: Res<RtSettings>,
+    views: Query<(Entity, Option<&RenderLayers>), With<ExtractedView>>,
+) {
+    view_uniforms.uniforms.clear();
+    for (entity, layers) in &views {
+        let primary_layers = layer_mask(layers);
+        let secondary_layers = match settings.render_layers {
+            RenderLayersMode::AllRays => primary_layers,
+            RenderLayersMode::PrimaryRays => u32::MAX,
+        };
+        let offset = view_uniforms.uniforms.push(GpuRaytracerView {
+            primary_layers,
+            secondary_layers,
+        });
+        commands
+            .entity(entity)
+            .insert(RaytracerViewUniformOffset { offset });
+    }
+    view_uniforms
+        .uniforms
+        .write_buffer(&render_device, &render_queue);
+}
+
 #[derive(Resource, Deref, DerefMut)]
 pub struct ViewBindGroup(BindGroup);
 
@@ -56,13 +122,17 @@ fn queue_view_bind_group(
     mut commands: Commands,
     render_device: Res<RenderDevice>,
     view_uniforms: Res<ViewUniforms>,
+    raytracer_view_uniforms: Res<RaytracerViewUniforms>,
     layout: Res<ViewBindGroupLayout>,
 ) {
-    if let Some(view_binding) = view_uniforms.uniforms.binding() {
+    if let (Some(view_binding), Some(raytracer_view_binding)) = (
+        view_uniforms.uniforms.binding(),
+        raytracer_view_uniforms.uniforms.binding(),
+    ) {
         let bind_group = render_device.create_bind_group(
             "view_bind_group",
             &layout,
-            &BindGroupEntries::single(view_binding),
+            &BindGroupEntries::sequential((view_binding, raytracer_view_binding)),
         );
 
         commands.insert_resource(ViewBindGroup(bind_group))
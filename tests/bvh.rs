@@ -0,0 +1,400 @@
+//! The wide BVH layouts, the ordered traversal and the linear BVH must find the same hits as
+//! the binary SAH BVH, with the stackless traversal.
+//! Refitting the instance BVH keeps tight bounds, and only the changed nodes are uploaded.
+//! The Karras hierarchy and the Morton codes of the linear BVH match known outputs.
+use bevy::{
+    prelude::*,
+    render::{
+        primitives::Aabb,
+        render_resource::{encase::StorageBuffer, ShaderType},
+    },
+};
+use rusticrayz::{
+    reference::{
+        build_instance_nodes, dirty_ranges, element_offset, instance_bvh_cost, karras_children,
+        morton_code, refit_instance_nodes, render, traversal_stats, Bounds, GpuInstance, GpuMesh,
+        GpuMeshIndex, GpuNode, GpuNodeBuffer, ReferenceScene, ReferenceSettings, ReferenceView,
+        Rng, REBUILD_COST_GROWTH,
+    },
+    BvhBuilder, BvhLayout, BvhSettings, BvhTraversal,
+};
+
+const SIZE: UVec2 = UVec2::new(64, 64);
+const WIDE_LAYOUTS: [BvhLayout; 2] = [BvhLayout::Wide4, BvhLayout::Wide8];
+
+fn settings(layout: BvhLayout) -> BvhSettings {
+    BvhSettings {
+        layout,
+        ..default()
+    }
+}
+
+fn linear(layout: BvhLayout) -> BvhSettings {
+    BvhSettings {
+        builder: BvhBuilder::Lbvh,
+        layout,
+        ..default()
+    }
+}
+
+fn ordered() -> BvhSettings {
+    BvhSettings {
+        traversal: BvhTraversal::Ordered,
+        ..default()
+    }
+}
+
+fn torus() -> Mesh {
+    Mesh::from(shape::Torus {
+        radius: 1.0,
+        ring_radius: 0.3,
+        subdivisions_segments: 48,
+        subdivisions_sides: 24,
+    })
+}
+
+fn scene(bvh: BvhSettings) -> (ReferenceScene, ReferenceView) {
+    let images = Assets::default();
+    let mut scene = ReferenceScene::default();
+    scene.bvh = bvh;
+
+    let torus = scene.add_mesh(torus()).unwrap();
+    let floor = scene
+        .add_mesh(Mesh::from(shape::Plane::from_size(10.0)))
+        .unwrap();
+    let light = scene.add_material(
+        &StandardMaterial {
+            base_color: Color::BLACK,
+            emissive: Color::WHITE,
+            ..default()
+        },
+        &images,
+    );
+    let gray = scene.add_material(
+        &StandardMaterial {
+            base_color: Color::GRAY,
+            ..default()
+        },
+        &images,
+    );
+    scene.add_instance(&floor, light, Transform::IDENTITY);
+    scene.add_instance(
+        &torus,
+        gray,
+        Transform::from_xyz(0.0, 0.5, 0.0).with_rotation(Quat::from_rotation_x(0.6)),
+    );
+
+    let view = ReferenceView::new(
+        SIZE,
+        &Transform::from_xyz(0.0, 3.0, 4.0)
+            .looking_at(Vec3::ZERO, Vec3::Y)
+            .into(),
+        &Projection::Perspective(default()),
+    );
+    (scene, view)
+}
+
+fn render_with(bvh: BvhSettings) -> Vec<f32> {
+    let (scene, view) = scene(bvh);
+    let settings = ReferenceSettings {
+        samples_per_pixel: 4,
+        max_bounces: 3,
+    };
+    render(&scene, &view, &settings).layers[0].data.clone()
+}
+
+/// Hits at the same distance may resolve to another triangle, with another random path
+fn assert_similar(expected: &[f32], actual: &[f32], name: &str) {
+    let different = expected
+        .chunks_exact(4)
+        .zip(actual.chunks_exact(4))
+        .filter(|(a, b)| a.iter().zip(b.iter()).any(|(a, b)| (a - b).abs() > 1e-4))
+        .count();
+    assert!(
+        different <= (SIZE.x * SIZE.y / 200) as usize,
+        "{name}: {different} pixels differ"
+    );
+}
+
+#[test]
+fn wide_layouts_render_like_binary() {
+    let binary = render_with(settings(BvhLayout::Binary));
+    for layout in WIDE_LAYOUTS {
+        let wide = render_with(settings(layout));
+        assert_similar(&binary, &wide, &format!("{layout:?}"));
+    }
+}
+
+#[test]
+fn ordered_traversal_renders_like_stackless() {
+    let stackless = render_with(default());
+    let ordered = render_with(ordered());
+    assert_similar(&stackless, &ordered, "Ordered");
+}
+
+/// Visiting the near child first lets the closest hit cull the far one
+#[test]
+fn ordered_traversal_visits_fewer_nodes() {
+    let stats = |bvh| {
+        let (scene, view) = scene(bvh);
+        traversal_stats(&scene, &view)
+    };
+    let stackless = stats(default());
+    let ordered = stats(ordered());
+    assert_eq!(stackless.rays, ordered.rays);
+    assert!(
+        ordered.nodes < stackless.nodes && ordered.primitives < stackless.primitives,
+        "ordered {ordered:?}, stackless {stackless:?}"
+    );
+}
+
+/// The wide layouts need no node per primitive, nor for each box of the binary nodes
+#[test]
+fn wide_layouts_are_smaller() {
+    let binary = GpuMesh::new(torus(), &settings(BvhLayout::Binary)).unwrap();
+    for layout in WIDE_LAYOUTS {
+        let wide = GpuMesh::new(torus(), &settings(layout)).unwrap();
+        assert_eq!(wide.bvh.layout, layout);
+        assert!(
+            wide.nodes.len() * 5 < binary.nodes.len() * 3,
+            "{layout:?}: {} nodes, {} for the binary layout",
+            wide.nodes.len(),
+            binary.nodes.len()
+        );
+    }
+}
+
+#[test]
+fn linear_bvh_renders_like_sah() {
+    let sah = render_with(default());
+    for layout in [BvhLayout::Binary, BvhLayout::Wide4] {
+        let lbvh = render_with(linear(layout));
+        assert_similar(&sah, &lbvh, &format!("Lbvh {layout:?}"));
+    }
+}
+
+/// The GPU builder relies on this size to place the nodes
+#[test]
+fn linear_bvh_has_a_primitive_per_leaf() {
+    let mesh = GpuMesh::new(torus(), &linear(BvhLayout::Binary)).unwrap();
+    let primitives = mesh.primitives.len();
+    assert_eq!(mesh.bvh.leaves, primitives);
+    assert_eq!(mesh.nodes.len(), 3 * primitives - 2);
+    let leaves = mesh.nodes.iter().filter(|node| node.is_leaf()).count();
+    assert_eq!(leaves, primitives);
+}
+
+/// Unit cubes on a 4x4 grid, moved by `offset`
+fn instances(offset: impl Fn(usize) -> Vec3) -> Vec<GpuInstance> {
+    let aabb = Aabb::from_min_max(Vec3::splat(-0.5), Vec3::splat(0.5));
+    (0..16)
+        .map(|index| {
+            let position = Vec3::new((index % 4) as f32, 0.0, (index / 4) as f32) * 3.0;
+            let transform = GlobalTransform::from_translation(position + offset(index));
+            GpuInstance::new(&aabb, &transform, GpuMeshIndex::default(), 0)
+        })
+        .collect()
+}
+
+fn jitter(index: usize) -> Vec3 {
+    Vec3::new((index as f32).sin(), 0.0, (index as f32).cos()) * 0.25
+}
+
+#[test]
+fn refit_matches_a_fresh_build() {
+    let moved = instances(jitter);
+    let fresh = build_instance_nodes(&moved, BvhBuilder::Sah);
+
+    // Refitting the nodes of a build recomputes the same boxes
+    let mut stale = fresh.clone();
+    for node in stale.iter_mut().filter(|node| !node.is_leaf()) {
+        node.min = Vec3::ZERO;
+        node.max = Vec3::ZERO;
+    }
+    assert_eq!(
+        refit_instance_nodes(&stale, &moved, f32::MAX),
+        Some(fresh.clone())
+    );
+
+    // Refitting the nodes built before the move gives every node the bounds of its leaves
+    let original = instances(|_| Vec3::ZERO);
+    let nodes = build_instance_nodes(&original, BvhBuilder::Sah);
+    let cost = instance_bvh_cost(&nodes, &original);
+    let refitted = refit_instance_nodes(&nodes, &moved, cost).expect("small moves keep the BVH");
+    for (index, node) in refitted.iter().enumerate() {
+        if node.is_leaf() {
+            continue;
+        }
+        let (min, max) = refitted[index + 1..node.exit_index as usize]
+            .iter()
+            .filter(|node| node.is_leaf())
+            .map(|leaf| moved[leaf.primitive_index() as usize].aabb())
+            .reduce(|(a_min, a_max), (b_min, b_max)| (a_min.min(b_min), a_max.max(b_max)))
+            .unwrap();
+        assert_eq!((node.min, node.max), (min, max), "node {index}");
+    }
+    assert_eq!(
+        (refitted[0].min, refitted[0].max),
+        (fresh[0].min, fresh[0].max)
+    );
+}
+
+#[test]
+fn refit_rebuilds_past_cost_growth() {
+    let original = instances(|_| Vec3::ZERO);
+    let nodes = build_instance_nodes(&original, BvhBuilder::Sah);
+    let cost = instance_bvh_cost(&nodes, &original);
+
+    // Swapping opposite corners stretches the boxes of their branches over the whole grid
+    let mut swapped = original.clone();
+    swapped.swap(0, 15);
+    swapped.swap(3, 12);
+    assert_eq!(refit_instance_nodes(&nodes, &swapped, cost), None);
+
+    // The limit is on the growth of the cost
+    let refitted = refit_instance_nodes(&nodes, &swapped, f32::MAX).unwrap();
+    let grown = instance_bvh_cost(&refitted, &swapped);
+    assert!(grown > cost * REBUILD_COST_GROWTH, "{grown} from {cost}");
+    let below = grown / REBUILD_COST_GROWTH * 1.001;
+    let above = grown / REBUILD_COST_GROWTH * 0.999;
+    assert!(refit_instance_nodes(&nodes, &swapped, below).is_some());
+    assert!(refit_instance_nodes(&nodes, &swapped, above).is_none());
+}
+
+/// Each dirty range, encoded alone, lands on the same bytes as in the whole buffer
+#[test]
+fn dirty_ranges_and_offsets() {
+    let node = |index: u32| GpuNode {
+        min: Vec3::splat(index as f32),
+        entry_index: index,
+        max: Vec3::splat(index as f32 + 1.0),
+        exit_index: index + 1,
+    };
+    let old = (0..10).map(node).collect::<Vec<_>>();
+    let mut new = old.clone();
+    for index in [0, 1, 4, 8, 9] {
+        new[index] = node(100 + index as u32);
+    }
+
+    let ranges = dirty_ranges(&old, &new);
+    assert_eq!(ranges, [0..2, 4..5, 8..10]);
+    assert!(dirty_ranges(&old, &old).is_empty());
+    let mut longer = old.clone();
+    longer.extend([node(10), node(11)]);
+    // One range of nodes, not the nodes 10 and 11
+    #[allow(clippy::single_range_in_vec_init)]
+    let appended = vec![10..12];
+    assert_eq!(dirty_ranges(&old, &longer), appended);
+
+    // The count comes first, padded to the alignment of the nodes
+    let size = GpuNode::min_size().get() as usize;
+    let offset = |index: usize| element_offset::<GpuNodeBuffer, GpuNode>(index) as usize;
+    assert_eq!(offset(0), 16);
+    assert_eq!(offset(4), 16 + 4 * size);
+
+    let mut buffer = StorageBuffer::new(Vec::new());
+    buffer
+        .write(&GpuNodeBuffer {
+            count: new.len() as u32,
+            data: new.clone(),
+        })
+        .unwrap();
+    let buffer = buffer.into_inner();
+    for range in ranges {
+        let mut bytes = StorageBuffer::new(Vec::new());
+        bytes.write(&new[range.clone()].to_vec()).unwrap();
+        let bytes = bytes.into_inner();
+        assert_eq!(bytes.len(), range.len() * size);
+        let start = offset(range.start);
+        assert_eq!(buffer[start..start + bytes.len()], bytes, "{range:?}");
+    }
+}
+
+/// Children of every inner node, then checks that the hierarchy has each node once
+fn karras_tree(codes: &[u32]) -> Vec<[usize; 2]> {
+    let children = (0..codes.len() - 1)
+        .map(|index| karras_children(codes, index))
+        .collect::<Vec<_>>();
+    let mut seen = vec![0; 2 * codes.len() - 1];
+    seen[0] += 1;
+    for child in children.iter().flatten() {
+        seen[*child] += 1;
+    }
+    assert!(
+        seen.iter().all(|&count| count == 1),
+        "{codes:?}: {children:?}"
+    );
+    children
+}
+
+/// The example of "Maximizing Parallelism in the Construction of BVHs, Octrees, and k-d Trees"
+#[test]
+fn karras_hierarchy_of_the_paper() {
+    let codes = [
+        0b00001, 0b00010, 0b00100, 0b00101, 0b10011, 0b11000, 0b11001, 0b11110,
+    ];
+    // Leaves are numbered after the 7 inner nodes
+    let leaf = |index: usize| 7 + index;
+    let expected = [
+        [3, 4],
+        [leaf(0), leaf(1)],
+        [leaf(2), leaf(3)],
+        [1, 2],
+        [leaf(4), 5],
+        [6, leaf(7)],
+        [leaf(5), leaf(6)],
+    ];
+    assert_eq!(karras_tree(&codes), expected);
+}
+
+/// Equal codes are ordered by their index, as if it extended them
+#[test]
+fn karras_hierarchy_of_duplicate_codes() {
+    let leaf = |index: usize| 3 + index;
+    let expected = [[1, 2], [leaf(0), leaf(1)], [leaf(2), leaf(3)]];
+    assert_eq!(karras_tree(&[7; 4]), expected);
+
+    // Runs of duplicates split at the first differing bit, then by index
+    let leaf = |index: usize| 4 + index;
+    let expected = [[leaf(0), 1], [3, leaf(4)], [leaf(2), leaf(3)], [leaf(1), 2]];
+    assert_eq!(karras_tree(&[0, 4, 4, 4, 5]), expected);
+
+    let mut rng = Rng::new(7);
+    for count in [2, 3, 17, 64] {
+        let mut codes = (0..count)
+            .map(|_| (rng.rand() * 8.0) as u32)
+            .collect::<Vec<_>>();
+        codes.sort();
+        karras_tree(&codes);
+    }
+}
+
+#[test]
+fn morton_codes_interleave_the_axes() {
+    let unit = Bounds {
+        min: Vec3::ZERO,
+        max: Vec3::ONE,
+    };
+    assert_eq!(morton_code(Vec3::ZERO, &unit), 0);
+    assert_eq!(morton_code(Vec3::new(0.5, 0.0, 0.0), &unit), 1 << 29);
+    assert_eq!(morton_code(Vec3::new(0.0, 0.5, 0.0), &unit), 1 << 28);
+    assert_eq!(morton_code(Vec3::new(0.0, 0.0, 0.5), &unit), 1 << 27);
+    assert_eq!(
+        morton_code(Vec3::new(1.0 / 1024.0, 0.0, 0.0), &unit),
+        1 << 2
+    );
+    // The last cell takes the maximum
+    assert_eq!(morton_code(Vec3::ONE, &unit), 0x3FFF_FFFF);
+
+    // An extent of 3 is covered by 768 cells of a power of two size
+    let bounds = Bounds {
+        min: Vec3::splat(-1.0),
+        max: Vec3::splat(2.0),
+    };
+    assert_eq!(
+        morton_code(Vec3::new(0.5, -1.0, -1.0), &bounds),
+        1 << 26 | 1 << 23
+    );
+    // 768 has its two top bits set on every axis
+    assert_eq!(morton_code(Vec3::splat(2.0), &bounds), 0x3F00_0000);
+}
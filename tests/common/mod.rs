@@ -0,0 +1,156 @@
+#![allow(dead_code)]
+
+use bevy::prelude::*;
+use rusticrayz::export::{ExrPrecision, HdrImage, HdrLayer, RenderMetadata};
+use std::path::{Path, PathBuf};
+
+pub mod scenes;
+
+/// Set to rewrite the stored references instead of comparing against them
+const BLESS_VAR: &str = "RUSTICRAYZ_BLESS";
+/// Side of the window over which local statistics are computed
+const SSIM_WINDOW: i32 = 7;
+
+/// Compares the radiance of `image` against `tests/golden/<name>.exr`.
+/// On failure, the render and a heatmap of the differences are written to the test target directory.
+pub fn assert_golden(name: &str, image: &HdrImage, min_ssim: f32) {
+    let reference_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{name}.exr"));
+
+    if std::env::var_os(BLESS_VAR).is_some() {
+        image
+            .write_exr(&reference_path, ExrPrecision::Half)
+            .expect("failed to write reference");
+        return;
+    }
+
+    let Some((size, reference)) = read_radiance(&reference_path) else {
+        panic!(
+            "missing reference {}, run the tests with {BLESS_VAR}=1 to create it",
+            reference_path.display()
+        );
+    };
+    assert_eq!(size, image.size, "{name}: resolution changed");
+
+    let radiance = image.layers[0]
+        .data
+        .chunks_exact(4)
+        .map(Vec4::from_slice)
+        .collect::<Vec<_>>();
+    let map = ssim_map(size, &luminance(&reference), &luminance(&radiance));
+    let ssim = map.iter().sum::<f32>() / map.len() as f32;
+
+    if ssim < min_ssim {
+        let out_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("golden");
+        std::fs::create_dir_all(&out_dir).unwrap();
+
+        image
+            .write_exr(
+                out_dir.join(format!("{name}.actual.exr")),
+                ExrPrecision::Float,
+            )
+            .unwrap();
+        heatmap(size, &map)
+            .write_exr(out_dir.join(format!("{name}.diff.exr")), ExrPrecision::Half)
+            .unwrap();
+
+        panic!(
+            "{name}: SSIM {ssim} is below {min_ssim}, see {}",
+            out_dir.display()
+        );
+    }
+}
+
+fn read_radiance(path: &Path) -> Option<(UVec2, Vec<Vec4>)> {
+    let image = exr::prelude::read_first_rgba_layer_from_file(
+        path,
+        |resolution, _| {
+            (
+                resolution.width(),
+                vec![Vec4::ZERO; resolution.width() * resolution.height()],
+            )
+        },
+        |(width, pixels), position, (r, g, b, a): (f32, f32, f32, f32)| {
+            pixels[position.y() * *width + position.x()] = Vec4::new(r, g, b, a);
+        },
+    )
+    .ok()?;
+
+    let size = image.layer_data.size;
+    let (_, pixels) = image.layer_data.channel_data.pixels;
+    Some((
+        UVec2::new(size.width() as u32, size.height() as u32),
+        pixels,
+    ))
+}
+
+/// Tonemapped luminance, so that bright emitters do not dominate the metric
+fn luminance(pixels: &[Vec4]) -> Vec<f32> {
+    pixels
+        .iter()
+        .map(|pixel| {
+            let luminance = pixel.truncate().dot(Vec3::new(0.2126, 0.7152, 0.0722));
+            luminance / (1.0 + luminance)
+        })
+        .collect()
+}
+
+/// Structural similarity of every pixel, computed over a square window around it
+fn ssim_map(size: UVec2, a: &[f32], b: &[f32]) -> Vec<f32> {
+    const C1: f32 = 0.01 * 0.01;
+    const C2: f32 = 0.03 * 0.03;
+
+    let size = size.as_ivec2();
+    let mut map = Vec::with_capacity(a.len());
+    for y in 0..size.y {
+        for x in 0..size.x {
+            let (mut mean_a, mut mean_b) = (0.0, 0.0);
+            let (mut var_a, mut var_b, mut covariance) = (0.0, 0.0, 0.0);
+            let mut count = 0.0;
+
+            let half = SSIM_WINDOW / 2;
+            let window = (y - half).max(0)..(y + half + 1).min(size.y);
+            for wy in window {
+                for wx in (x - half).max(0)..(x + half + 1).min(size.x) {
+                    let index = (wy * size.x + wx) as usize;
+                    mean_a += a[index];
+                    mean_b += b[index];
+                    var_a += a[index] * a[index];
+                    var_b += b[index] * b[index];
+                    covariance += a[index] * b[index];
+                    count += 1.0;
+                }
+            }
+
+            mean_a /= count;
+            mean_b /= count;
+            var_a = var_a / count - mean_a * mean_a;
+            var_b = var_b / count - mean_b * mean_b;
+            covariance = covariance / count - mean_a * mean_b;
+
+            let ssim = ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            map.push(ssim);
+        }
+    }
+    map
+}
+
+/// Black where the images match, going through red to yellow as they diverge
+fn heatmap(size: UVec2, ssim: &[f32]) -> HdrImage {
+    let error = ssim
+        .iter()
+        .map(|ssim| (1.0 - ssim).clamp(0.0, 1.0))
+        .collect::<Vec<_>>();
+    let colors = error
+        .iter()
+        .map(|error| {
+            let error = error * 2.0;
+            Vec4::new(error.min(1.0), (error - 1.0).max(0.0), 0.0, 1.0)
+        })
+        .collect::<Vec<_>>();
+
+    HdrImage::new(size, &colors, RenderMetadata::default())
+        .with_layer(HdrLayer::scalar("error", &error))
+}
@@ -0,0 +1,42 @@
+//! The example scenes, built for the CPU reference from the descriptions the examples spawn.
+use bevy::prelude::*;
+use rusticrayz::reference::{ReferenceScene, ReferenceView};
+
+#[path = "../../examples/scenes/mod.rs"]
+mod examples;
+
+/// Same aspect ratio as the GPU color buffer
+pub const SIZE: UVec2 = UVec2::new(128, 72);
+
+fn reference(scene: examples::Scene) -> (ReferenceScene, ReferenceView) {
+    let images = Assets::default();
+    let mut reference = ReferenceScene::default();
+
+    let meshes = scene
+        .meshes
+        .into_iter()
+        .map(|mesh| reference.add_mesh(mesh).unwrap())
+        .collect::<Vec<_>>();
+    let materials = scene
+        .materials
+        .iter()
+        .map(|material| reference.add_material(material, &images))
+        .collect::<Vec<_>>();
+    for (mesh, material, transform) in scene.objects {
+        reference.add_instance(&meshes[mesh], materials[material], transform);
+    }
+
+    let projection = Projection::Perspective(default());
+    let view = ReferenceView::new(SIZE, &scene.camera.into(), &projection);
+    (reference, view)
+}
+
+/// `examples/cornell_box.rs`
+pub fn cornell_box() -> (ReferenceScene, ReferenceView) {
+    reference(examples::cornell_box())
+}
+
+/// `examples/emissive_walls.rs`
+pub fn emissive_walls() -> (ReferenceScene, ReferenceView) {
+    reference(examples::emissive_walls())
+}
@@ -0,0 +1,194 @@
+//! Skinned and morphed instances must render like the same geometry built in its final pose.
+use bevy::{
+    prelude::*,
+    render::mesh::{morph::MorphAttributes, morph::MorphTargetImage, VertexAttributeValues},
+};
+use rusticrayz::{
+    reference::{
+        render, GpuMesh, MeshDeformation, MeshPose, ReferenceScene, ReferenceSettings,
+        ReferenceView,
+    },
+    BvhLayout, BvhSettings,
+};
+
+const SIZE: UVec2 = UVec2::new(64, 64);
+const LAYOUTS: [BvhLayout; 3] = [BvhLayout::Binary, BvhLayout::Wide4, BvhLayout::Wide8];
+
+fn torus() -> Mesh {
+    Mesh::from(shape::Torus {
+        radius: 1.0,
+        ring_radius: 0.3,
+        subdivisions_segments: 48,
+        subdivisions_sides: 24,
+    })
+}
+
+/// The torus bound to two joints: the half with a positive x follows the second one
+fn skinned_torus() -> Mesh {
+    let mut mesh = torus();
+    let Some(VertexAttributeValues::Float32x3(positions)) =
+        mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+    else {
+        unreachable!();
+    };
+    let (indices, weights): (Vec<_>, Vec<_>) = positions
+        .iter()
+        .map(|position| {
+            let second = (position[0] * 2.0 + 0.5).clamp(0.0, 1.0);
+            ([0u16, 1, 0, 0], [1.0 - second, second, 0.0, 0.0])
+        })
+        .unzip();
+    mesh.insert_attribute(
+        Mesh::ATTRIBUTE_JOINT_INDEX,
+        VertexAttributeValues::Uint16x4(indices),
+    );
+    mesh.insert_attribute(Mesh::ATTRIBUTE_JOINT_WEIGHT, weights);
+    mesh
+}
+
+/// Lifts the torus and bends its positive half upwards
+fn pose() -> MeshPose {
+    MeshPose {
+        joints: vec![
+            Mat4::from_translation(Vec3::Y * 0.5),
+            Mat4::from_translation(Vec3::Y * 0.5) * Mat4::from_rotation_z(0.5),
+        ],
+        morph_weights: vec![],
+    }
+}
+
+/// The same mesh with the vertices in their deformed place
+fn rebuilt(mesh: &Mesh, morph_targets: Option<&Image>, pose: &MeshPose) -> Mesh {
+    let deformation = MeshDeformation::new(mesh, morph_targets).unwrap();
+    let bind_pose = GpuMesh::new(mesh.clone(), &default()).unwrap();
+    let vertices = deformation.deform(&bind_pose.vertices, pose);
+
+    let mut rebuilt = torus();
+    rebuilt.insert_attribute(
+        Mesh::ATTRIBUTE_POSITION,
+        vertices
+            .iter()
+            .map(|vertex| vertex.position.to_array())
+            .collect::<Vec<_>>(),
+    );
+    rebuilt.insert_attribute(
+        Mesh::ATTRIBUTE_NORMAL,
+        vertices
+            .iter()
+            .map(|vertex| vertex.normal.to_array())
+            .collect::<Vec<_>>(),
+    );
+    rebuilt
+}
+
+/// A floor, under a torus added by `add_torus`
+fn render_with(layout: BvhLayout, add_torus: impl FnOnce(&mut ReferenceScene, u32)) -> Vec<f32> {
+    let images = Assets::default();
+    let mut scene = ReferenceScene::default();
+    scene.bvh = BvhSettings {
+        layout,
+        ..default()
+    };
+
+    let floor = scene
+        .add_mesh(Mesh::from(shape::Plane::from_size(10.0)))
+        .unwrap();
+    let light = scene.add_material(
+        &StandardMaterial {
+            base_color: Color::BLACK,
+            emissive: Color::WHITE,
+            ..default()
+        },
+        &images,
+    );
+    let gray = scene.add_material(
+        &StandardMaterial {
+            base_color: Color::GRAY,
+            ..default()
+        },
+        &images,
+    );
+    scene.add_instance(&floor, light, Transform::IDENTITY);
+    add_torus(&mut scene, gray);
+
+    let view = ReferenceView::new(
+        SIZE,
+        &Transform::from_xyz(0.0, 3.0, 4.0)
+            .looking_at(Vec3::ZERO, Vec3::Y)
+            .into(),
+        &Projection::Perspective(default()),
+    );
+    let settings = ReferenceSettings {
+        samples_per_pixel: 4,
+        max_bounces: 3,
+    };
+    render(&scene, &view, &settings).layers[0].data.clone()
+}
+
+/// Deformed vertices may round differently, and hit another triangle with another random path
+fn assert_similar(expected: &[f32], actual: &[f32], name: &str) {
+    let different = expected
+        .chunks_exact(4)
+        .zip(actual.chunks_exact(4))
+        .filter(|(a, b)| a.iter().zip(b.iter()).any(|(a, b)| (a - b).abs() > 1e-4))
+        .count();
+    assert!(
+        different <= (SIZE.x * SIZE.y / 100) as usize,
+        "{name}: {different} pixels differ"
+    );
+}
+
+/// The refitted BVH of the copy finds the same hits as a BVH built in the pose
+#[test]
+fn skinned_instance_renders_like_rebuilt_mesh() {
+    let rebuilt = rebuilt(&skinned_torus(), None, &pose());
+    for layout in LAYOUTS {
+        let expected = render_with(layout, |scene, material| {
+            let mesh = scene.add_mesh(rebuilt.clone()).unwrap();
+            scene.add_instance(&mesh, material, Transform::IDENTITY);
+        });
+        // Skinned vertices ignore the transform of their instance
+        let skinned = render_with(layout, |scene, material| {
+            scene
+                .add_deformed_instance(
+                    skinned_torus(),
+                    None,
+                    &pose(),
+                    material,
+                    Transform::from_xyz(5.0, 0.0, 0.0),
+                )
+                .unwrap();
+        });
+        assert_similar(&expected, &skinned, &format!("{layout:?}"));
+    }
+}
+
+/// Morph targets displace the vertices in the space of their instance
+#[test]
+fn morphed_instance_renders_like_moved_mesh() {
+    let mesh = torus();
+    let vertex_count = mesh.count_vertices();
+    let lift = MorphAttributes::new(Vec3::Y, Vec3::ZERO, Vec3::ZERO);
+    let targets = [vec![lift; vertex_count]];
+    let image = MorphTargetImage::new(
+        targets.iter().map(|target| target.iter().copied()),
+        vertex_count,
+    )
+    .unwrap()
+    .0;
+    let pose = MeshPose {
+        joints: vec![],
+        morph_weights: vec![0.5],
+    };
+
+    let expected = render_with(BvhLayout::Binary, |scene, material| {
+        let mesh = scene.add_mesh(torus()).unwrap();
+        scene.add_instance(&mesh, material, Transform::from_xyz(0.0, 0.5, 0.0));
+    });
+    let morphed = render_with(BvhLayout::Binary, |scene, material| {
+        scene
+            .add_deformed_instance(mesh, Some(&image), &pose, material, Transform::IDENTITY)
+            .unwrap();
+    });
+    assert_similar(&expected, &morphed, "Morphed");
+}
@@ -0,0 +1,320 @@
+//! Analytic checks of the integrator: scenes and integrals whose exact result is known.
+//! A new lobe of the standard material is sampled by `directional_albedo` too, and must pass these.
+use bevy::{
+    prelude::*,
+    render::mesh::{Indices, VertexAttributeValues},
+};
+use rusticrayz::reference::{
+    cosine_hemisphere_pdf, directional_albedo, render, sample_lambertian, ReferenceScene,
+    ReferenceSettings, ReferenceView, Rng,
+};
+use std::f32::consts::{FRAC_PI_2, PI};
+
+const SIZE: UVec2 = UVec2::new(32, 32);
+
+fn sphere(radius: f32) -> Mesh {
+    Mesh::from(shape::UVSphere {
+        radius,
+        sectors: 32,
+        stacks: 32,
+    })
+}
+
+/// Flips the winding and normals, so that the mesh is seen from the inside
+fn inverted(mut mesh: Mesh) -> Mesh {
+    if let Some(Indices::U32(indices)) = mesh.indices_mut() {
+        for triangle in indices.chunks_exact_mut(3) {
+            triangle.swap(1, 2);
+        }
+    }
+    if let Some(VertexAttributeValues::Float32x3(normals)) =
+        mesh.attribute_mut(Mesh::ATTRIBUTE_NORMAL)
+    {
+        for normal in normals {
+            *normal = (-Vec3::from_array(*normal)).to_array();
+        }
+    }
+    mesh
+}
+
+fn material(scene: &mut ReferenceScene, material: StandardMaterial) -> u32 {
+    scene.add_material(&material, &Assets::default())
+}
+
+fn radiance(data: &[f32]) -> impl Iterator<Item = Vec3> + '_ {
+    data.chunks_exact(4).map(Vec3::from_slice)
+}
+
+/// Inside a uniformly emitting enclosure, a white sphere reflects all the light it receives
+/// and cannot be told apart from the background.
+#[test]
+fn white_furnace() {
+    let environment = 0.5;
+
+    let mut scene = ReferenceScene::default();
+    let enclosure = scene.add_mesh(inverted(sphere(10.0))).unwrap();
+    let sphere = scene.add_mesh(sphere(1.0)).unwrap();
+    let sky = material(
+        &mut scene,
+        StandardMaterial {
+            base_color: Color::BLACK,
+            emissive: Color::rgb(environment, environment, environment),
+            ..default()
+        },
+    );
+    let white = material(
+        &mut scene,
+        StandardMaterial {
+            base_color: Color::WHITE,
+            ..default()
+        },
+    );
+    scene.add_instance(&enclosure, sky, Transform::IDENTITY);
+    scene.add_instance(&sphere, white, Transform::IDENTITY);
+
+    let view = ReferenceView::new(
+        SIZE,
+        &Transform::from_xyz(0.0, 0.0, 4.0).into(),
+        &Projection::Perspective(default()),
+    );
+    let settings = ReferenceSettings {
+        samples_per_pixel: 4,
+        max_bounces: 5,
+    };
+    let image = render(&scene, &view, &settings);
+
+    for (index, pixel) in radiance(&image.layers[0].data).enumerate() {
+        assert!(
+            pixel.abs_diff_eq(Vec3::splat(environment), 1e-3),
+            "pixel {index}: {pixel}"
+        );
+    }
+}
+
+/// A small spherical light over a diffuse plane. The irradiance from a spherical emitter
+/// of radiance `L` and radius `r` whose center is at distance `d` is `π L (r / d)² cos θ`,
+/// so the plane reflects `albedo L (r / d)² cos θ`.
+#[test]
+fn sphere_light_over_plane() {
+    let emission = 4.0;
+    let radius = 0.25;
+    let height = 1.0;
+    let albedo = 0.5;
+
+    let mut scene = ReferenceScene::default();
+    let plane = scene
+        .add_mesh(Mesh::from(shape::Plane::from_size(20.0)))
+        .unwrap();
+    let light_mesh = scene.add_mesh(sphere(radius)).unwrap();
+    let diffuse = material(
+        &mut scene,
+        StandardMaterial {
+            base_color: Color::rgb(albedo, albedo, albedo),
+            ..default()
+        },
+    );
+    let light = material(
+        &mut scene,
+        StandardMaterial {
+            base_color: Color::BLACK,
+            emissive: Color::rgb(emission, emission, emission),
+            ..default()
+        },
+    );
+    scene.add_instance(&plane, diffuse, Transform::IDENTITY);
+    let light_center = Vec3::new(0.0, height, 0.0);
+    scene.add_instance(
+        &light_mesh,
+        light,
+        Transform::from_translation(light_center),
+    );
+
+    // Looking straight down, next to the light so that it stays out of frame
+    let view = ReferenceView::new(
+        SIZE,
+        &Transform::from_xyz(0.8, 3.0, 0.0)
+            .looking_at(Vec3::new(0.8, 0.0, 0.0), Vec3::Z)
+            .into(),
+        &Projection::Perspective(PerspectiveProjection {
+            fov: 0.1,
+            ..default()
+        }),
+    );
+    let settings = ReferenceSettings {
+        samples_per_pixel: 256,
+        max_bounces: 2,
+    };
+    let image = render(&scene, &view, &settings);
+
+    let expected = |x: u32, y: u32| {
+        // Unjittered ray of the pixel, computed the same way as `get_ray`
+        let uv = UVec2::new(x, y).as_vec2() / SIZE.as_vec2() * 2.0 - 1.0;
+        let target = view.inverse_projection * Vec4::new(uv.x, -uv.y, 1.0, 1.0);
+        let dir = view
+            .view
+            .transform_vector3((target.xyz() / target.w).normalize());
+        let origin = view.view.transform_point3(Vec3::ZERO);
+        let point = origin + dir * (origin.y / -dir.y);
+
+        let to_light = light_center - point;
+        let distance = to_light.length();
+        let cos_theta = to_light.y / distance;
+        albedo * emission * (radius / distance).powi(2) * cos_theta
+    };
+
+    let pixels = (0..SIZE.y).flat_map(|y| (0..SIZE.x).map(move |x| (x, y)));
+    let expected = pixels.map(|(x, y)| expected(x, y)).sum::<f32>();
+    let rendered = radiance(&image.layers[0].data)
+        .map(|pixel| pixel.x)
+        .sum::<f32>();
+
+    let error = (rendered - expected).abs() / expected;
+    assert!(
+        error < 0.03,
+        "rendered {rendered}, expected {expected}, error {error}"
+    );
+}
+
+/// Lambertian reflection scatters exactly `albedo`, whatever the incoming direction
+#[test]
+fn lambertian_energy_conservation() {
+    let mut scene = ReferenceScene::default();
+    for albedo in [0.0, 0.18, 0.5, 1.0] {
+        let index = material(
+            &mut scene,
+            StandardMaterial {
+                base_color: Color::rgb(albedo, albedo, albedo),
+                ..default()
+            },
+        );
+        for theta in [0.0f32, 0.5, 1.0, 1.5] {
+            let wo = Vec3::new(theta.sin(), 0.0, theta.cos());
+            let estimate = directional_albedo(&scene.materials[index as usize], wo, 4096);
+            assert!(
+                estimate.abs_diff_eq(Vec3::splat(albedo), 1e-3),
+                "albedo {albedo}, θ {theta}: {estimate}"
+            );
+        }
+    }
+}
+
+/// The GGX lobe of metals never creates energy. It keeps nearly all of it when smooth,
+/// and loses some to the masking of its microfacets when rough.
+#[test]
+fn metallic_energy_conservation() {
+    let mut scene = ReferenceScene::default();
+    let mut albedo = |metallic: f32, roughness: f32, theta: f32| {
+        let index = material(
+            &mut scene,
+            StandardMaterial {
+                base_color: Color::WHITE,
+                metallic,
+                perceptual_roughness: roughness,
+                ..default()
+            },
+        );
+        let wo = Vec3::new(theta.sin(), 0.0, theta.cos());
+        directional_albedo(&scene.materials[index as usize], wo, 4096)
+    };
+
+    for metallic in [0.25, 0.5, 1.0] {
+        for roughness in [0.0, 0.05, 0.3, 0.6, 1.0] {
+            for theta in [0.0f32, 0.5, 1.0, 1.5] {
+                let estimate = albedo(metallic, roughness, theta);
+                assert!(
+                    estimate.max_element() <= 1.0 + 1e-3 && estimate.min_element() >= 0.0,
+                    "metallic {metallic}, roughness {roughness}, θ {theta}: {estimate}"
+                );
+            }
+        }
+    }
+
+    // Albedos of the single scattering lobe, integrated by quadrature
+    let expected = [
+        (0.05, 0.5, 1.0),
+        (0.6, 0.0, 0.825),
+        (0.6, 1.5, 0.907),
+        (1.0, 0.0, 0.307),
+        (1.0, 1.0, 0.434),
+        (1.0, 1.5, 0.808),
+    ];
+    for (roughness, theta, expected) in expected {
+        let estimate = albedo(1.0, roughness, theta);
+        assert!(
+            estimate.abs_diff_eq(Vec3::splat(expected), 0.02),
+            "roughness {roughness}, θ {theta}: {estimate}, expected {expected}"
+        );
+    }
+}
+
+/// The metal lobe reflects the base color at normal incidence, and whitens at grazing angles
+#[test]
+fn metallic_fresnel_follows_base_color() {
+    let mut scene = ReferenceScene::default();
+    let index = material(
+        &mut scene,
+        StandardMaterial {
+            base_color: Color::rgb(1.0, 0.5, 0.0),
+            metallic: 1.0,
+            perceptual_roughness: 0.05,
+            ..default()
+        },
+    );
+    let material = &scene.materials[index as usize];
+
+    let normal = directional_albedo(material, Vec3::Z, 1024);
+    assert!(
+        normal.abs_diff_eq(Vec3::new(1.0, 0.5, 0.0), 0.01),
+        "{normal}"
+    );
+    let theta = 1.5f32;
+    let grazing = directional_albedo(material, Vec3::new(theta.sin(), 0.0, theta.cos()), 1024);
+    assert!(grazing.z > 0.3, "{grazing}");
+}
+
+/// The density returned by `cosine_hemisphere_pdf` integrates to one over the hemisphere
+#[test]
+fn cosine_hemisphere_pdf_is_normalized() {
+    let steps = 10_000;
+    let d_theta = FRAC_PI_2 / steps as f32;
+    let integral = (0..steps)
+        .map(|i| {
+            let theta = (i as f32 + 0.5) * d_theta;
+            cosine_hemisphere_pdf(theta.cos()) * 2.0 * PI * theta.sin() * d_theta
+        })
+        .sum::<f32>();
+    assert!((integral - 1.0).abs() < 1e-3, "{integral}");
+}
+
+/// The directions drawn by `sample_lambertian` follow the density it reports:
+/// for a cosine-weighted hemisphere, `cos² θ` is uniformly distributed.
+#[test]
+fn sample_lambertian_matches_its_pdf() {
+    const BINS: usize = 10;
+    let samples = 100_000;
+
+    let mut rng = Rng::new(1);
+    let mut histogram = [0u32; BINS];
+    for _ in 0..samples {
+        let sample = sample_lambertian(&mut rng, Vec3::ONE, Vec3::Z);
+        assert!(sample.wi.z >= 0.0, "{}", sample.wi);
+        assert!((sample.wi.length() - 1.0).abs() < 1e-4, "{}", sample.wi);
+        assert_eq!(sample.pdf, cosine_hemisphere_pdf(sample.wi.z));
+
+        let bin = (sample.wi.z * sample.wi.z * BINS as f32) as usize;
+        histogram[bin.min(BINS - 1)] += 1;
+    }
+
+    let expected = samples as f32 / BINS as f32;
+    for (bin, count) in histogram.into_iter().enumerate() {
+        let error = (count as f32 - expected).abs() / expected;
+        assert!(
+            error < 0.05,
+            "bin {bin}: {count} samples, expected {expected}"
+        );
+    }
+
+    // Below the surface, the sample is mirrored
+    let sample = sample_lambertian(&mut rng, Vec3::ONE, -Vec3::Z);
+    assert!(sample.wi.z <= 0.0);
+}
@@ -0,0 +1,159 @@
+//! The exported files read back with the layers, metadata and pixels that were written.
+use bevy::prelude::*;
+use exr::prelude::{read, AttributeValue, FlatSamples, ReadChannels, ReadLayers, Text};
+use rusticrayz::export::{ExrPrecision, HdrImage, HdrLayer, RenderMetadata};
+use std::{path::PathBuf, time::Duration};
+
+const SIZE: UVec2 = UVec2::new(3, 2);
+
+fn out_dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"))
+        .join("export")
+        .join(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn image() -> HdrImage {
+    let radiance = (0..6)
+        .map(|i| Vec4::new(i as f32, 0.5 * i as f32, 10.0 + i as f32, 1.0))
+        .collect::<Vec<_>>();
+    let normals = (0..6)
+        .map(|i| Vec3::new(i as f32, -1.0, 0.25))
+        .collect::<Vec<_>>();
+    let depth = (0..6).map(|i| 100.0 + i as f32).collect::<Vec<_>>();
+    let metadata = RenderMetadata {
+        samples: 64,
+        max_bounces: 5,
+        render_time: Duration::from_millis(1500),
+        camera_transform: Mat4::from_translation(Vec3::new(1.0, 2.0, 3.0)),
+    };
+
+    HdrImage::new(SIZE, &radiance, metadata)
+        .with_layer(HdrLayer::xyz("normal", &normals))
+        .with_layer(HdrLayer::scalar("depth", &depth))
+}
+
+#[test]
+fn exr_round_trip() {
+    let path = out_dir("exr").join("frame.exr");
+    let image = image();
+    image.write_exr(&path, ExrPrecision::Float).unwrap();
+
+    let file = read()
+        .no_deep_data()
+        .largest_resolution_level()
+        .all_channels()
+        .all_layers()
+        .all_attributes()
+        .from_file(&path)
+        .unwrap();
+    assert_eq!(file.layer_data.len(), 1);
+    let layer = &file.layer_data[0];
+    assert_eq!(layer.size.width(), SIZE.x as usize);
+    assert_eq!(layer.size.height(), SIZE.y as usize);
+
+    // The radiance is unprefixed, the AOVs are prefixed with their layer
+    let channel = |name: &str| {
+        let channel = layer
+            .channel_data
+            .list
+            .iter()
+            .find(|channel| channel.name == *name)
+            .unwrap_or_else(|| panic!("missing channel {name}"));
+        match &channel.sample_data {
+            FlatSamples::F32(samples) => samples.clone(),
+            samples => panic!("{name} is not stored as f32: {samples:?}"),
+        }
+    };
+    let mut names = layer
+        .channel_data
+        .list
+        .iter()
+        .map(|channel| channel.name.to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        ["A", "B", "G", "R", "depth.Y", "normal.X", "normal.Y", "normal.Z"]
+    );
+
+    // Pixels go row by row from the top, like the layers
+    fn expected(layer: &HdrLayer, index: usize) -> Vec<f32> {
+        let count = layer.channels.len();
+        layer
+            .data
+            .iter()
+            .skip(index)
+            .step_by(count)
+            .copied()
+            .collect()
+    }
+    let [radiance, normal, depth] = [0, 1, 2].map(|i| &image.layers[i]);
+    for (index, name) in ["R", "G", "B", "A"].into_iter().enumerate() {
+        assert_eq!(channel(name), expected(radiance, index), "{name}");
+    }
+    for (index, name) in ["normal.X", "normal.Y", "normal.Z"].into_iter().enumerate() {
+        assert_eq!(channel(name), expected(normal, index), "{name}");
+    }
+    assert_eq!(channel("depth.Y"), depth.data);
+
+    let attributes = &layer.attributes;
+    let other = |name: &str| attributes.other.get(&Text::from(name)).cloned();
+    assert_eq!(other("rusticrayz:samples"), Some(AttributeValue::I32(64)));
+    assert_eq!(other("rusticrayz:maxBounces"), Some(AttributeValue::I32(5)));
+    assert_eq!(
+        other("rusticrayz:renderTime"),
+        Some(AttributeValue::F64(1.5))
+    );
+    assert_eq!(
+        attributes.world_to_camera,
+        Some(image.metadata.camera_transform.inverse().to_cols_array())
+    );
+    let software = attributes.software_name.as_ref().unwrap().to_string();
+    assert!(software.starts_with("rusticrayz "));
+}
+
+/// The header and the samples of a PFM, rows from the top
+fn read_pfm(path: PathBuf) -> (String, Vec<f32>) {
+    let bytes = std::fs::read(&path).unwrap_or_else(|_| panic!("missing {}", path.display()));
+    let mut lines = 0;
+    let header_len = bytes
+        .iter()
+        .position(|&byte| {
+            lines += (byte == b'\n') as u32;
+            lines == 3
+        })
+        .unwrap()
+        + 1;
+    let header = String::from_utf8(bytes[..header_len].to_vec()).unwrap();
+    let samples = bytes[header_len..]
+        .chunks_exact(4)
+        .map(|sample| f32::from_le_bytes(sample.try_into().unwrap()))
+        .collect::<Vec<_>>();
+
+    let stride = samples.len() / SIZE.y as usize;
+    let rows = samples.chunks_exact(stride).rev().flatten().copied();
+    (header, rows.collect())
+}
+
+#[test]
+fn pfm_writes_a_file_per_layer() {
+    let dir = out_dir("pfm");
+    let image = image();
+    image.write_pfm(dir.join("frame.pfm")).unwrap();
+
+    // The alpha of the radiance is dropped
+    let (header, radiance) = read_pfm(dir.join("frame.pfm"));
+    assert_eq!(header, "PF\n3 2\n-1.0\n");
+    let expected = image.layers[0].data.chunks_exact(4).flat_map(|p| &p[..3]);
+    assert!(radiance.iter().eq(expected));
+
+    let (header, normal) = read_pfm(dir.join("frame.normal.pfm"));
+    assert_eq!(header, "PF\n3 2\n-1.0\n");
+    assert_eq!(normal, image.layers[1].data);
+
+    let (header, depth) = read_pfm(dir.join("frame.depth.pfm"));
+    assert_eq!(header, "Pf\n3 2\n-1.0\n");
+    assert_eq!(depth, image.layers[2].data);
+}
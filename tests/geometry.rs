@@ -0,0 +1,363 @@
+//! The compact encodings of the geometry must decode to the same picture,
+//! geometry beyond a storage buffer binding must be split into chunks,
+//! the optional vertex attributes must only cost the meshes having them,
+//! missing or unusual attributes must not hide a mesh,
+//! and invalid data must be dropped or replaced rather than traced.
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::{Indices, MeshVertexAttribute, PrimitiveTopology, VertexAttributeValues},
+        render_resource::VertexFormat,
+        settings::WgpuLimits,
+    },
+};
+use common::{assert_golden, scenes};
+use rusticrayz::{
+    reference::{
+        render, GpuMesh, GpuVertexCompact, ReferenceScene, ReferenceSettings, ReferenceView,
+    },
+    GeometryLimits, GeometrySettings, MeshValidation, PrepareMeshError, RaytracerError,
+};
+
+mod common;
+
+/// Directions around the sphere, with the axes and the seams of the octahedron
+fn directions() -> impl Iterator<Item = Vec3> {
+    let axes = [
+        Vec3::X,
+        Vec3::Y,
+        Vec3::Z,
+        Vec3::NEG_X,
+        Vec3::NEG_Y,
+        Vec3::NEG_Z,
+    ];
+    let sphere = (0..64).flat_map(|i| {
+        (0..32).map(move |j| {
+            let theta = i as f32 / 64.0 * std::f32::consts::TAU;
+            let z = j as f32 / 31.0 * 2.0 - 1.0;
+            let r = (1.0 - z * z).sqrt();
+            Vec3::new(r * theta.cos(), r * theta.sin(), z)
+        })
+    });
+    axes.into_iter().chain(sphere)
+}
+
+#[test]
+fn quantized_vertices_round_trip() {
+    for (index, normal) in directions().enumerate() {
+        let vertex = GpuVertexCompact {
+            position: Vec3::new(index as f32 * 0.1, -3.7, 1e6),
+            normal,
+            u: index as f32 / 100.0,
+            v: 1.0 - index as f32 / 3000.0,
+        };
+        let decoded = GpuVertexCompact::dequantize(vertex.quantize());
+
+        assert_eq!(decoded.position, vertex.position);
+        assert!(
+            decoded.normal.dot(normal) > 0.9999,
+            "{normal} decoded to {}",
+            decoded.normal
+        );
+        // Halves keep 11 significant bits
+        assert!((decoded.u - vertex.u).abs() <= vertex.u.abs() / 1024.0);
+        assert!((decoded.v - vertex.v).abs() <= vertex.v.abs() / 1024.0);
+    }
+}
+
+#[test]
+fn compact_cornell_box() {
+    let (mut scene, view) = scenes::cornell_box();
+    scene.geometry = GeometrySettings {
+        compact_primitives: true,
+        quantized_vertices: true,
+    };
+    let settings = ReferenceSettings {
+        samples_per_pixel: 32,
+        max_bounces: 5,
+    };
+    let image = render(&scene, &view, &settings);
+    assert_golden("cornell_box", &image, 0.98);
+}
+
+#[test]
+fn chunks_follow_device_limits() {
+    let limits = GeometryLimits::new(&WgpuLimits::default());
+    assert_eq!(limits.max_binding_size, 128 << 20);
+    assert_eq!(limits.max_chunks, 1);
+
+    let limits = GeometryLimits::new(&WgpuLimits {
+        max_storage_buffers_per_shader_stage: 64,
+        ..default()
+    });
+    assert_eq!(limits.max_chunks, 4);
+
+    // The chunks hold more of the compact encodings
+    let [vertices, primitives, ..] = limits.chunk_capacity(default());
+    let compact = GeometrySettings {
+        compact_primitives: true,
+        quantized_vertices: true,
+    };
+    let [compact_vertices, compact_primitives, ..] = limits.chunk_capacity(compact);
+    assert!(compact_vertices > vertices);
+    assert!(compact_primitives >= 4 * primitives);
+}
+
+#[test]
+fn meshes_fill_chunks_in_order() {
+    let cube = GpuMesh::new(Mesh::from(shape::Cube::default()), &default()).unwrap();
+    let sphere = GpuMesh::new(
+        Mesh::try_from(shape::Icosphere::default()).unwrap(),
+        &default(),
+    )
+    .unwrap();
+
+    // Room for two cubes per chunk
+    let max_binding_size = [
+        2 * 32 * cube.vertices.len() as u64,
+        2 * 48 * cube.primitives.len() as u64,
+        16 + 2 * 32 * cube.nodes.len() as u64,
+    ]
+    .into_iter()
+    .max()
+    .unwrap();
+    let limits = GeometryLimits {
+        max_binding_size,
+        max_chunks: 2,
+    };
+
+    let cubes = vec![&cube; 5];
+    let chunks = limits.assign_chunks(default(), cubes.into_iter());
+    assert_eq!(chunks, [Some(0), Some(0), Some(1), Some(1), None]);
+
+    // A mesh larger than a chunk is left out, the next ones still fit
+    let chunks = limits.assign_chunks(default(), [&sphere, &cube].into_iter());
+    assert_eq!(chunks, [None, Some(0)]);
+}
+
+#[test]
+fn vertex_attributes_are_interleaved() {
+    let cube = GpuMesh::try_from(Mesh::from(shape::Cube::default())).unwrap();
+    assert!(cube.attributes.is_empty());
+    assert_eq!(cube.attributes.flags(), 0);
+
+    let mut mesh = Mesh::from(shape::Cube::default());
+    let count = mesh.count_vertices();
+    let colors = (0..count)
+        .map(|i| [i as f32, 0.5, 0.25, 1.0])
+        .collect::<Vec<_>>();
+    let uvs = (0..count).map(|i| [0.0, i as f32]).collect::<Vec<_>>();
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors.clone());
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_1, uvs.clone());
+    let mesh = GpuMesh::try_from(mesh).unwrap();
+
+    assert_eq!(mesh.attributes.stride(), 6);
+    assert_eq!(mesh.attributes.len(), 6 * count as u32);
+    let words = mesh.attributes.words();
+    for (i, vertex) in words.chunks_exact(6).enumerate() {
+        let vertex = vertex
+            .iter()
+            .copied()
+            .map(f32::from_bits)
+            .collect::<Vec<_>>();
+        assert_eq!(vertex[..4], colors[i]);
+        assert_eq!(vertex[4..], uvs[i]);
+    }
+}
+
+/// White with red vertex colors renders like red, up to the rounding of the interpolation
+#[test]
+fn vertex_colors_tint_base_color() {
+    let render_cube = |base_color: Color, vertex_color: Option<Color>| {
+        let images = Assets::default();
+        let mut scene = ReferenceScene::default();
+        let light = scene.add_material(
+            &StandardMaterial {
+                base_color: Color::BLACK,
+                emissive: Color::WHITE,
+                ..default()
+            },
+            &images,
+        );
+        let cube_material = scene.add_material(
+            &StandardMaterial {
+                base_color,
+                ..default()
+            },
+            &images,
+        );
+
+        let floor = scene
+            .add_mesh(Mesh::from(shape::Plane::from_size(10.0)))
+            .unwrap();
+        scene.add_instance(&floor, light, Transform::IDENTITY);
+        let mut cube = Mesh::from(shape::Cube::default());
+        if let Some(color) = vertex_color {
+            let colors = vec![color.as_rgba_f32(); cube.count_vertices()];
+            cube.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+        }
+        let cube = scene.add_mesh(cube).unwrap();
+        scene.add_instance(&cube, cube_material, Transform::from_xyz(0.0, 1.0, 0.0));
+
+        let view = ReferenceView::new(
+            UVec2::new(32, 32),
+            &Transform::from_xyz(2.0, 3.0, 3.0)
+                .looking_at(Vec3::Y, Vec3::Y)
+                .into(),
+            &Projection::Perspective(default()),
+        );
+        let settings = ReferenceSettings {
+            samples_per_pixel: 4,
+            max_bounces: 3,
+        };
+        render(&scene, &view, &settings).layers[0].data.clone()
+    };
+
+    let red = Color::rgb(1.0, 0.0, 0.0);
+    let expected = render_cube(red, None);
+    let tinted = render_cube(Color::WHITE, Some(red));
+    assert!(expected
+        .iter()
+        .zip(&tinted)
+        .all(|(expected, tinted)| (expected - tinted).abs() < 1e-5));
+    assert_ne!(render_cube(Color::WHITE, None), expected);
+}
+
+fn cube_normals() -> Vec<Vec3> {
+    let cube = GpuMesh::try_from(Mesh::from(shape::Cube::default())).unwrap();
+    cube.vertices.iter().map(|vertex| vertex.normal).collect()
+}
+
+#[test]
+fn missing_normals_and_uvs_are_generated() {
+    let mut mesh = Mesh::from(shape::Cube::default());
+    mesh.remove_attribute(Mesh::ATTRIBUTE_NORMAL);
+    mesh.remove_attribute(Mesh::ATTRIBUTE_UV_0);
+    let mesh = GpuMesh::try_from(mesh).unwrap();
+
+    // The faces of the cube share no vertex, so the normals are flat
+    for (vertex, normal) in mesh.vertices.iter().zip(cube_normals()) {
+        assert!(vertex.normal.dot(normal) > 0.9999);
+        assert_eq!((vertex.u, vertex.v), (0.0, 0.0));
+    }
+}
+
+#[test]
+fn normalized_attribute_formats() {
+    let mut mesh = Mesh::from(shape::Cube::default());
+    let count = mesh.count_vertices();
+    let snorm = |x: f32| (x * 32767.0).round() as i16;
+    let normals = cube_normals()
+        .iter()
+        .map(|normal| [snorm(normal.x), snorm(normal.y), snorm(normal.z), 0])
+        .collect::<Vec<_>>();
+    let uvs = (0..count)
+        .map(|i| [(i * 1000) as u16, u16::MAX])
+        .collect::<Vec<_>>();
+    // Same ids as the standard attributes, in other formats
+    let normal = MeshVertexAttribute::new("Vertex_Normal", 1, VertexFormat::Snorm16x4);
+    let uv = MeshVertexAttribute::new("Vertex_Uv", 2, VertexFormat::Unorm16x2);
+    mesh.insert_attribute(normal, VertexAttributeValues::Snorm16x4(normals));
+    mesh.insert_attribute(uv, VertexAttributeValues::Unorm16x2(uvs));
+    let mesh = GpuMesh::try_from(mesh).unwrap();
+
+    for (i, (vertex, normal)) in mesh.vertices.iter().zip(cube_normals()).enumerate() {
+        assert!(vertex.normal.dot(normal) > 0.9999);
+        assert!((vertex.u - (i * 1000) as f32 / 65535.0).abs() < 1e-6);
+        assert_eq!(vertex.v, 1.0);
+    }
+}
+
+#[test]
+fn lines_and_points_are_skipped() {
+    for topology in [PrimitiveTopology::LineList, PrimitiveTopology::PointList] {
+        let mut mesh = Mesh::new(topology);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vec![[0.0, 0.0, 0.0]; 4]);
+        assert_eq!(
+            GpuMesh::try_from(mesh).err(),
+            Some(PrepareMeshError::UnsupportedTopology(topology))
+        );
+    }
+}
+
+/// A quad, then a triangle of each kind of invalid data
+fn invalid_mesh() -> Mesh {
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    let positions = vec![
+        [0.0, 0.0, 0.0],
+        [1.0, 0.0, 0.0],
+        [1.0, 1.0, 0.0],
+        [0.0, 1.0, 0.0],
+        [f32::NAN, 0.0, 0.0],
+    ];
+    let mut normals = vec![[0.0, 0.0, 1.0]; 5];
+    normals[1] = [f32::INFINITY, 0.0, 0.0];
+    normals[2] = [0.0, 0.0, 0.0];
+    let mut uvs = vec![[0.5, 0.5]; 5];
+    uvs[3] = [f32::NAN, 0.0];
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.set_indices(Some(Indices::U32(vec![
+        0, 1, 2, 0, 2, 3, // quad
+        0, 1, 9, // out of range
+        0, 4, 1, // non-finite
+        0, 1, 1, // repeated index
+        0, 1, 0, // repeated index
+        0, 1, 1, // repeated index
+    ])));
+    mesh
+}
+
+#[test]
+fn invalid_data_is_dropped_or_replaced() {
+    let mesh = GpuMesh::try_from(invalid_mesh()).unwrap();
+    assert_eq!(
+        mesh.validation,
+        MeshValidation {
+            out_of_range_primitives: 1,
+            non_finite_primitives: 1,
+            degenerate_primitives: 3,
+            invalid_normals: 2,
+            non_finite_attributes: 1,
+        }
+    );
+    assert_eq!(mesh.validation.dropped_primitives(), 5);
+    assert_eq!(mesh.primitives.len(), 2);
+
+    for vertex in &mesh.vertices {
+        assert!(vertex.position.is_finite() && vertex.u.is_finite() && vertex.v.is_finite());
+    }
+    // The replaced normals are generated from the quad
+    for vertex in &mesh.vertices[..4] {
+        assert!(vertex.normal.dot(Vec3::Z) > 0.9999);
+    }
+    assert_eq!((mesh.vertices[3].u, mesh.vertices[3].v), (0.0, 0.0));
+
+    // The report reaches the main world as an error
+    let id = AssetId::<Mesh>::default();
+    assert_eq!(
+        RaytracerError::from_validation(id, &mesh.validation),
+        Some(RaytracerError::InvalidMeshData(id, mesh.validation))
+    );
+
+    let cube = GpuMesh::try_from(Mesh::from(shape::Cube::default())).unwrap();
+    assert!(cube.validation.is_clean());
+    assert_eq!(RaytracerError::from_validation(id, &cube.validation), None);
+}
+
+#[test]
+fn meshes_without_valid_primitives_are_rejected() {
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vec![[0.0, 0.0, 0.0]; 3]);
+    assert_eq!(
+        GpuMesh::try_from(mesh.clone()).err(),
+        Some(PrepareMeshError::NoPrimitive)
+    );
+
+    mesh.set_indices(Some(Indices::U16(vec![0, 1, 2, 3])));
+    assert_eq!(
+        GpuMesh::try_from(mesh).err(),
+        Some(PrepareMeshError::IncompatiblePrimitiveTopology)
+    );
+}
@@ -0,0 +1,25 @@
+//! Renders the example scenes with the CPU reference and compares them against `tests/golden`.
+//! After an intended change to the picture, run with `RUSTICRAYZ_BLESS=1` and commit the new references.
+use common::{assert_golden, scenes};
+use rusticrayz::reference::{render, ReferenceSettings};
+
+mod common;
+
+const SETTINGS: ReferenceSettings = ReferenceSettings {
+    samples_per_pixel: 32,
+    max_bounces: 5,
+};
+
+#[test]
+fn cornell_box() {
+    let (scene, view) = scenes::cornell_box();
+    let image = render(&scene, &view, &SETTINGS);
+    assert_golden("cornell_box", &image, 0.98);
+}
+
+#[test]
+fn emissive_walls() {
+    let (scene, view) = scenes::emissive_walls();
+    let image = render(&scene, &view, &SETTINGS);
+    assert_golden("emissive_walls", &image, 0.98);
+}
@@ -0,0 +1,106 @@
+//! Raytraced materials reach the shader as words: the indices of their textures, then their data.
+use bevy::{prelude::*, render::render_resource::ShaderType};
+use indexmap::IndexSet;
+use rusticrayz::{
+    pack_material_index, GpuCustomMaterial, RaytracedMaterial, TextureSlots, TextureTable,
+};
+
+#[derive(Asset, TypePath, Clone)]
+struct ToonMaterial {
+    color: Color,
+    bands: u32,
+    ramp: Option<Handle<Image>>,
+    outline: Option<Handle<Image>>,
+}
+
+#[derive(ShaderType)]
+struct ToonData {
+    color: Vec4,
+    bands: u32,
+}
+
+impl RaytracedMaterial for ToonMaterial {
+    type Data = ToonData;
+    const FUNCTION: &'static str = "sample_toon";
+
+    fn shader() -> &'static str {
+        ""
+    }
+
+    fn textures(&self) -> Vec<Option<Handle<Image>>> {
+        vec![self.ramp.clone(), self.outline.clone()]
+    }
+
+    fn data(&self) -> ToonData {
+        ToonData {
+            color: self.color.into(),
+            bands: self.bands,
+        }
+    }
+}
+
+#[test]
+fn custom_material_words() {
+    let ramp = Handle::<Image>::weak_from_u128(7);
+    let shared = Handle::<Image>::weak_from_u128(8);
+    let material = ToonMaterial {
+        color: Color::rgba(0.25, 0.5, 1.0, 1.0),
+        bands: 3,
+        ramp: Some(ramp.clone()),
+        outline: None,
+    };
+    let gpu = GpuCustomMaterial::new(2, &material);
+    assert_eq!(gpu.kind, 2);
+
+    // The data keeps the layout of a storage buffer: a vec4, a u32 and the padding of the struct
+    let color = [0.25f32, 0.5, 1.0, 1.0].map(f32::to_bits);
+    assert_eq!(gpu.data.len(), 8);
+    assert_eq!(gpu.data[..4], color);
+    assert_eq!(gpu.data[4], 3);
+
+    // Textures already in the table are shared, empty slots are U32_MAX
+    let mut textures = IndexSet::from([shared, ramp.clone()]);
+    let words = gpu.words(&mut textures);
+    assert_eq!(words[..2], [1, u32::MAX]);
+    assert_eq!(words[2..], gpu.data);
+    assert_eq!(textures.len(), 2);
+
+    let mut textures = IndexSet::new();
+    gpu.words(&mut textures);
+    assert_eq!(textures.into_iter().collect::<Vec<_>>(), [ramp]);
+}
+
+#[test]
+fn texture_slots_are_stable_and_recycled() {
+    let [a, b, c, d] = [1, 2, 3, 4].map(Handle::<Image>::weak_from_u128);
+    let mut table = TextureTable::new(3);
+    assert_eq!([&a, &b, &c].map(|texture| table.slot(texture)), [0, 1, 2]);
+    assert_eq!(table.slot(&b), 1);
+
+    // A full table gives no slot and counts the texture until the next retain
+    assert_eq!(table.slot(&d), u32::MAX);
+    assert_eq!(table.overflow(), 1);
+
+    // The textures left keep their slots, and the freed one is given to the next texture
+    table.retain(|texture| texture != a.id());
+    assert_eq!(table.overflow(), 0);
+    assert_eq!([table.get(b.id()), table.get(c.id())], [Some(1), Some(2)]);
+    assert_eq!(table.slot(&d), 0);
+
+    // Free slots at the end shrink the table
+    table.retain(|texture| texture == d.id());
+    assert_eq!(table.slots(), [Some(d.clone())]);
+    assert_eq!(table.slot(&a), 1);
+}
+
+#[test]
+fn material_indices_pack_kind_and_offset() {
+    assert_eq!(pack_material_index(0, 7), Some(7));
+    assert_eq!(pack_material_index(3, 0x12), Some(0x0300_0012));
+    assert_eq!(pack_material_index(255, 0xFF_FFFF), Some(u32::MAX));
+
+    // Past 8 bits of kind or 24 bits of offset, the material is dropped
+    assert_eq!(pack_material_index(256, 0), None);
+    assert_eq!(pack_material_index(0, 1 << 24), None);
+    assert_eq!(pack_material_index(1, usize::MAX), None);
+}
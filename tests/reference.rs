@@ -0,0 +1,293 @@
+use bevy::prelude::*;
+use rusticrayz::{
+    reference::{render, ReferenceScene, ReferenceSettings, ReferenceView},
+    InstanceMaterialOverride, RayVisibility,
+};
+use std::f32::consts::PI;
+
+const SIZE: UVec2 = UVec2::new(32, 32);
+
+fn view() -> ReferenceView {
+    ReferenceView::new(
+        SIZE,
+        &Transform::from_xyz(0.0, 0.0, 5.0).into(),
+        &Projection::Perspective(default()),
+    )
+}
+
+fn pixel(data: &[f32], x: u32, y: u32) -> Vec4 {
+    let index = ((y * SIZE.x + x) * 4) as usize;
+    Vec4::from_slice(&data[index..index + 4])
+}
+
+#[test]
+fn empty_scene_is_black() {
+    let scene = ReferenceScene::default();
+    let image = render(&scene, &view(), &ReferenceSettings::default());
+
+    let radiance = &image.layers[0].data;
+    assert!(radiance
+        .chunks_exact(4)
+        .all(|pixel| pixel[..3] == [0.0, 0.0, 0.0]));
+}
+
+#[test]
+fn emissive_quad_is_seen_from_the_front_only() {
+    let images = Assets::default();
+    let mut scene = ReferenceScene::default();
+    let quad = scene
+        .add_mesh(Mesh::from(shape::Quad::new(Vec2::splat(2.0))))
+        .unwrap();
+    let light = scene.add_material(
+        &StandardMaterial {
+            base_color: Color::BLACK,
+            emissive: Color::WHITE,
+            ..default()
+        },
+        &images,
+    );
+    scene.add_instance(&quad, light, Transform::IDENTITY);
+
+    let image = render(&scene, &view(), &ReferenceSettings::default());
+    let center = pixel(&image.layers[0].data, SIZE.x / 2, SIZE.y / 2);
+    assert!(center.abs_diff_eq(Vec4::ONE, 1e-4), "{center}");
+    let corner = pixel(&image.layers[0].data, 0, 0);
+    assert_eq!(corner.truncate(), Vec3::ZERO);
+
+    // Backfaces are culled
+    let behind = ReferenceView::new(
+        SIZE,
+        &Transform::from_xyz(0.0, 0.0, -5.0)
+            .looking_at(Vec3::ZERO, Vec3::Y)
+            .into(),
+        &Projection::Perspective(default()),
+    );
+    let image = render(&scene, &behind, &ReferenceSettings::default());
+    let center = pixel(&image.layers[0].data, SIZE.x / 2, SIZE.y / 2);
+    assert_eq!(center.truncate(), Vec3::ZERO);
+}
+
+#[test]
+fn instances_outside_the_view_layers_are_skipped() {
+    let images = Assets::default();
+    let mut scene = ReferenceScene::default();
+    let quad = scene
+        .add_mesh(Mesh::from(shape::Quad::new(Vec2::splat(2.0))))
+        .unwrap();
+    let light = scene.add_material(
+        &StandardMaterial {
+            base_color: Color::BLACK,
+            emissive: Color::WHITE,
+            ..default()
+        },
+        &images,
+    );
+    let index = scene.add_instance(&quad, light, Transform::IDENTITY);
+    // Layer 1 only
+    scene.instances[index as usize].layers = 0b10;
+
+    let image = render(&scene, &view(), &ReferenceSettings::default());
+    let center = pixel(&image.layers[0].data, SIZE.x / 2, SIZE.y / 2);
+    assert_eq!(center.truncate(), Vec3::ZERO);
+
+    let view = ReferenceView {
+        primary_layers: 0b11,
+        ..view()
+    };
+    let image = render(&scene, &view, &ReferenceSettings::default());
+    let center = pixel(&image.layers[0].data, SIZE.x / 2, SIZE.y / 2);
+    assert!(center.abs_diff_eq(Vec4::ONE, 1e-4), "{center}");
+}
+
+/// A light hidden from the camera still lights the scene, unless it is hidden from diffuse rays
+#[test]
+fn ray_visibility_hides_instances_from_kinds_of_rays() {
+    let images = Assets::default();
+    let mut scene = ReferenceScene::default();
+    let quad = scene
+        .add_mesh(Mesh::from(shape::Quad::new(Vec2::splat(2.0))))
+        .unwrap();
+    let light = scene.add_material(
+        &StandardMaterial {
+            base_color: Color::BLACK,
+            emissive: Color::WHITE,
+            ..default()
+        },
+        &images,
+    );
+    let white = scene.add_material(&StandardMaterial::default(), &images);
+    let settings = ReferenceSettings {
+        samples_per_pixel: 4,
+        max_bounces: 2,
+    };
+    let center = |scene: &ReferenceScene| {
+        let image = render(scene, &view(), &settings);
+        pixel(&image.layers[0].data, SIZE.x / 2, SIZE.y / 2)
+    };
+
+    let index = scene.add_instance(&quad, light, Transform::IDENTITY) as usize;
+    assert!(center(&scene).abs_diff_eq(Vec4::ONE, 1e-4));
+    scene.instances[index].visibility = RayVisibility::ALL.without(RayVisibility::CAMERA).bits();
+    assert_eq!(center(&scene).truncate(), Vec3::ZERO);
+
+    // Another light faces a wall, the camera sees its culled back
+    scene.instances[index].visibility = RayVisibility::NONE.bits();
+    let facing_wall = Transform::from_xyz(0.0, 0.0, 1.0).with_rotation(Quat::from_rotation_y(PI));
+    let index = scene.add_instance(&quad, light, facing_wall) as usize;
+    scene.add_instance(&quad, white, Transform::from_scale(Vec3::splat(4.0)));
+    assert!(center(&scene).x > 0.0);
+    scene.instances[index].visibility = RayVisibility::ALL.without(RayVisibility::DIFFUSE).bits();
+    assert_eq!(center(&scene).truncate(), Vec3::ZERO);
+}
+
+/// A mirror reflects a light behind the camera with specular rays only
+#[test]
+fn ray_visibility_tells_specular_from_diffuse_bounces() {
+    let images = Assets::default();
+    let mut scene = ReferenceScene::default();
+    let quad = scene
+        .add_mesh(Mesh::from(shape::Quad::new(Vec2::splat(2.0))))
+        .unwrap();
+    let light = scene.add_material(
+        &StandardMaterial {
+            base_color: Color::BLACK,
+            emissive: Color::WHITE,
+            ..default()
+        },
+        &images,
+    );
+    let mirror = scene.add_material(
+        &StandardMaterial {
+            metallic: 1.0,
+            perceptual_roughness: 0.0,
+            ..default()
+        },
+        &images,
+    );
+    let settings = ReferenceSettings {
+        samples_per_pixel: 4,
+        max_bounces: 2,
+    };
+    let center = |scene: &ReferenceScene| {
+        let image = render(scene, &view(), &settings);
+        pixel(&image.layers[0].data, SIZE.x / 2, SIZE.y / 2)
+    };
+
+    scene.add_instance(&quad, mirror, Transform::IDENTITY);
+    let behind_camera = Transform::from_xyz(0.0, 0.0, 8.0).with_rotation(Quat::from_rotation_y(PI));
+    let index = scene.add_instance(&quad, light, behind_camera) as usize;
+    assert!(center(&scene).x > 0.9, "{}", center(&scene));
+    scene.instances[index].visibility = RayVisibility::ALL.without(RayVisibility::DIFFUSE).bits();
+    assert!(center(&scene).x > 0.9, "{}", center(&scene));
+    scene.instances[index].visibility = RayVisibility::ALL.without(RayVisibility::SPECULAR).bits();
+    assert_eq!(center(&scene).truncate(), Vec3::ZERO);
+}
+
+/// The roughness of an instance is offset, then clamped
+#[test]
+fn instance_roughness_offset_blurs_reflections() {
+    let images = Assets::default();
+    let mut scene = ReferenceScene::default();
+    let quad = scene
+        .add_mesh(Mesh::from(shape::Quad::new(Vec2::splat(2.0))))
+        .unwrap();
+    let light = scene.add_material(
+        &StandardMaterial {
+            base_color: Color::BLACK,
+            emissive: Color::WHITE,
+            ..default()
+        },
+        &images,
+    );
+    let metal = |perceptual_roughness| StandardMaterial {
+        metallic: 1.0,
+        perceptual_roughness,
+        ..default()
+    };
+    let mirror = scene.add_material(&metal(0.0), &images);
+    let rough = scene.add_material(&metal(1.0), &images);
+    let settings = ReferenceSettings {
+        samples_per_pixel: 16,
+        max_bounces: 2,
+    };
+    let image = |scene: &ReferenceScene| render(scene, &view(), &settings).layers[0].data.clone();
+
+    let index = scene.add_instance(&quad, mirror, Transform::IDENTITY) as usize;
+    let behind_camera = Transform::from_xyz(0.0, 0.0, 8.0).with_rotation(Quat::from_rotation_y(PI));
+    scene.add_instance(&quad, light, behind_camera);
+    let sharp = image(&scene);
+
+    let set_offset = |scene: &mut ReferenceScene, roughness_offset| {
+        scene.instances[index].set_material_override(&InstanceMaterialOverride {
+            roughness_offset,
+            ..default()
+        });
+    };
+    set_offset(&mut scene, 2.0);
+    let blurred = image(&scene);
+    let center = |data: &[f32]| pixel(data, SIZE.x / 2, SIZE.y / 2).x;
+    assert!(
+        center(&blurred) < 0.5 * center(&sharp),
+        "{} {}",
+        center(&blurred),
+        center(&sharp)
+    );
+
+    // Past the range, the roughness is the one of the bounds
+    scene.instances[index].material = rough;
+    set_offset(&mut scene, 0.0);
+    assert_eq!(image(&scene), blurred);
+    set_offset(&mut scene, -2.0);
+    assert_eq!(image(&scene), sharp);
+}
+
+/// Instances of one material vary by their overrides
+#[test]
+fn instance_overrides_tint_and_scale_emission() {
+    let images = Assets::default();
+    let mut scene = ReferenceScene::default();
+    let quad = scene
+        .add_mesh(Mesh::from(shape::Quad::new(Vec2::splat(2.0))))
+        .unwrap();
+    let light = scene.add_material(
+        &StandardMaterial {
+            base_color: Color::BLACK,
+            emissive: Color::WHITE,
+            ..default()
+        },
+        &images,
+    );
+    let white = scene.add_material(&StandardMaterial::default(), &images);
+    let settings = ReferenceSettings {
+        samples_per_pixel: 4,
+        max_bounces: 2,
+    };
+    let center = |scene: &ReferenceScene| {
+        let image = render(scene, &view(), &settings);
+        pixel(&image.layers[0].data, SIZE.x / 2, SIZE.y / 2)
+    };
+
+    let index = scene.add_instance(&quad, light, Transform::IDENTITY) as usize;
+    scene.instances[index].set_material_override(&InstanceMaterialOverride {
+        emissive_multiplier: 0.5,
+        ..default()
+    });
+    assert!(center(&scene).abs_diff_eq(Vec4::new(0.5, 0.5, 0.5, 1.0), 1e-4));
+
+    // A wall lit by a light facing it, the camera sees the culled back of the light
+    scene.instances[index].visibility = RayVisibility::NONE.bits();
+    let facing_wall = Transform::from_xyz(0.0, 0.0, 1.0).with_rotation(Quat::from_rotation_y(PI));
+    scene.add_instance(&quad, light, facing_wall);
+    let wall = scene.add_instance(&quad, white, Transform::from_scale(Vec3::splat(4.0))) as usize;
+    let lit = center(&scene);
+    scene.instances[wall].set_material_override(&InstanceMaterialOverride {
+        tint: Color::rgb(1.0, 0.0, 0.0),
+        ..default()
+    });
+    let tinted = center(&scene);
+    assert!(
+        tinted.x > 0.0 && (tinted.x - lit.x).abs() < 1e-5,
+        "{tinted} {lit}"
+    );
+    assert_eq!((tinted.y, tinted.z), (0.0, 0.0));
+}